@@ -0,0 +1,98 @@
+#![feature(test)]
+extern crate chip8emu_rs;
+extern crate test;
+
+use std::convert::TryFrom;
+
+use chip8emu_rs::{Chip8, Instruction, Peripherals, Program, Quirks};
+use chip8emu_rs::trace::DEFAULT_TRACE_LENGTH;
+use test::Bencher;
+
+/// Instructions executed per iteration
+const STEPS: usize = 10000;
+
+/// A counting loop, the steady state of most games
+fn counting_loop() -> Vec<u8> {
+    Program::new()
+        .label("loop")
+        .push(Instruction::Add { vr: 0, k: 1 })
+        .push(Instruction::Skne { vr: 0, k: 0 })
+        .push(Instruction::Add { vr: 1, k: 1 })
+        .push(Instruction::Movr { vr: 2, vy: 0 })
+        .push(Instruction::Xor { vr: 2, vy: 1 })
+        .jmp("loop")
+        .build()
+        .unwrap()
+}
+
+/// A loop storing over its own first instruction, the worst case for the
+/// decoded instruction cache
+fn self_modifying_loop() -> Vec<u8> {
+    // `mov v0, 0x60` is 60 60, rewritten with the same bytes
+    Program::new()
+        .push(Instruction::Mov { vr: 1, k: 0x60 })
+        .label("loop")
+        .push(Instruction::Mov { vr: 0, k: 0x60 })
+        .mvi("loop")
+        .push(Instruction::Str { vr: 1 })
+        .push(Instruction::Add { vr: 2, k: 1 })
+        .jmp("loop")
+        .build()
+        .unwrap()
+}
+
+/// Steps a ROM with the trace off, as it is until the debugger is used
+fn run(b: &mut Bencher, rom: &[u8]) {
+    run_with(b, rom, |_| {});
+}
+
+fn run_with<F>(b: &mut Bencher, rom: &[u8], setup: F)
+    where F: FnOnce(&mut Chip8)
+{
+    let mut chip8 = Chip8::new(rom, Quirks::default());
+    setup(&mut chip8);
+    let mut peripherals = Peripherals::new();
+    b.iter(|| {
+        for _ in 0..STEPS {
+            chip8.step(&mut peripherals).unwrap();
+        }
+    });
+}
+
+#[bench]
+fn step_counting_loop(b: &mut Bencher) {
+    run(b, &counting_loop());
+}
+
+#[bench]
+fn step_self_modifying_loop(b: &mut Bencher) {
+    run(b, &self_modifying_loop());
+}
+
+#[bench]
+fn step_counting_loop_uncached(b: &mut Bencher) {
+    run_with(b, &counting_loop(), |chip8| chip8.set_decode_cache(false));
+}
+
+#[bench]
+fn step_self_modifying_loop_uncached(b: &mut Bencher) {
+    run_with(b, &self_modifying_loop(), |chip8| chip8.set_decode_cache(false));
+}
+
+/// The cost of step back history while debugging
+#[bench]
+fn step_counting_loop_traced(b: &mut Bencher) {
+    run_with(b, &counting_loop(), |chip8| chip8.set_trace_length(DEFAULT_TRACE_LENGTH));
+}
+
+/// Decoding alone, which the cache saves on every step
+#[bench]
+fn decode_uncached(b: &mut Bencher) {
+    let rom = counting_loop();
+    let opcodes: Vec<u16> = rom.chunks(2).map(|w| ((w[0] as u16) << 8) | w[1] as u16).collect();
+    b.iter(|| {
+        for idx in 0..STEPS {
+            test::black_box(Instruction::try_from(opcodes[idx % opcodes.len()]).ok());
+        }
+    });
+}
@@ -0,0 +1,655 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use disasm::ROM_START;
+use instruction::Instruction;
+
+/// Deepest include nesting, which also stops include cycles
+const MAX_INCLUDE_DEPTH: usize = 16;
+/// Deepest chain of constants defined in terms of other constants
+const MAX_CONSTANT_DEPTH: usize = 64;
+/// Room left for a ROM in XO-CHIP's 64 KiB address space
+const MAX_ROM_SIZE: usize = 0x10000 - ROM_START;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub file: String,
+    /// Line number starting from 1, or 0 for errors about the whole file
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        }
+    }
+}
+
+impl error::Error for AssemblerError {
+    fn description(&self) -> &str {
+        "assembler error"
+    }
+}
+
+/// Assembles source using the mnemonics `Instruction` is printed with, e.g.
+/// `mov v3, 0x10`, `sprite 1,2,5` or `str v0-v5`, into a ROM loaded at
+/// `ROM_START`. The file name is only used in error messages; includes are
+/// relative to the current directory.
+///
+/// Besides instructions, a line can hold:
+///
+/// ```text
+/// ; a comment
+/// start:                  ; a label, optionally followed by an instruction
+/// SPEED = 2               ; a constant
+///     db 0x3c, $42, 0b10000001
+///     dw start + 2
+///     include "sprites.asm"
+/// ```
+///
+/// Values are decimal, `0x`/`$` hexadecimal or `0b` binary numbers, labels
+/// and constants, added or subtracted.
+pub fn assemble(name: &str, source: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut assembler = Assembler::new();
+    assembler.read_source(name, source, Path::new(""), 0)?;
+    assembler.emit()
+}
+
+/// Assembles a file, with includes relative to its directory
+pub fn assemble_file(path: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut assembler = Assembler::new();
+    assembler.read_file(Path::new(path), None)?;
+    assembler.emit()
+}
+
+enum Body {
+    Instruction { mnemonic: String, operands: Vec<String> },
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+impl Body {
+    fn len(&self) -> usize {
+        match *self {
+            Body::Instruction { .. } => 2,
+            Body::Bytes(ref values) => values.len(),
+            Body::Words(ref values) => 2 * values.len(),
+        }
+    }
+}
+
+/// Where a line came from, an index into `Assembler::files` and a line number
+type Location = (usize, usize);
+
+struct Statement {
+    location: Location,
+    body: Body,
+}
+
+enum Symbol {
+    Label(usize),
+    Constant(String),
+}
+
+struct Assembler {
+    files: Vec<String>,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, (Symbol, Location)>,
+    addr: usize,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Assembler {
+            files: Vec::new(),
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            addr: ROM_START,
+        }
+    }
+
+    fn error(&self, location: Location, message: String) -> AssemblerError {
+        AssemblerError {
+            file: self.files[location.0].clone(),
+            line: location.1,
+            message: message,
+        }
+    }
+
+    /// Reads a file, reporting failures at the include line if there is one
+    fn read_file(&mut self,
+                 path: &Path,
+                 included_from: Option<(Location, usize)>)
+                 -> Result<(), AssemblerError> {
+        let name = path.to_string_lossy().into_owned();
+        let mut source = String::new();
+        let read = File::open(path).and_then(|mut f| f.read_to_string(&mut source));
+        if let Err(e) = read {
+            let message = format!("Cannot read {}: {}", name, e);
+            return Err(match included_from {
+                Some((location, _)) => self.error(location, message),
+                None => {
+                    AssemblerError {
+                        file: name,
+                        line: 0,
+                        message: message,
+                    }
+                }
+            });
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let depth = included_from.map_or(0, |(_, depth)| depth + 1);
+        self.read_source(&name, &source, dir, depth)
+    }
+
+    /// First pass: splits the lines into statements and assigns the labels
+    fn read_source(&mut self,
+                   name: &str,
+                   source: &str,
+                   dir: &Path,
+                   depth: usize)
+                   -> Result<(), AssemblerError> {
+        let file = self.files.len();
+        self.files.push(name.into());
+        for (idx, raw_line) in source.lines().enumerate() {
+            let location = (file, idx + 1);
+            let mut line = strip_comment(raw_line).trim();
+
+            if let Some(colon) = line.find(':') {
+                let label = line[..colon].trim();
+                if is_identifier(label) {
+                    let addr = self.addr;
+                    self.define(label, Symbol::Label(addr), location)?;
+                    line = line[colon + 1..].trim();
+                }
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(equals) = line.find('=') {
+                let name = line[..equals].trim();
+                if is_identifier(name) {
+                    let value = line[equals + 1..].trim();
+                    self.define(name, Symbol::Constant(value.into()), location)?;
+                    continue;
+                }
+            }
+
+            let (mnemonic, rest) = match line.find(char::is_whitespace) {
+                Some(end) => (line[..end].to_lowercase(), line[end..].trim()),
+                None => (line.to_lowercase(), ""),
+            };
+            let body = match mnemonic.as_str() {
+                "include" => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        let message = format!("Includes nested more than {} deep",
+                                              MAX_INCLUDE_DEPTH);
+                        return Err(self.error(location, message));
+                    }
+                    let path = parse_string(rest).map_err(|e| self.error(location, e))?;
+                    self.read_file(&dir.join(path), Some((location, depth)))?;
+                    continue;
+                }
+                "db" | "dw" if rest.is_empty() => {
+                    let message = format!("{} needs at least one value", mnemonic);
+                    return Err(self.error(location, message));
+                }
+                "db" => Body::Bytes(split_operands(rest)),
+                "dw" => Body::Words(split_operands(rest)),
+                _ => {
+                    Body::Instruction {
+                        mnemonic: mnemonic,
+                        operands: split_operands(rest),
+                    }
+                }
+            };
+            self.addr += body.len();
+            if self.addr > ROM_START + MAX_ROM_SIZE {
+                return Err(self.error(location, "Program does not fit in memory".into()));
+            }
+            self.statements.push(Statement {
+                location: location,
+                body: body,
+            });
+        }
+        Ok(())
+    }
+
+    fn define(&mut self,
+              name: &str,
+              symbol: Symbol,
+              location: Location)
+              -> Result<(), AssemblerError> {
+        if register(name).is_some() {
+            return Err(self.error(location, format!("{} is a register name", name)));
+        }
+        if let Some(&(_, first)) = self.symbols.get(name) {
+            let message = format!("{} is already defined at {}:{}",
+                                  name,
+                                  self.files[first.0],
+                                  first.1);
+            return Err(self.error(location, message));
+        }
+        self.symbols.insert(name.into(), (symbol, location));
+        Ok(())
+    }
+
+    /// Second pass: encodes the statements now that every label is known
+    fn emit(&self) -> Result<Vec<u8>, AssemblerError> {
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            let location = statement.location;
+            match statement.body {
+                Body::Instruction { ref mnemonic, ref operands } => {
+                    let opcode = self.instruction(mnemonic, operands)
+                        .map(u16::from)
+                        .map_err(|e| self.error(location, e))?;
+                    rom.push((opcode >> 8) as u8);
+                    rom.push(opcode as u8);
+                }
+                Body::Bytes(ref values) => {
+                    for value in values {
+                        let byte = self.value(value, 8).map_err(|e| self.error(location, e))?;
+                        rom.push(byte as u8);
+                    }
+                }
+                Body::Words(ref values) => {
+                    for value in values {
+                        let word = self.value(value, 16).map_err(|e| self.error(location, e))?;
+                        rom.push((word >> 8) as u8);
+                        rom.push(word as u8);
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Instruction, String> {
+        let count = operand_count(mnemonic)
+            .ok_or_else(|| format!("Unknown instruction {}", mnemonic))?;
+        if operands.len() != count {
+            return Err(format!("{} takes {} operands, found {}", mnemonic, count, operands.len()));
+        }
+        let vr = |idx: usize| expect_register(&operands[idx]);
+        let byte = |idx: usize| self.value(&operands[idx], 8).map(|value| value as u8);
+        let nibble = |idx: usize| self.value(&operands[idx], 4).map(|value| value as usize);
+        let addr = |idx: usize| self.value(&operands[idx], 12).map(|value| value as usize);
+
+        Ok(match mnemonic {
+            "cls" => Instruction::Cls,
+            "ret" => Instruction::Ret,
+            "scr" => Instruction::Scr,
+            "scl" => Instruction::Scl,
+            "exit" => Instruction::Exit,
+            "low" => Instruction::Low,
+            "high" => Instruction::High,
+            "lmvi" => Instruction::Lmvi,
+            "audio" => Instruction::Audio,
+            "sys" => Instruction::Sys { addr: addr(0)? },
+            "jmp" => Instruction::Jmp { addr: addr(0)? },
+            "jsr" => Instruction::Jsr { addr: addr(0)? },
+            "jmi" => Instruction::Jmi { addr: addr(0)? },
+            "mvi" => Instruction::Mvi { k: self.value(&operands[0], 12)? },
+            "scd" => Instruction::Scd { n: nibble(0)? },
+            "scu" => Instruction::Scu { n: nibble(0)? },
+            "plane" => Instruction::Plane { n: nibble(0)? },
+            // The second operand picks between the byte and register forms
+            "skeq" | "skne" | "mov" | "add" => {
+                let x = vr(0)?;
+                match (mnemonic, register(&operands[1])) {
+                    ("skeq", Some(y)) => Instruction::Skeqr { vr: x, vy: y },
+                    ("skne", Some(y)) => Instruction::Skner { vr: x, vy: y },
+                    ("mov", Some(y)) => Instruction::Movr { vr: x, vy: y },
+                    ("add", Some(y)) => Instruction::Addr { vr: x, vy: y },
+                    ("skeq", None) => Instruction::Skeq { vr: x, k: byte(1)? },
+                    ("skne", None) => Instruction::Skne { vr: x, k: byte(1)? },
+                    ("mov", None) => Instruction::Mov { vr: x, k: byte(1)? },
+                    _ => Instruction::Add { vr: x, k: byte(1)? },
+                }
+            }
+            "rnd" => Instruction::Rnd { vr: vr(0)?, k: byte(1)? },
+            "or" => Instruction::Or { vr: vr(0)?, vy: vr(1)? },
+            "and" => Instruction::And { vr: vr(0)?, vy: vr(1)? },
+            "xor" => Instruction::Xor { vr: vr(0)?, vy: vr(1)? },
+            "sub" => Instruction::Subr { vr: vr(0)?, vy: vr(1)? },
+            "shr" => Instruction::Shr { vr: vr(0)?, vy: vr(1)? },
+            "subn" => Instruction::Subn { vr: vr(0)?, vy: vr(1)? },
+            "shl" => Instruction::Shl { vr: vr(0)?, vy: vr(1)? },
+            "sreg" | "lreg" => {
+                let (x, y) = register_range(&operands[0])?;
+                if mnemonic == "sreg" {
+                    Instruction::Sreg { vr: x, vy: y }
+                } else {
+                    Instruction::Lreg { vr: x, vy: y }
+                }
+            }
+            "str" => Instruction::Str { vr: last_register(mnemonic, &operands[0])? },
+            "ldr" => Instruction::Ldr { vr: last_register(mnemonic, &operands[0])? },
+            "rstr" => Instruction::Rstr { vr: last_register(mnemonic, &operands[0])? },
+            "rldr" => Instruction::Rldr { vr: last_register(mnemonic, &operands[0])? },
+            "sprite" => {
+                // Printed with bare register numbers, `v` names work too
+                Instruction::Sprite {
+                    rx: self.register_number(&operands[0])?,
+                    ry: self.register_number(&operands[1])?,
+                    s: nibble(2)?,
+                }
+            }
+            "skp" => Instruction::Skp { vr: vr(0)? },
+            "sknp" => Instruction::Sknp { vr: vr(0)? },
+            "key" => Instruction::Key { vr: vr(0)? },
+            "gdelay" => Instruction::Gdelay { vr: vr(0)? },
+            "sdelay" => Instruction::Sdelay { vr: vr(0)? },
+            "ssound" => Instruction::Ssound { vr: vr(0)? },
+            "adi" => Instruction::Adi { vr: vr(0)? },
+            "font" => Instruction::Font { vr: vr(0)? },
+            "xfont" => Instruction::Xfont { vr: vr(0)? },
+            "bcd" => Instruction::Bcd { vr: vr(0)? },
+            "pitch" => Instruction::Pitch { vr: vr(0)? },
+            _ => unreachable!("operand_count accepted {}", mnemonic),
+        })
+    }
+
+    fn register_number(&self, operand: &str) -> Result<usize, String> {
+        match register(operand) {
+            Some(x) => Ok(x),
+            None => self.value(operand, 4).map(|value| value as usize),
+        }
+    }
+
+    /// Evaluates a value that has to fit in `bits` bits
+    fn value(&self, text: &str, bits: u32) -> Result<u16, String> {
+        let value = self.eval(text, 0)?;
+        if value < 0 {
+            return Err(format!("{} is negative", text.trim()));
+        }
+        if value >= 1 << bits {
+            let text = text.trim();
+            if parse_number(text).is_ok() {
+                return Err(format!("{} does not fit in {} bits", text, bits));
+            }
+            return Err(format!("{} = 0x{:x} does not fit in {} bits", text, value, bits));
+        }
+        Ok(value as u16)
+    }
+
+    fn eval(&self, text: &str, depth: usize) -> Result<i64, String> {
+        let mut total = 0;
+        let mut sign = 1;
+        let mut rest = text;
+        loop {
+            let end = rest.find(|c: char| c == '+' || c == '-').unwrap_or(rest.len());
+            let term = rest[..end].trim();
+            if term.is_empty() {
+                // Only a leading minus can go without a value before it
+                if rest.len() != text.len() || !rest[end..].starts_with('-') {
+                    return Err(format!("Missing value in {}", text.trim()));
+                }
+            } else {
+                total += sign * self.term(term, depth)?;
+            }
+            if end == rest.len() {
+                return Ok(total);
+            }
+            sign = if rest[end..].starts_with('-') { -1 } else { 1 };
+            rest = &rest[end + 1..];
+        }
+    }
+
+    fn term(&self, term: &str, depth: usize) -> Result<i64, String> {
+        if term.starts_with(|c: char| c.is_digit(10) || c == '$') {
+            return parse_number(term);
+        }
+        match self.symbols.get(term) {
+            Some(&(Symbol::Label(addr), _)) => Ok(addr as i64),
+            Some(&(Symbol::Constant(ref value), _)) => {
+                if depth >= MAX_CONSTANT_DEPTH {
+                    return Err(format!("Constant {} is defined in terms of itself", term));
+                }
+                self.eval(value, depth + 1)
+            }
+            None if register(term).is_some() => Err(format!("Unexpected register {}", term)),
+            None => Err(format!("Unknown symbol {}", term)),
+        }
+    }
+}
+
+fn operand_count(mnemonic: &str) -> Option<usize> {
+    match mnemonic {
+        "cls" | "ret" | "scr" | "scl" | "exit" | "low" | "high" | "lmvi" | "audio" => Some(0),
+        "sys" | "jmp" | "jsr" | "jmi" | "mvi" | "scd" | "scu" | "plane" | "sreg" | "lreg" |
+        "str" | "ldr" | "rstr" | "rldr" | "skp" | "sknp" | "key" | "gdelay" | "sdelay" |
+        "ssound" | "adi" | "font" | "xfont" | "bcd" | "pitch" => Some(1),
+        "skeq" | "skne" | "mov" | "add" | "rnd" | "or" | "and" | "xor" | "sub" | "shr" |
+        "subn" | "shl" => Some(2),
+        "sprite" => Some(3),
+        _ => None,
+    }
+}
+
+/// Resolves `v0`-`vf`. Two digit numbers are decimal, as in `v10`.
+fn register(operand: &str) -> Option<usize> {
+    let name = operand.trim().to_lowercase();
+    if !name.starts_with('v') {
+        return None;
+    }
+    let digits = &name[1..];
+    let idx = match digits.len() {
+        1 => usize::from_str_radix(digits, 16).ok(),
+        2 => digits.parse().ok(),
+        _ => None,
+    };
+    idx.and_then(|idx| if idx < 16 { Some(idx) } else { None })
+}
+
+fn expect_register(operand: &str) -> Result<usize, String> {
+    register(operand).ok_or_else(|| format!("Expected a register, found {}", operand))
+}
+
+/// Parses `vX-vY`
+fn register_range(operand: &str) -> Result<(usize, usize), String> {
+    let error = || format!("Expected a register range like v0-v3, found {}", operand);
+    let dash = operand.find('-').ok_or_else(&error)?;
+    let x = register(&operand[..dash]).ok_or_else(&error)?;
+    let y = register(&operand[dash + 1..]).ok_or_else(&error)?;
+    Ok((x, y))
+}
+
+/// Parses the `v0-vX` operand of `str` and friends, where plain `vX` is short
+/// for the whole range
+fn last_register(mnemonic: &str, operand: &str) -> Result<usize, String> {
+    if let Some(x) = register(operand) {
+        return Ok(x);
+    }
+    match register_range(operand)? {
+        (0, x) => Ok(x),
+        _ => Err(format!("{} ranges start at v0", mnemonic)),
+    }
+}
+
+fn parse_number(text: &str) -> Result<i64, String> {
+    let lower = text.to_lowercase();
+    let parsed = if lower.starts_with("0x") {
+        i64::from_str_radix(&lower[2..], 16)
+    } else if lower.starts_with('$') {
+        i64::from_str_radix(&lower[1..], 16)
+    } else if lower.starts_with("0b") {
+        i64::from_str_radix(&lower[2..], 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| format!("Invalid number {}", text))
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_') &&
+    name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn split_operands(text: &str) -> Vec<String> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    text.split(',').map(|operand| operand.trim().into()).collect()
+}
+
+/// Parses a double quoted string, without escapes
+fn parse_string(text: &str) -> Result<PathBuf, String> {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Ok(PathBuf::from(&text[1..text.len() - 1]))
+    } else {
+        Err(format!("Expected a quoted file name, found {}", text))
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+
+    use super::*;
+
+    fn error(source: &str) -> String {
+        assemble("game.asm", source).unwrap_err().to_string()
+    }
+
+    /// Writes the files of a test project under the temporary directory and
+    /// returns the path of the first one
+    fn project(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = env::temp_dir().join(name);
+        for &(file, source) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path).and_then(|mut f| f.write_all(source.as_bytes())).unwrap();
+        }
+        dir.join(files[0].0).to_str().unwrap().into()
+    }
+
+    #[test]
+    fn labels_resolve_backward_and_forward() {
+        let source = "    jmp end
+                      loop: add v0, 1
+                          jmp loop
+                      end: jsr loop
+                          mvi data
+                          ret
+                      data: db 1";
+        assert_eq!(assemble("game.asm", source).unwrap(),
+                   vec![0x12, 0x06, 0x70, 0x01, 0x12, 0x02, 0x22, 0x02, 0xA2, 0x0C, 0x00, 0xEE,
+                        0x01]);
+        assert_eq!(error("jmp nowhere"), "game.asm:1: Unknown symbol nowhere");
+        assert_eq!(error("a: cls\na: cls"), "game.asm:2: a is already defined at game.asm:1");
+        assert_eq!(error("v1: cls"), "game.asm:1: v1 is a register name");
+    }
+
+    #[test]
+    fn constants_are_expressions_over_labels_and_constants() {
+        let source = "SPEED = BASE + 2
+                      BASE = 3
+                      BEFORE = data - 1
+                          mov v0, SPEED
+                          mvi data + SPEED
+                          mvi BEFORE
+                      data: db SPEED - 1";
+        assert_eq!(assemble("game.asm", source).unwrap(),
+                   vec![0x60, 0x05, 0xA2, 0x0B, 0xA2, 0x05, 0x04]);
+        assert_eq!(error("A = B\nB = A\nmov v0, A"),
+                   "game.asm:3: Constant A is defined in terms of itself");
+        assert_eq!(error("LIMIT = 255 + 1\nmov v0, LIMIT"),
+                   "game.asm:2: LIMIT = 0x100 does not fit in 8 bits");
+    }
+
+    #[test]
+    fn data_is_emitted_in_place() {
+        let source = "start: db 0x3c, $42, 0b10000001, 7
+                          dw start + 2, 0x1234";
+        assert_eq!(assemble("game.asm", source).unwrap(),
+                   vec![0x3C, 0x42, 0x81, 0x07, 0x02, 0x02, 0x12, 0x34]);
+        assert_eq!(error("db 256"), "game.asm:1: 256 does not fit in 8 bits");
+        assert_eq!(error("db -1"), "game.asm:1: -1 is negative");
+        assert_eq!(error("cls\ndw"), "game.asm:2: dw needs at least one value");
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let main = project("chip8emu-assembler-include-test",
+                           &[("main.asm", "jsr draw\ninclude \"lib/draw.asm\"\njmp 0x200"),
+                             ("lib/draw.asm", "draw: mvi glyph\nret\ninclude \"glyph.asm\""),
+                             ("lib/glyph.asm", "glyph: db 0xF0")]);
+        let rom = assemble_file(&main);
+        fs::remove_dir_all(Path::new(&main).parent().unwrap()).unwrap();
+        assert_eq!(rom.unwrap(), vec![0x22, 0x02, 0xA2, 0x06, 0x00, 0xEE, 0xF0, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn include_cycles_are_stopped() {
+        let main = project("chip8emu-assembler-cycle-test",
+                           &[("a.asm", "cls\ninclude \"b.asm\""),
+                             ("b.asm", "include \"a.asm\"")]);
+        let err = assemble_file(&main).unwrap_err();
+        fs::remove_dir_all(Path::new(&main).parent().unwrap()).unwrap();
+        assert_eq!(err.message,
+                   format!("Includes nested more than {} deep", MAX_INCLUDE_DEPTH));
+        assert!(err.file.ends_with("a.asm") && err.line == 2, "{}", err);
+    }
+
+    #[test]
+    fn errors_name_the_file_and_line() {
+        assert_eq!(error("cls\n\nfoo v1"), "game.asm:3: Unknown instruction foo");
+        assert_eq!(error("; comment\nmov v0"), "game.asm:2: mov takes 2 operands, found 1");
+        assert_eq!(error("jmp 0x1000"), "game.asm:1: 0x1000 does not fit in 12 bits");
+        assert_eq!(error("include \"missing.asm\"").split(": ").take(2).collect::<Vec<_>>(),
+                   vec!["game.asm:1", "Cannot read missing.asm"]);
+
+        let main = project("chip8emu-assembler-error-test",
+                           &[("main.asm", "cls\ninclude \"bad.asm\""),
+                             ("bad.asm", "cls\nmov v16, 1")]);
+        let err = assemble_file(&main).unwrap_err();
+        fs::remove_dir_all(Path::new(&main).parent().unwrap()).unwrap();
+        assert!(err.file.ends_with("bad.asm") && err.line == 2, "{}", err);
+    }
+
+    #[test]
+    fn every_instruction_reassembles_to_its_opcode() {
+        for opcode in 0..0x10000u32 {
+            let opcode = opcode as u16;
+            let instruction = match Instruction::try_from(opcode) {
+                Ok(instruction) => instruction,
+                Err(_) => continue,
+            };
+            let source = format!("{:?}", instruction);
+            let rom = assemble("opcode.asm", &source)
+                .unwrap_or_else(|err| panic!("{:04x} {}: {}", opcode, source, err));
+            assert_eq!(rom.len(), 2, "{}", source);
+            let reassembled = (rom[0] as u16) << 8 | rom[1] as u16;
+            assert!(reassembled == opcode,
+                    "{} assembles to {:04x} instead of {:04x}",
+                    source,
+                    reassembled,
+                    opcode);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+extern crate chip8emu_rs;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+
+use chip8emu_rs::assembler;
+
+fn main() {
+    let mut source_path = None;
+    let mut output_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => {
+                output_path = Some(args.next().unwrap_or_else(|| fail("Missing value for -o")))
+            }
+            "--help" | "-h" => usage(),
+            _ if arg.starts_with('-') => fail(&format!("Unknown option {}", arg)),
+            _ => source_path = Some(arg),
+        }
+    }
+    let source_path = source_path.unwrap_or_else(|| usage());
+    let output_path = output_path.unwrap_or_else(|| {
+        Path::new(&source_path).with_extension("ch8").to_string_lossy().into_owned()
+    });
+    if output_path == source_path {
+        fail(&format!("Refusing to overwrite {} with the ROM", source_path));
+    }
+
+    let rom = assembler::assemble_file(&source_path).unwrap_or_else(|e| fail(&e.to_string()));
+    if let Err(e) = File::create(&output_path).and_then(|mut f| f.write_all(&rom)) {
+        fail(&format!("Cannot write {}: {}", output_path, e));
+    }
+    println!("Wrote {} bytes to {}", rom.len(), output_path);
+}
+
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    println!("Usage: chip8-asm [-o <rom>] <source>");
+    println!();
+    println!("Assembles a source file, written with the mnemonics of the debugger and");
+    println!("chip8-disasm, into a ROM. The ROM defaults to the source name with a .ch8");
+    println!("extension.");
+    process::exit(1);
+}
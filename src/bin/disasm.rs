@@ -0,0 +1,54 @@
+extern crate chip8emu_rs;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+use chip8emu_rs::disasm::Disassembly;
+
+fn main() {
+    let mut rom_path = None;
+    let mut output_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => {
+                output_path = Some(args.next().unwrap_or_else(|| fail("Missing value for -o")))
+            }
+            "--help" | "-h" => usage(),
+            _ if arg.starts_with('-') => fail(&format!("Unknown option {}", arg)),
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| usage());
+
+    let mut rom = Vec::new();
+    if let Err(e) = File::open(&rom_path).and_then(|mut f| f.read_to_end(&mut rom)) {
+        fail(&format!("Cannot read {}: {}", rom_path, e));
+    }
+    let source = Disassembly::new(&rom).to_string();
+
+    let written = match output_path {
+        Some(ref path) => File::create(path).and_then(|mut f| f.write_all(source.as_bytes())),
+        None => io::stdout().write_all(source.as_bytes()),
+    };
+    if let Err(e) = written {
+        fail(&format!("Cannot write the disassembly: {}", e));
+    }
+}
+
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    println!("Usage: chip8-disasm [-o <file>] <rom>");
+    println!();
+    println!("Disassembles the code reachable from 0x200 and writes the rest of the ROM");
+    println!("as data, to stdout unless an output file is given.");
+    process::exit(1);
+}
@@ -0,0 +1,317 @@
+extern crate chip8emu_rs;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::process;
+
+use chip8emu_rs::{ClockConfig, Emulator, HeadlessFrontend, Movie, Quirks};
+use chip8emu_rs::headless::{self, KeyScript};
+use chip8emu_rs::{octo, quirks};
+use chip8emu_rs::screenshot::{self, Format};
+
+const DEFAULT_FRAMES: u64 = 600;
+
+const EXIT_OK: i32 = 0;
+const EXIT_MISMATCH: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+const EXIT_FAULT: i32 = 3;
+
+/// Instructions printed to show how a faulting ROM got where it did
+const FAULT_HISTORY: usize = 16;
+
+struct Options {
+    rom_path: String,
+    frames: Option<u64>,
+    clock: ClockConfig,
+    quirks: Quirks,
+    script: KeyScript,
+    dump_path: Option<String>,
+    expect_path: Option<String>,
+    format: Option<Format>,
+    seed: Option<u64>,
+    record_path: Option<String>,
+    replay_path: Option<String>,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1));
+    match run(&options) {
+        Ok(status) => process::exit(status),
+        Err(message) => fail(&message),
+    }
+}
+
+/// Runs the ROM as the options say and returns the exit status
+fn run(options: &Options) -> Result<i32, String> {
+    let rom = octo::load_rom(&options.rom_path)?;
+
+    let mut emulator = Emulator::new(&rom, HeadlessFrontend::new(options.script.clone()));
+    let mut clock = options.clock;
+    clock.throttle = false;
+    emulator.set_clock(clock);
+    emulator.set_quirks(options.quirks);
+    // Nobody can rewind an unattended run, skip the snapshots
+    emulator.set_rewind_frames(0);
+    // Only the instructions leading to a fault are ever printed
+    emulator.set_trace_length(FAULT_HISTORY);
+    if let Some(seed) = options.seed {
+        emulator.set_seed(seed);
+    }
+
+    let mut max_frames = options.frames.unwrap_or(DEFAULT_FRAMES);
+    if let Some(ref path) = options.replay_path {
+        let movie = Movie::load_from_file(path)
+            .map_err(|e| format!("Cannot replay {}: {}", path, e))?;
+        // Unless told otherwise, stop where the recording stopped
+        max_frames = options.frames.unwrap_or(movie.frames());
+        emulator.start_replay(movie)
+            .map_err(|e| format!("Cannot replay {}: {}", path, e))?;
+    }
+    if options.record_path.is_some() {
+        emulator.start_recording();
+    }
+
+    let mut frames = 0;
+    while frames < max_frames {
+        if let Err(fault) = emulator.run_frame() {
+            for entry in emulator.chip8().trace().last(FAULT_HISTORY) {
+                eprintln!("{}", entry);
+            }
+            eprintln!("{} after {} frames", fault, frames);
+            return Ok(EXIT_FAULT);
+        }
+        frames += 1;
+        if emulator.chip8().is_spinning() || emulator.chip8().is_halted() {
+            break;
+        }
+    }
+    let pc = emulator.chip8().pc();
+    if frames < max_frames {
+        println!("Stopped at 0x{:03x} after {} frames", pc, frames);
+    } else {
+        println!("Still running at 0x{:03x} after {} frames", pc, frames);
+    }
+
+    if let Some(ref path) = options.record_path {
+        let movie = emulator.take_recording().expect("Recording was started");
+        movie.save_to_file(path)
+            .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    }
+
+    let video = &emulator.peripherals().video_engine;
+    if let Some(ref path) = options.dump_path {
+        let format = options.format.unwrap_or_else(|| Format::from_path(path));
+        let data = screenshot::encode(video, format);
+        File::create(path)
+            .and_then(|mut f| f.write_all(&data))
+            .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    }
+
+    if let Some(ref path) = options.expect_path {
+        let format = options.format.unwrap_or_else(|| Format::from_path(path));
+        let expected = read_file(path)?;
+        if screenshot::encode(video, format) != expected {
+            eprintln!("Framebuffer does not match {}", path);
+            return Ok(EXIT_MISMATCH);
+        }
+        println!("Framebuffer matches {}", path);
+    }
+    Ok(EXIT_OK)
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Options {
+    let mut options = Options {
+        rom_path: String::new(),
+        frames: None,
+        clock: ClockConfig::default(),
+        quirks: Quirks::default(),
+        script: KeyScript::new(),
+        dump_path: None,
+        expect_path: None,
+        format: None,
+        seed: None,
+        record_path: None,
+        replay_path: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => options.frames = Some(numeric_value(&mut args, "--frames")),
+            "--ipf" => {
+                let ipf = numeric_value(&mut args, "--ipf");
+                options.clock.instructions_per_frame = if ipf > 0 { ipf as usize } else { 1 };
+            }
+            "--quirks" => {
+                let preset = value(&mut args, "--quirks");
+                options.quirks = Quirks::from_name(&preset).unwrap_or_else(|e| fail(&e));
+            }
+            "--input" => {
+                let path = value(&mut args, "--input");
+                let text = String::from_utf8(read_file(&path).unwrap_or_else(|e| fail(&e)))
+                    .unwrap_or_else(|_| fail(&format!("{} is not a text file", path)));
+                let script = KeyScript::parse(&text)
+                    .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+                for event in script.events() {
+                    options.script.push(*event);
+                }
+            }
+            "--press" => {
+                let spec = value(&mut args, "--press");
+                parse_press(&spec, &mut options.script)
+                    .unwrap_or_else(|e| fail(&format!("Invalid --press {}: {}", spec, e)));
+            }
+            "--dump" => options.dump_path = Some(value(&mut args, "--dump")),
+            "--expect" => options.expect_path = Some(value(&mut args, "--expect")),
+            "--format" => {
+                let name = value(&mut args, "--format");
+                options.format = Some(Format::from_name(&name).unwrap_or_else(|e| fail(&e)));
+            }
+            "--seed" => options.seed = Some(numeric_value(&mut args, "--seed")),
+            "--record" => options.record_path = Some(value(&mut args, "--record")),
+            "--replay" => options.replay_path = Some(value(&mut args, "--replay")),
+            "--help" | "-h" => usage(),
+            _ if arg.starts_with("--") => fail(&format!("Unknown option {}", arg)),
+            _ => options.rom_path = arg,
+        }
+    }
+    if options.rom_path.is_empty() {
+        fail("Missing ROM file, see --help");
+    }
+    options
+}
+
+/// Parses `<frame>:<key>[:<frames>]`, holding the key for one frame by default
+fn parse_press(spec: &str, script: &mut KeyScript) -> Result<(), String> {
+    let fields: Vec<&str> = spec.split(':').collect();
+    if fields.len() < 2 || fields.len() > 3 {
+        return Err("expected <frame>:<key>[:<frames>]".into());
+    }
+    let frame = fields[0].parse().map_err(|_| format!("invalid frame {}", fields[0]))?;
+    let key = headless::parse_key(fields[1])?;
+    let frames = match fields.get(2) {
+        Some(n) => n.parse().map_err(|_| format!("invalid duration {}", n))?,
+        None => 1,
+    };
+    script.press(frame, key, frames);
+    Ok(())
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> String {
+    args.next().unwrap_or_else(|| fail(&format!("Missing value for {}", name)))
+}
+
+fn numeric_value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> u64 {
+    let value = value(args, name);
+    value.parse().unwrap_or_else(|_| fail(&format!("Invalid value {} for {}", value, name)))
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map(|_| buf)
+        .map_err(|e| format!("Cannot read {}: {}", path, e))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(EXIT_ERROR);
+}
+
+fn usage() -> ! {
+    println!("Usage: chip8-headless [options] <rom>");
+    println!();
+    println!("Runs a ROM without a display until it spins on a jump to itself, exits,");
+    println!("or the frame limit is reached. ROMs ending in .8o are compiled from Octo");
+    println!("source first.");
+    println!();
+    println!("Options:");
+    println!("  --frames <n>          Frame limit (default {})", DEFAULT_FRAMES);
+    println!("  --ipf <n>             Instructions per frame (default {})",
+             ClockConfig::default().instructions_per_frame);
+    println!("  --quirks <preset>     Interpreter quirks: {}", quirks::PRESET_NAMES.join(", "));
+    println!("  --input <file>        Key script, one '<frame> <key> down|up' per line");
+    println!("  --press <f>:<k>[:<n>] Hold key k for n frames (default 1) from frame f");
+    println!("  --dump <file>         Write the final framebuffer to a file");
+    println!("  --expect <file>       Compare the final framebuffer with a golden image");
+    println!("  --format <fmt>        ascii, pbm or png (default: from the file extension)");
+    println!("  --seed <n>            Seed for the random number instruction");
+    println!("  --record <file>       Record the keypad into a movie file");
+    println!("  --replay <file>       Replay a movie file instead of the key script; the");
+    println!("                        frame limit defaults to the movie length");
+    println!();
+    println!("Exit status: {} on success, {} on framebuffer mismatch, {} on errors, {} when",
+             EXIT_OK,
+             EXIT_MISMATCH,
+             EXIT_ERROR,
+             EXIT_FAULT);
+    println!("the ROM faults (invalid opcode, stack or memory fault)");
+    process::exit(EXIT_OK);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Draws the digit 8 in the top left corner and spins
+    const ROM: [u8; 8] = [0x60, 0x08, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+
+    /// Writes a file under the temporary directory and returns its path
+    fn temp_file(name: &str, data: &[u8]) -> String {
+        let path = env::temp_dir().join(name);
+        File::create(&path).and_then(|mut f| f.write_all(data)).unwrap();
+        path.to_str().unwrap().into()
+    }
+
+    fn options(args: &[&str]) -> Options {
+        parse_args(args.iter().map(|arg| (*arg).into()))
+    }
+
+    #[test]
+    fn golden_images_are_compared() {
+        let rom = temp_file("chip8emu-headless-test.ch8", &ROM);
+        let golden = temp_file("chip8emu-headless-test.txt", &[]);
+        let dump = options(&["--dump", &golden, "--format", "ascii", &rom]);
+        assert_eq!(run(&dump), Ok(EXIT_OK));
+        let expect = options(&["--expect", &golden, "--format", "ascii", &rom]);
+        assert_eq!(run(&expect), Ok(EXIT_OK));
+
+        let mut image = read_file(&golden).unwrap();
+        let pixel = image.iter().position(|&c| c == b'1').unwrap();
+        image[pixel] = b'0';
+        temp_file("chip8emu-headless-test.txt", &image);
+        assert_eq!(run(&expect), Ok(EXIT_MISMATCH));
+
+        fs::remove_file(&golden).unwrap();
+        assert!(run(&expect).unwrap_err().starts_with("Cannot read"));
+        fs::remove_file(&rom).unwrap();
+    }
+
+    #[test]
+    fn faults_have_their_own_status() {
+        // 0x5001 is not an instruction
+        let rom = temp_file("chip8emu-headless-fault-test.ch8", &[0x50, 0x01]);
+        let status = run(&options(&[&rom]));
+        fs::remove_file(&rom).unwrap();
+        assert_eq!(status, Ok(EXIT_FAULT));
+    }
+
+    #[test]
+    fn presses_are_added_to_the_script() {
+        let options = options(&["--press", "10:a", "--press", "2:F:3", "rom.ch8"]);
+        let events: Vec<(u64, u8, bool)> = options.script
+            .events()
+            .iter()
+            .map(|event| (event.frame, u8::from(event.key), event.pressed))
+            .collect();
+        assert_eq!(events,
+                   vec![(2, 0xF, true), (5, 0xF, false), (10, 0xA, true), (11, 0xA, false)]);
+        let mut script = KeyScript::new();
+        assert!(parse_press("10", &mut script).is_err());
+        assert!(parse_press("10:g", &mut script).is_err());
+        assert!(parse_press("x:1", &mut script).is_err());
+        assert!(parse_press("1:1:1:1", &mut script).is_err());
+    }
+}
@@ -1,13 +1,22 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{thread, time};
 
+use debugger::watchpoint::{Access, WatchHit, WatchKind};
+use fault::{Fault, StepOutcome};
 use instruction::Instruction;
-use peripherals::Peripherals;
+use peripherals::{key_from_index, Key, Peripherals};
+use quirks::Quirks;
+use rng::XorShift64;
+use savestate;
+use savestate::{SaveStateError, StateReader, StateWriter};
+use timer::Timers;
+use trace::{Trace, TraceEntry, VideoUndo};
+use video_engine::NUM_PLANES;
 
-use rand::{thread_rng, Rng};
+use rand::{Rng, SeedableRng};
 
+/// XO-CHIP extends the address space to 64 KiB
+const MEM_SIZE: usize = 0x10000;
 const FONT_BASE_ADDR: usize = 0x0;
 const NUM_FONTS: usize = 16;
 const FONT_SIZE: usize = 5;
@@ -19,28 +28,80 @@ const FONT_MAP: [u8; NUM_FONTS * FONT_SIZE] =
      0xF0, 0x80, 0x80, 0x80, 0xF0, 0xE0, 0x90, 0x90, 0x90, 0xE0, 0xF0, 0x80, 0xF0, 0x80, 0xF0,
      0xF0, 0x80, 0xF0, 0x80, 0x80];
 
+const BIG_FONT_BASE_ADDR: usize = FONT_BASE_ADDR + NUM_FONTS * FONT_SIZE;
+const BIG_FONT_SIZE: usize = 10;
+const BIG_FONT_MAP: [u8; NUM_FONTS * BIG_FONT_SIZE] =
+    [0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x18, 0x78, 0x78, 0x18, 0x18,
+     0x18, 0x18, 0x18, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+     0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF,
+     0xFF, 0x03, 0x03, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+     0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x03, 0x06,
+     0x0C, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+     0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x7E, 0xFF, 0xC3, 0xC3, 0xC3,
+     0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC,
+     0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, 0xFC, 0xFE, 0xC3, 0xC3, 0xC3,
+     0xC3, 0xC3, 0xC3, 0xFE, 0xFC, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+     0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0];
+
+/// Number of SUPER-CHIP RPL user flags
+const NUM_RPL_FLAGS: usize = 16;
+const NUM_REGISTERS: usize = 16;
+const STACK_SIZE: usize = 16;
+
+#[derive(Clone)]
 pub struct Chip8 {
     mem: Vec<u8>,
     reg_v: Vec<u8>,
     reg_i: u16,
-    reg_delay_timer: Arc<AtomicUsize>,
-    reg_sound_timer: u8,
+    timers: Timers,
     pc: usize,
     sp: usize,
     stack: Vec<usize>,
+    rpl: Vec<u8>,
+    quirks: Quirks,
+    vblank: bool,
+    halted: bool,
+    /// Key seen going down by a pending `FX0A`, which completes on release
+    key_wait: Option<Key>,
+    rom_hash: u64,
+    seed: u64,
+    rng: XorShift64,
+    trace: Trace,
+    /// Entry of the instruction being executed, while tracing
+    tracing: Option<TraceEntry>,
+    trace_start: TraceStart,
+    watchpoints: HashMap<usize, WatchKind>,
+    /// Watched accesses made since the last `take_watch_hits`
+    watch_hits: Vec<WatchHit>,
+    decoded: DecodeCache,
 }
 
 impl Chip8 {
-    pub fn new(rom: &[u8]) -> Chip8 {
+    /// Creates a machine with a random seed for `CXNN`, see `set_seed`
+    pub fn new(rom: &[u8], quirks: Quirks) -> Chip8 {
+        let seed = XorShift64::random_seed();
         let mut chip8 = Chip8 {
-            mem: vec![0; 0xFFF],
-            reg_v: vec![0; 16],
+            mem: vec![0; MEM_SIZE],
+            reg_v: vec![0; NUM_REGISTERS],
             reg_i: 0,
-            reg_delay_timer: Arc::new(AtomicUsize::new(0)),
-            reg_sound_timer: 0,
+            timers: Timers::new(),
             pc: 0x200,
             sp: 0,
-            stack: vec![0; 16],
+            stack: vec![0; STACK_SIZE],
+            rpl: vec![0; NUM_RPL_FLAGS],
+            quirks: quirks,
+            vblank: true,
+            halted: false,
+            key_wait: None,
+            rom_hash: savestate::rom_hash(rom),
+            seed: seed,
+            rng: XorShift64::from_seed(seed),
+            trace: Trace::default(),
+            tracing: None,
+            trace_start: TraceStart::default(),
+            watchpoints: HashMap::new(),
+            watch_hits: Vec::new(),
+            decoded: DecodeCache::new(),
         };
         chip8.load_fonts();
         chip8.load_rom(rom);
@@ -51,6 +112,9 @@ impl Chip8 {
         for i in FONT_BASE_ADDR..FONT_MAP.len() {
             self.mem[i] = FONT_MAP[i - FONT_BASE_ADDR]
         }
+        for (i, byte) in BIG_FONT_MAP.iter().enumerate() {
+            self.mem[BIG_FONT_BASE_ADDR + i] = *byte;
+        }
     }
 
     fn load_rom(&mut self, rom: &[u8]) {
@@ -59,68 +123,355 @@ impl Chip8 {
         }
     }
 
-    pub fn step(&mut self, peripherals: &mut Peripherals) {
-        let hi_nibble = self.mem[self.pc] as u16;
-        let lo_nibble = self.mem[self.pc + 1] as u16;
-        let opcode = (hi_nibble << 8) | lo_nibble;
-        match Instruction::try_from(opcode) {
-            Err(msg) => panic!("Error decoding instruction at 0x{0:03x}: {1}", self.pc, msg),
-            Ok(instruction) => self.step_instruction(instruction, peripherals),
+    /// Executes one instruction. On a fault nothing is changed, so the
+    /// machine can be inspected at the faulting instruction.
+    pub fn step(&mut self, peripherals: &mut Peripherals) -> Result<StepOutcome, Fault> {
+        if self.halted {
+            return Ok(StepOutcome::Halted);
+        }
+        let pc = self.pc;
+        let instruction = match self.decoded.get(pc) {
+            Some(instruction) => instruction,
+            None => self.decode(pc)?,
+        };
+        self.check_instruction(&instruction, peripherals)?;
+
+        if self.trace.is_enabled() {
+            // Decoding is lossless, so the opcode needs no caching
+            self.begin_trace(u16::from(instruction), &instruction, peripherals);
+            self.step_instruction(instruction, peripherals);
+            self.end_trace();
+        } else {
+            self.step_instruction(instruction, peripherals);
+        }
+        Ok(if self.halted {
+            StepOutcome::Halted
+        } else {
+            StepOutcome::Executed
+        })
+    }
+
+    /// Fetches and decodes the instruction at `pc`, caching it for next time
+    fn decode(&mut self, pc: usize) -> Result<Instruction, Fault> {
+        let opcode = self.opcode_at(pc).ok_or(Fault::PcOutOfRange { pc: pc })?;
+        let instruction = Instruction::try_from(opcode).map_err(|_| {
+                Fault::InvalidOpcode {
+                    pc: pc,
+                    opcode: opcode,
+                }
+            })?;
+        self.decoded.insert(pc, instruction);
+        Ok(instruction)
+    }
+
+    /// Finds the faults an instruction would cause before it changes anything
+    fn check_instruction(&self,
+                         instruction: &Instruction,
+                         peripherals: &Peripherals)
+                         -> Result<(), Fault> {
+        let i = self.reg_i as usize;
+        match *instruction {
+            Instruction::Ret if self.sp == 0 => Err(Fault::StackUnderflow { pc: self.pc }),
+            Instruction::Jsr { .. } if self.sp >= STACK_SIZE => {
+                Err(Fault::StackOverflow { pc: self.pc })
+            }
+            Instruction::Lmvi => self.check_memory(self.pc + 2, 2),
+            Instruction::Sreg { vr, vy } |
+            Instruction::Lreg { vr, vy } => {
+                self.check_memory(i, register_range(vr, vy).len())
+            }
+            Instruction::Str { vr } |
+            Instruction::Ldr { vr } => self.check_memory(i, vr + 1),
+            Instruction::Bcd { .. } => self.check_memory(i, 3),
+            Instruction::Audio => self.check_memory(i, 16),
+            Instruction::Sprite { s, .. } => {
+                let bytes_per_plane = if s == 0 { 32 } else { s };
+                let selected_planes = peripherals.video_engine.selected_planes();
+                let planes = (0..NUM_PLANES).filter(|p| selected_planes & (1 << p) != 0).count();
+                self.check_memory(i, bytes_per_plane * planes)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_memory(&self, start: usize, len: usize) -> Result<(), Fault> {
+        if start + len > MEM_SIZE {
+            Err(Fault::MemoryOutOfBounds {
+                pc: self.pc,
+                addr: start.max(MEM_SIZE),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records the state an instruction is about to change
+    fn begin_trace(&mut self,
+                   opcode: u16,
+                   instruction: &Instruction,
+                   peripherals: &Peripherals) {
+        let video = match *instruction {
+            Instruction::Cls | Instruction::Scd { .. } | Instruction::Scu { .. } |
+            Instruction::Scr | Instruction::Scl | Instruction::Low | Instruction::High |
+            Instruction::Plane { .. } => {
+                VideoUndo::Snapshot(Box::new(peripherals.video_engine.clone()))
+            }
+            Instruction::Sprite { .. } => VideoUndo::Flips(Vec::new()),
+            _ => VideoUndo::Unchanged,
+        };
+        // Registers, stack and RPL flags are copied aside, end_trace keeps
+        // the ones that changed
+        self.trace_start.regs.copy_from_slice(&self.reg_v);
+        self.trace_start.stack.copy_from_slice(&self.stack);
+        self.trace_start.rpl.copy_from_slice(&self.rpl);
+        self.tracing = Some(TraceEntry {
+            pc: self.pc,
+            opcode: opcode,
+            regs: Vec::new(),
+            reg_i: (self.reg_i, self.reg_i),
+            mem: Vec::new(),
+            sp: self.sp,
+            stack: Vec::new(),
+            rpl: Vec::new(),
+            delay: self.timers.delay(),
+            sound: self.timers.sound(),
+            vblank: self.vblank,
+            halted: self.halted,
+            key_wait: self.key_wait,
+            rng_state: self.rng.state(),
+            video: video,
+        });
+    }
+
+    fn end_trace(&mut self) {
+        let mut entry = match self.tracing.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+        let start = &self.trace_start;
+        for (idx, (&old, &new)) in start.regs.iter().zip(self.reg_v.iter()).enumerate() {
+            if old != new {
+                entry.regs.push((idx, old, new));
+            }
+        }
+        entry.reg_i.1 = self.reg_i;
+        for (idx, (&old, &new)) in start.stack.iter().zip(self.stack.iter()).enumerate() {
+            if old != new {
+                entry.stack.push((idx, old));
+            }
+        }
+        for (idx, (&old, &new)) in start.rpl.iter().zip(self.rpl.iter()).enumerate() {
+            if old != new {
+                entry.rpl.push((idx, old));
+            }
+        }
+        // A wait such as FX0A runs again and again without changing
+        // anything, only its first run is kept
+        let repeated = self.trace.last(1).first().map_or(false, |last| last.pc == entry.pc);
+        if repeated && self.is_unchanged_since(&entry) {
+            return;
+        }
+        self.trace.push(entry);
+    }
+
+    /// True if a traced instruction left the machine as it found it, PC
+    /// included
+    fn is_unchanged_since(&self, entry: &TraceEntry) -> bool {
+        let video_unchanged = match entry.video {
+            VideoUndo::Unchanged => true,
+            VideoUndo::Flips(ref flips) => flips.is_empty(),
+            VideoUndo::Snapshot(_) => false,
+        };
+        entry.pc == self.pc && entry.regs.is_empty() && entry.reg_i.0 == self.reg_i &&
+        entry.mem.is_empty() && entry.sp == self.sp && entry.stack.is_empty() &&
+        entry.rpl.is_empty() && entry.delay == self.timers.delay() &&
+        entry.sound == self.timers.sound() && entry.vblank == self.vblank &&
+        entry.halted == self.halted && entry.key_wait == self.key_wait &&
+        entry.rng_state == self.rng.state() && video_unchanged
+    }
+
+    /// Undoes the last traced instruction. Returns its entry, or None when
+    /// the trace is empty.
+    pub fn step_back(&mut self, peripherals: &mut Peripherals) -> Option<TraceEntry> {
+        let entry = match self.trace.pop() {
+            Some(entry) => entry,
+            None => return None,
+        };
+        self.pc = entry.pc;
+        for &(idx, old, _) in &entry.regs {
+            self.reg_v[idx] = old;
         }
+        self.reg_i = entry.reg_i.0;
+        for &(addr, old, _) in entry.mem.iter().rev() {
+            self.mem[addr] = old;
+            self.decoded.forget(addr);
+        }
+        self.sp = entry.sp;
+        for &(idx, old) in &entry.stack {
+            self.stack[idx] = old;
+        }
+        for &(idx, old) in &entry.rpl {
+            self.rpl[idx] = old;
+        }
+        self.timers.set_delay(entry.delay);
+        self.timers.set_sound(entry.sound);
+        self.vblank = entry.vblank;
+        self.halted = entry.halted;
+        self.key_wait = entry.key_wait;
+        self.rng.set_state(entry.rng_state).expect("Traced generator states are valid");
+        match entry.video {
+            VideoUndo::Unchanged => {}
+            VideoUndo::Flips(ref flips) => {
+                for &(x, y, plane_mask) in flips.iter().rev() {
+                    peripherals.video_engine.flip_pixel(x, y, plane_mask);
+                }
+            }
+            VideoUndo::Snapshot(ref video) => peripherals.video_engine = (**video).clone(),
+        }
+        Some(entry)
+    }
+
+    /// The word at `pos`, or None past the end of memory
+    fn opcode_at(&self, pos: usize) -> Option<u16> {
+        if pos >= MEM_SIZE - 1 {
+            return None;
+        }
+        let hi_nibble = self.mem[pos] as u16;
+        let lo_nibble = self.mem[pos + 1] as u16;
+        Some((hi_nibble << 8) | lo_nibble)
+    }
+
+    /// Skips the next instruction, which is two words long for XO-CHIP's
+    /// `F000 NNNN`
+    fn skip_next(&mut self) {
+        self.pc += if self.opcode_at(self.pc + 2) == Some(0xF000) { 4 } else { 2 };
+    }
+
+    /// Advances the delay and sound timers by one 60 Hz tick, feeding the
+    /// sound peripheral while the sound timer is running
+    pub fn tick_timers(&mut self, peripherals: &mut Peripherals) {
+        peripherals.sound.render_frame(self.timers.sound() > 0);
+        self.timers.tick();
+        self.vblank = true;
     }
 
-    fn memory_read(&self, pos: usize) -> u8 {
-        self.mem[pos]
+    /// Reads data memory. Instruction fetches do not go through here, so
+    /// they never trigger a watchpoint.
+    fn memory_read(&mut self, pos: usize) -> u8 {
+        let data = self.mem[pos];
+        self.watch_access(pos, Access::Read, data, data);
+        data
     }
 
     fn memory_write(&mut self, pos: usize, data: u8) {
+        let old = self.mem[pos];
+        if let Some(ref mut entry) = self.tracing {
+            entry.mem.push((pos, old, data));
+        }
+        self.watch_access(pos, Access::Write, old, data);
         self.mem[pos] = data;
-        // println!("[W][0x{:03x}] 0x{:03x}", pos, data);
+        self.decoded.forget(pos);
+    }
+
+    fn watch_access(&mut self, pos: usize, access: Access, old: u8, new: u8) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        if let Some(kind) = self.watchpoints.get(&pos) {
+            if kind.matches(access) {
+                self.watch_hits.push(WatchHit {
+                    pc: self.pc,
+                    addr: pos,
+                    access: access,
+                    old: old,
+                    new: new,
+                });
+            }
+        }
     }
 
     fn step_instruction(&mut self, instruction: Instruction, peripherals: &mut Peripherals) {
         match instruction {
             Instruction::Cls => peripherals.video_engine.cls(),
             Instruction::Ret => {
-                assert!(self.sp > 0);
                 self.sp -= 1;
-                let old_pc = self.stack[self.sp];
-                println!("Resuming execution at PC 0x{:x}", old_pc);
-                self.pc = old_pc; // Jump to the instruction immediately after
+                self.pc = self.stack[self.sp]; // Jump to the instruction immediately after
+            }
+            // Machine code routines only exist on the original hardware
+            Instruction::Sys { .. } => {}
+            Instruction::Scd { n } => peripherals.video_engine.scroll_down(n),
+            Instruction::Scu { n } => peripherals.video_engine.scroll_up(n),
+            Instruction::Scr => peripherals.video_engine.scroll_right(4),
+            Instruction::Scl => peripherals.video_engine.scroll_left(4),
+            Instruction::Exit => {
+                self.halted = true;
+                return;
             }
-            Instruction::Jmp { addr } => self.pc = addr - 2, // Correct for pc increment later
+            Instruction::Low => peripherals.video_engine.set_hires(false),
+            Instruction::High => peripherals.video_engine.set_hires(true),
+            // Correct for pc increment later, wrapping for jumps to 0x000
+            Instruction::Jmp { addr } => self.pc = addr.wrapping_sub(2),
             Instruction::Jsr { addr } => {
                 self.stack[self.sp] = self.pc;
                 self.sp += 1;
-                self.pc = addr - 2
-            } // Correct for pc increment later
+                self.pc = addr.wrapping_sub(2)
+            }
             Instruction::Mov { vr, k } => self.reg_v[vr] = k,
             Instruction::Movr { vr, vy } => self.reg_v[vr] = self.reg_v[vy],
-            Instruction::And { vr, vy } => self.reg_v[vr] &= self.reg_v[vy],
-            Instruction::Xor { vr, vy } => self.reg_v[vr] ^= self.reg_v[vy],
-            Instruction::Shr { vr } => {
-                let current_val = self.reg_v[vr];
-                self.reg_v[0xF] = current_val & 1;
+            Instruction::Or { vr, vy } => {
+                self.reg_v[vr] |= self.reg_v[vy];
+                self.logic_reset_vf();
+            }
+            Instruction::And { vr, vy } => {
+                self.reg_v[vr] &= self.reg_v[vy];
+                self.logic_reset_vf();
+            }
+            Instruction::Xor { vr, vy } => {
+                self.reg_v[vr] ^= self.reg_v[vy];
+                self.logic_reset_vf();
+            }
+            Instruction::Shr { vr, vy } => {
+                let current_val = self.shift_source(vr, vy);
                 self.reg_v[vr] = current_val >> 1;
+                self.reg_v[0xF] = current_val & 1;
             }
-            Instruction::Shl { vr } => {
-                let current_val = self.reg_v[vr];
-                self.reg_v[0xF] = (current_val & 0xFF) >> 7;
+            Instruction::Shl { vr, vy } => {
+                let current_val = self.shift_source(vr, vy);
                 self.reg_v[vr] = current_val << 1;
+                self.reg_v[0xF] = (current_val & 0xFF) >> 7;
             }
             Instruction::Skner { vr, vy } => {
                 if self.reg_v[vr] != self.reg_v[vy] {
-                    self.pc += 2;
+                    self.skip_next();
+                }
+            }
+            Instruction::Skeqr { vr, vy } => {
+                if self.reg_v[vr] == self.reg_v[vy] {
+                    self.skip_next();
+                }
+            }
+            Instruction::Sreg { vr, vy } => {
+                for (offset, idx) in register_range(vr, vy).into_iter().enumerate() {
+                    let target_pos = self.reg_i as usize + offset;
+                    let data = self.reg_v[idx];
+                    self.memory_write(target_pos, data);
+                }
+            }
+            Instruction::Lreg { vr, vy } => {
+                for (offset, idx) in register_range(vr, vy).into_iter().enumerate() {
+                    let source_pos = self.reg_i as usize + offset;
+                    let data = self.memory_read(source_pos);
+                    self.reg_v[idx] = data;
                 }
             }
             Instruction::Skeq { vr, k } => {
                 if self.reg_v[vr] == k {
-                    self.pc += 2
+                    self.skip_next();
                 }
             }
             Instruction::Skne { vr, k } => {
                 if self.reg_v[vr] != k {
-                    self.pc += 2
+                    self.skip_next();
                 }
             }
             Instruction::Add { vr, k } => {
@@ -131,94 +482,91 @@ impl Chip8 {
                 let old_r = self.reg_v[vr];
                 let old_y = self.reg_v[vy];
                 let (result, overflow) = old_r.overflowing_add(old_y);
-                self.reg_v[0xF] = if overflow { 1 } else { 0 };
+                // As on the COSMAC VIP, the flag is written last and wins
+                // when VR is VF
                 self.reg_v[vr] = result;
+                self.reg_v[0xF] = if overflow { 1 } else { 0 };
             }
             Instruction::Subr { vr, vy } => {
                 let old_r = self.reg_v[vr];
                 let old_y = self.reg_v[vy];
                 let (result, overflow) = old_r.overflowing_sub(old_y);
-                self.reg_v[0xF] = if overflow { 0 } else { 1 };
                 self.reg_v[vr] = result;
+                self.reg_v[0xF] = if overflow { 0 } else { 1 };
             }
             Instruction::Subn { vr, vy } => {
                 let old_r = self.reg_v[vr];
                 let old_y = self.reg_v[vy];
                 let (result, overflow) = old_y.overflowing_sub(old_r);
-                self.reg_v[0xF] = if overflow { 0 } else { 1 };
                 self.reg_v[vr] = result;
+                self.reg_v[0xF] = if overflow { 0 } else { 1 };
             }
             Instruction::Mvi { k } => self.reg_i = k,
+            Instruction::Lmvi => {
+                self.reg_i = self.opcode_at(self.pc + 2).expect("Checked before executing");
+                self.pc += 2; // Skip the address word
+            }
+            Instruction::Jmi { addr } => {
+                let vr = if self.quirks.jump_vx { (addr >> 8) & 0xF } else { 0 };
+                // Correct for pc increment later
+                self.pc = (addr + self.reg_v[vr] as usize).wrapping_sub(2);
+            }
             Instruction::Rnd { vr, k } => {
-                let mut buffer = [0u8; 1];
-                thread_rng().fill_bytes(&mut buffer);
-                self.reg_v[vr] = buffer[0] & k;
+                self.reg_v[vr] = self.rng.gen::<u8>() & k;
             }
             Instruction::Sprite { rx, ry, s } => {
-                let x = self.reg_v[rx] as usize;
-                let y = self.reg_v[ry] as usize;
-                let height = s;
-                self.reg_v[0xF] = 0;
-                for yline in 0..height {
-                    let mem_pos = self.reg_i as usize + yline as usize;
-                    let pixel = self.memory_read(mem_pos);
-                    for xline in 0..8 {
-                        if pixel & (0x80 >> xline) != 0 {
-                            let collision = peripherals.video_engine
-                                .set_pixel_to_1(x + xline, y + yline);
-                            if collision {
-                                self.reg_v[0xF] = 1;
-                            }
-                        }
-
-                    }
+                if self.quirks.display_wait && !self.vblank {
+                    self.pc = self.pc.wrapping_sub(2); // Wait for the next frame
+                } else {
+                    self.vblank = false;
+                    self.draw_sprite(rx, ry, s, peripherals);
                 }
             }
-            Instruction::Skp { k } => {
-                println!("Skipping if key {:x} is pressed", k);
-                let key = peripherals.keypad.get_current_key_input();
-                match key {
-                    Some(x) if x == k => self.pc += 2,
-                    _ => {}
+            Instruction::Skp { vr } => {
+                if peripherals.keypad.is_pressed(key_from_index(self.reg_v[vr] as usize & 0xF)) {
+                    self.skip_next();
                 }
             }
-            Instruction::Sknp { k } => {
-                println!("Skipping if key {:x} is not pressed", k);
-                let key = peripherals.keypad.get_current_key_input();
-                match key {
-                    Some(x) if x == k => {}
-                    _ => self.pc += 2,
+            Instruction::Sknp { vr } => {
+                if !peripherals.keypad.is_pressed(key_from_index(self.reg_v[vr] as usize & 0xF)) {
+                    self.skip_next();
                 }
             }
             Instruction::Key { vr } => {
-                println!("Waiting for a key to be pressed");
-                let key = peripherals.keypad.get_current_key_input();
-                match key {
-                    Some(x) => {
-                        println!(" ... Got {:x}", x);
-                        self.reg_v[vr] = x
+                // Like the COSMAC VIP, wait for a key to go down and then
+                // for that same key to be released. A key already held when
+                // the wait starts does not count.
+                match self.key_wait {
+                    None => {
+                        self.key_wait = peripherals.keypad.take_press();
+                        self.pc = self.pc.wrapping_sub(2); // Emulate a SLEEP
+                    }
+                    Some(key) if peripherals.keypad.is_pressed(key) => {
+                        self.pc = self.pc.wrapping_sub(2)
                     }
-                    _ => {
-                        println!(" ... Waiting more");
-                        self.pc -= 2; // Emulate a SLEEP
+                    Some(key) => {
+                        self.reg_v[vr] = u8::from(key);
+                        self.key_wait = None;
                     }
                 }
-
             }
             Instruction::Adi { vr } => {
-                self.reg_i += self.reg_v[vr] as u16;
+                self.reg_i = self.reg_i.wrapping_add(self.reg_v[vr] as u16);
             }
             Instruction::Font { vr } => {
                 let character = self.reg_v[vr] as usize;
                 self.reg_i = (FONT_BASE_ADDR + (character * FONT_SIZE)) as u16;
-                println!("Font draw for character: {:x}", character);
+            }
+            Instruction::Xfont { vr } => {
+                let character = self.reg_v[vr] as usize & 0xF;
+                self.reg_i = (BIG_FONT_BASE_ADDR + (character * BIG_FONT_SIZE)) as u16;
             }
             Instruction::Bcd { vr } => {
                 let value = self.reg_v[vr];
                 let i = self.reg_i as usize;
-                self.mem[i] = value / 100;
-                self.mem[i + 1] = (value / 10) % 10;
-                self.mem[i + 2] = value % 10;
+                self.memory_write(i, value / 100);
+                self.memory_write(i + 1, (value / 10) % 10);
+                self.memory_write(i + 2, value % 10);
             }
             Instruction::Str { vr } => {
                 for idx in 0..(vr + 1) {
@@ -226,32 +574,229 @@ impl Chip8 {
                     let data = self.reg_v[idx];
                     self.memory_write(target_pos, data);
                 }
+                if self.quirks.load_store_increment_i {
+                    self.reg_i = self.reg_i.wrapping_add(vr as u16 + 1);
+                }
             }
             Instruction::Ldr { vr } => {
                 for idx in 0..(vr + 1) {
-                    self.reg_v[idx] = self.memory_read(self.reg_i as usize + idx)
+                    let source_pos = self.reg_i as usize + idx;
+                    let data = self.memory_read(source_pos);
+                    self.reg_v[idx] = data;
+                }
+                if self.quirks.load_store_increment_i {
+                    self.reg_i = self.reg_i.wrapping_add(vr as u16 + 1);
                 }
             }
+            Instruction::Rstr { vr } => {
+                for idx in 0..(vr + 1) {
+                    self.rpl[idx] = self.reg_v[idx];
+                }
+            }
+            Instruction::Rldr { vr } => {
+                for idx in 0..(vr + 1) {
+                    self.reg_v[idx] = self.rpl[idx];
+                }
+            }
+            Instruction::Plane { n } => peripherals.video_engine.select_planes(n as u8),
+            Instruction::Audio => {
+                let mut pattern = [0u8; 16];
+                let i = self.reg_i as usize;
+                for (offset, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.memory_read(i + offset);
+                }
+                peripherals.sound.set_pattern(pattern);
+            }
+            Instruction::Pitch { vr } => peripherals.sound.set_pitch(self.reg_v[vr]),
             Instruction::Gdelay { vr } => {
                 let amount = self.reg_delay_timer();
                 self.reg_v[vr] = amount;
             }
             Instruction::Sdelay { vr } => {
-                let amount = self.reg_v[vr];
-                self.reg_delay_timer.store((amount + 1) as _, Ordering::SeqCst);
-                let timer_clone = self.reg_delay_timer.clone();
-                thread::spawn(move || loop {
-                    let ticks_left = timer_clone.fetch_sub(1, Ordering::SeqCst) - 1;
-                    if ticks_left > 0 {
-                        println!("Still {} ticks left", ticks_left);
-                        thread::sleep(time::Duration::from_millis(1000 / 60));
-                    } else {
-                        break;
+                self.timers.set_delay(self.reg_v[vr]);
+            }
+            Instruction::Ssound { vr } => {
+                self.timers.set_sound(self.reg_v[vr]);
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn logic_reset_vf(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.reg_v[0xF] = 0;
+        }
+    }
+
+    fn shift_source(&self, vr: usize, vy: usize) -> u8 {
+        if self.quirks.shift_vy {
+            self.reg_v[vy]
+        } else {
+            self.reg_v[vr]
+        }
+    }
+
+    /// Draws an 8xN sprite, or a 16x16 SUPER-CHIP sprite when N is 0, on
+    /// every selected plane. Each plane consumes its own sprite data.
+    fn draw_sprite(&mut self, rx: usize, ry: usize, s: usize, peripherals: &mut Peripherals) {
+        let (width, height) = if s == 0 { (16, 16) } else { (8, s) };
+        let bytes_per_row = width / 8;
+        let screen_width = peripherals.video_engine.width();
+        let screen_height = peripherals.video_engine.height();
+        let selected_planes = peripherals.video_engine.selected_planes();
+        // The starting position always wraps, the sprite itself only with the quirk
+        let x = self.reg_v[rx] as usize % screen_width;
+        let y = self.reg_v[ry] as usize % screen_height;
+        self.reg_v[0xF] = 0;
+        let mut mem_pos = self.reg_i as usize;
+        for plane in 0..NUM_PLANES {
+            let plane_mask = 1 << plane;
+            if selected_planes & plane_mask == 0 {
+                continue;
+            }
+            for yline in 0..height {
+                let mut pixel = 0u16;
+                for _ in 0..bytes_per_row {
+                    pixel = (pixel << 8) | self.memory_read(mem_pos) as u16;
+                    mem_pos += 1;
+                }
+                for xline in 0..width {
+                    if pixel & (1 << (width - 1 - xline)) != 0 {
+                        let (mut px, mut py) = (x + xline, y + yline);
+                        if self.quirks.sprite_wrap {
+                            px %= screen_width;
+                            py %= screen_height;
+                        }
+                        let collision = peripherals.video_engine.flip_pixel(px, py, plane_mask);
+                        if collision {
+                            self.reg_v[0xF] = 1;
+                        }
+                        if let Some(TraceEntry { video: VideoUndo::Flips(ref mut flips), .. }) =
+                               self.tracing {
+                            flips.push((px, py, plane_mask));
+                        }
                     }
-                });
+                }
             }
         }
-        self.pc += 2;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_block(&self.mem);
+        writer.put_bytes(&self.reg_v);
+        writer.put_u16(self.reg_i);
+        writer.put_u8(self.timers.delay());
+        writer.put_u8(self.timers.sound());
+        writer.put_u16(self.pc as u16);
+        writer.put_u8(self.sp as u8);
+        for addr in &self.stack {
+            writer.put_u16(*addr as u16);
+        }
+        writer.put_bytes(&self.rpl);
+        writer.put_bool(self.vblank);
+        writer.put_bool(self.halted);
+        writer.put_u8(match self.key_wait {
+            Some(key) => 0x10 | u8::from(key),
+            None => 0,
+        });
+        writer.put_u64(self.rng.state());
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.mem = reader.get_block(MEM_SIZE)?.to_vec();
+        self.decoded.clear();
+        self.reg_v = reader.get_bytes(NUM_REGISTERS)?.to_vec();
+        self.reg_i = reader.get_u16()?;
+        self.timers.set_delay(reader.get_u8()?);
+        self.timers.set_sound(reader.get_u8()?);
+        self.pc = reader.get_u16()? as usize;
+        self.sp = reader.get_u8()? as usize;
+        if self.sp > STACK_SIZE {
+            return Err(SaveStateError::Corrupt(format!("stack pointer {} out of range", self.sp)));
+        }
+        for i in 0..STACK_SIZE {
+            self.stack[i] = reader.get_u16()? as usize;
+        }
+        self.rpl = reader.get_bytes(NUM_RPL_FLAGS)?.to_vec();
+        self.vblank = reader.get_bool()?;
+        self.halted = reader.get_bool()?;
+        self.key_wait = match reader.get_u8()? {
+            0 => None,
+            code if code & 0xF0 == 0x10 => Some(key_from_index(code as usize & 0xF)),
+            code => return Err(SaveStateError::Corrupt(format!("invalid key wait {}", code))),
+        };
+        self.rng.set_state(reader.get_u64()?).map_err(SaveStateError::Corrupt)?;
+        // The trace belongs to the timeline being left
+        self.trace.clear();
+        Ok(())
+    }
+
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    /// The last executed instructions
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Sets how many instructions are traced, 0 disabling the trace
+    pub fn set_trace_length(&mut self, length: usize) {
+        self.trace.set_capacity(length);
+    }
+
+    /// Turns the decoded instruction cache on or off. It is on by default,
+    /// turning it off decodes every instruction as it runs.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decoded.set_enabled(enabled);
+    }
+
+    /// Stops on `kind` accesses to `addr`, replacing any watchpoint there
+    pub fn add_watchpoint(&mut self, addr: usize, kind: WatchKind) {
+        self.watchpoints.insert(addr, kind);
+    }
+
+    /// Returns false if there was no watchpoint at `addr`
+    pub fn remove_watchpoint(&mut self, addr: usize) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    pub fn watchpoints(&self) -> &HashMap<usize, WatchKind> {
+        &self.watchpoints
+    }
+
+    /// Watched accesses made since the last call, in execution order
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        self.watch_hits.drain(..).collect()
+    }
+
+    /// The seed the random generator started from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restarts the random generator from `seed`, making `CXNN` repeatable
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng.reseed(seed);
+    }
+
+    /// True when the current instruction is a jump to itself, the usual way
+    /// for a ROM to stop once it is done
+    pub fn is_spinning(&self) -> bool {
+        self.pc <= 0xFFF && self.opcode_at(self.pc) == Some(0x1000 | self.pc as u16)
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
     }
 
     pub fn pc(&self) -> usize {
@@ -271,11 +816,11 @@ impl Chip8 {
     }
 
     pub fn reg_delay_timer(&self) -> u8 {
-        self.reg_delay_timer.load(Ordering::SeqCst) as _
+        self.timers.delay()
     }
 
     pub fn reg_sound_timer(&self) -> u8 {
-        self.reg_sound_timer
+        self.timers.sound()
     }
 
     pub fn stack(&self) -> &Vec<usize> {
@@ -285,4 +830,506 @@ impl Chip8 {
     pub fn sp(&self) -> usize {
         self.sp
     }
+
+    pub fn rpl(&self) -> &Vec<u8> {
+        &self.rpl
+    }
+}
+
+/// Registers, stack and RPL flags from before the instruction being traced,
+/// kept in place so that tracing does not allocate them on every step
+#[derive(Clone, Default)]
+struct TraceStart {
+    regs: [u8; NUM_REGISTERS],
+    stack: [usize; STACK_SIZE],
+    rpl: [u8; NUM_RPL_FLAGS],
+}
+
+/// Instructions decoded so far, by address. Writes drop the ones they
+/// overlap, so self-modifying code is decoded again.
+///
+/// The table is only allocated once something is decoded, and clones start
+/// empty: copies of the machine, such as the one a save state is loaded
+/// into, don't carry it and rebuild it as code runs.
+struct DecodeCache {
+    entries: Vec<Option<Instruction>>,
+    enabled: bool,
+}
+
+impl DecodeCache {
+    fn new() -> Self {
+        DecodeCache {
+            entries: Vec::new(),
+            enabled: true,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.clear();
+    }
+
+    fn get(&self, pc: usize) -> Option<Instruction> {
+        self.entries.get(pc).and_then(|cached| *cached)
+    }
+
+    fn insert(&mut self, pc: usize, instruction: Instruction) {
+        if !self.enabled {
+            return;
+        }
+        if self.entries.is_empty() {
+            self.entries = vec![None; MEM_SIZE];
+        }
+        self.entries[pc] = Some(instruction);
+    }
+
+    /// Drops the cached instructions that contain the byte at `pos`
+    fn forget(&mut self, pos: usize) {
+        if self.entries.is_empty() {
+            return;
+        }
+        self.entries[pos] = None;
+        if pos > 0 {
+            self.entries[pos - 1] = None;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries = Vec::new();
+    }
+}
+
+impl Clone for DecodeCache {
+    fn clone(&self) -> Self {
+        DecodeCache {
+            entries: Vec::new(),
+            enabled: self.enabled,
+        }
+    }
+}
+
+/// Registers touched by the XO-CHIP `5XY2`/`5XY3` range transfers, in
+/// transfer order. The range is walked backwards when X is greater than Y.
+fn register_range(vr: usize, vy: usize) -> Vec<usize> {
+    if vr <= vy {
+        (vr..vy + 1).collect()
+    } else {
+        (vy..vr + 1).rev().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peripherals::Key;
+
+    /// Runs the first `steps` instructions of a ROM
+    fn run(rom: &[u8], steps: usize) -> Chip8 {
+        run_with(rom, steps, &mut Peripherals::new())
+    }
+
+    fn run_with(rom: &[u8], steps: usize, peripherals: &mut Peripherals) -> Chip8 {
+        let mut chip8 = Chip8::new(rom, Quirks::vip());
+        for _ in 0..steps {
+            chip8.step(peripherals).unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn cls_clears_the_screen() {
+        let mut peripherals = Peripherals::new();
+        peripherals.video_engine.flip_pixel(3, 4, 1);
+        let chip8 = run_with(&[0x00, 0xE0], 1, &mut peripherals);
+        assert_eq!(peripherals.video_engine.pixel(3, 4), 0);
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    #[test]
+    fn jsr_and_ret_use_the_stack() {
+        // 0x200: call 0x206, 0x202: v1 := 1, 0x206: return
+        let rom = [0x22, 0x06, 0x61, 0x01, 0x00, 0x00, 0x00, 0xEE];
+        let chip8 = run(&rom, 1);
+        assert_eq!((chip8.pc(), chip8.sp(), chip8.stack()[0]), (0x206, 1, 0x200));
+        let chip8 = run(&rom, 3);
+        assert_eq!((chip8.pc(), chip8.sp(), chip8.reg_v()[1]), (0x204, 0, 1));
+    }
+
+    #[test]
+    fn sys_is_ignored() {
+        let chip8 = run(&[0x03, 0x00], 1);
+        assert_eq!(chip8.pc(), 0x202);
+        assert!(chip8.reg_v().iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn jmp_and_jmi_set_the_pc() {
+        assert_eq!(run(&[0x13, 0x45], 1).pc(), 0x345);
+        // BNNN adds V0 without the jump_vx quirk
+        assert_eq!(run(&[0x60, 0x04, 0x61, 0x10, 0xB3, 0x00], 3).pc(), 0x304);
+    }
+
+    #[test]
+    fn skips_compare_registers_and_constants() {
+        // 3XNN, 4XNN, 5XY0 and 9XY0 with V1 = 5 and V2 = 5 or 6
+        assert_eq!(run(&[0x61, 0x05, 0x31, 0x05], 2).pc(), 0x206);
+        assert_eq!(run(&[0x61, 0x05, 0x31, 0x06], 2).pc(), 0x204);
+        assert_eq!(run(&[0x61, 0x05, 0x41, 0x06], 2).pc(), 0x206);
+        assert_eq!(run(&[0x61, 0x05, 0x41, 0x05], 2).pc(), 0x204);
+        assert_eq!(run(&[0x61, 0x05, 0x62, 0x05, 0x51, 0x20], 3).pc(), 0x208);
+        assert_eq!(run(&[0x61, 0x05, 0x62, 0x06, 0x51, 0x20], 3).pc(), 0x206);
+        assert_eq!(run(&[0x61, 0x05, 0x62, 0x06, 0x91, 0x20], 3).pc(), 0x208);
+        assert_eq!(run(&[0x61, 0x05, 0x62, 0x05, 0x91, 0x20], 3).pc(), 0x206);
+    }
+
+    #[test]
+    fn skips_step_over_long_loads() {
+        assert_eq!(run(&[0x31, 0x00, 0xF0, 0x00, 0x12, 0x34], 1).pc(), 0x206);
+    }
+
+    #[test]
+    fn mov_and_add_leave_vf_alone() {
+        let chip8 = run(&[0x61, 0xFF, 0x71, 0x02], 2);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0x01, 0));
+        // As the destination, VF just holds the wrapped sum
+        assert_eq!(run(&[0x6F, 0xFF, 0x7F, 0x02], 2).reg_v()[0xF], 0x01);
+        assert_eq!(run(&[0x62, 0x2A, 0x81, 0x20], 2).reg_v()[1], 0x2A);
+    }
+
+    #[test]
+    fn logic_operations() {
+        let rom = |op: u8| [0x6F, 0x07, 0x61, 0x0C, 0x62, 0x0A, 0x81, 0x20 | op];
+        let chip8 = run(&rom(1), 4);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0x0E, 0));
+        assert_eq!(run(&rom(2), 4).reg_v()[1], 0x08);
+        assert_eq!(run(&rom(3), 4).reg_v()[1], 0x06);
+        // VF as the destination ends up reset
+        assert_eq!(run(&[0x6F, 0x0C, 0x61, 0x0A, 0x8F, 0x11], 3).reg_v()[0xF], 0);
+    }
+
+    #[test]
+    fn arithmetic_sets_the_flag() {
+        let rom = |op: u8| [0x61, 0x05, 0x62, 0x03, 0x81, 0x20 | op];
+        let chip8 = run(&rom(4), 3);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0x08, 0));
+        let chip8 = run(&rom(5), 3);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0x02, 1));
+        let chip8 = run(&rom(7), 3);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0xFE, 0));
+        let chip8 = run(&[0x61, 0xFF, 0x62, 0x03, 0x81, 0x24], 3);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0x02, 1));
+    }
+
+    #[test]
+    fn shifts_keep_the_bit_shifted_out() {
+        // Without the quirk disabled, VY is shifted into VX
+        let chip8 = run(&[0x62, 0x07, 0x81, 0x26], 2);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0x03, 1));
+        let chip8 = run(&[0x62, 0x41, 0x81, 0x2E], 2);
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[0xF]), (0x82, 0));
+    }
+
+    #[test]
+    fn flag_wins_when_vf_is_the_destination() {
+        // 8XY4: 0xFF + 0x02 carries, 0x05 + 0x03 does not
+        assert_eq!(run(&[0x6F, 0xFF, 0x61, 0x02, 0x8F, 0x14], 3).reg_v[0xF], 1);
+        assert_eq!(run(&[0x6F, 0x05, 0x61, 0x03, 0x8F, 0x14], 3).reg_v[0xF], 0);
+        // 8XY5: 5 - 3 does not borrow, 3 - 5 does
+        assert_eq!(run(&[0x6F, 0x05, 0x61, 0x03, 0x8F, 0x15], 3).reg_v[0xF], 1);
+        assert_eq!(run(&[0x6F, 0x03, 0x61, 0x05, 0x8F, 0x15], 3).reg_v[0xF], 0);
+        // 8XY7: 3 - 5 borrows, 5 - 3 does not
+        assert_eq!(run(&[0x6F, 0x05, 0x61, 0x03, 0x8F, 0x17], 3).reg_v[0xF], 0);
+        assert_eq!(run(&[0x6F, 0x03, 0x61, 0x05, 0x8F, 0x17], 3).reg_v[0xF], 1);
+        // 8XY6 and 8XYE keep the bit shifted out
+        assert_eq!(run(&[0x6F, 0x03, 0x8F, 0xF6], 2).reg_v[0xF], 1);
+        assert_eq!(run(&[0x6F, 0x02, 0x8F, 0xF6], 2).reg_v[0xF], 0);
+        assert_eq!(run(&[0x6F, 0x81, 0x8F, 0xFE], 2).reg_v[0xF], 1);
+        assert_eq!(run(&[0x6F, 0x01, 0x8F, 0xFE], 2).reg_v[0xF], 0);
+    }
+
+    #[test]
+    fn vf_as_an_operand_is_read_before_the_flag_is_written() {
+        let result = |rom: &[u8]| {
+            let chip8 = run(rom, 3);
+            (chip8.reg_v[1], chip8.reg_v[0xF])
+        };
+        // 8XY4: 0xFF + VF = 0x02 carries
+        assert_eq!(result(&[0x61, 0xFF, 0x6F, 0x02, 0x81, 0xF4]), (0x01, 1));
+        // 8XY5: 5 - VF = 3 does not borrow
+        assert_eq!(result(&[0x61, 0x05, 0x6F, 0x03, 0x81, 0xF5]), (0x02, 1));
+        // 8XY7: VF = 3 - 5 borrows
+        assert_eq!(result(&[0x61, 0x05, 0x6F, 0x03, 0x81, 0xF7]), (0xFE, 0));
+        // 8XY6 and 8XYE shift VF into V1 on the VIP
+        assert_eq!(result(&[0x61, 0x00, 0x6F, 0x03, 0x81, 0xF6]), (0x01, 1));
+        assert_eq!(result(&[0x61, 0x00, 0x6F, 0x81, 0x81, 0xFE]), (0x02, 1));
+    }
+
+    #[test]
+    fn index_register_instructions() {
+        assert_eq!(run(&[0xA1, 0x23], 1).reg_i(), 0x123);
+        assert_eq!(run(&[0xA1, 0x00, 0x61, 0x05, 0xF1, 0x1E], 3).reg_i(), 0x105);
+        assert_eq!(run(&[0x61, 0x0A, 0xF1, 0x29], 2).reg_i(), 50);
+        let chip8 = run(&[0x61, 0x0A, 0xF1, 0x30], 2);
+        assert_eq!(chip8.reg_i() as usize, BIG_FONT_BASE_ADDR + 100);
+    }
+
+    #[test]
+    fn rnd_is_masked() {
+        assert_eq!(run(&[0x61, 0x55, 0xC1, 0x00], 2).reg_v()[1], 0);
+        for _ in 0..20 {
+            assert!(run(&[0xC1, 0x0F], 1).reg_v()[1] <= 0x0F);
+        }
+    }
+
+    #[test]
+    fn sprites_flip_pixels_and_report_collisions() {
+        // Digit 0 from the font at (1, 2): F0 90 90 90 F0
+        let rom = [0x61, 0x01, 0x62, 0x02, 0xA0, 0x00, 0xD1, 0x25, 0xD1, 0x25];
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = run_with(&rom, 4, &mut peripherals);
+        assert_eq!(peripherals.video_engine.pixel(1, 2), 1);
+        assert_eq!(peripherals.video_engine.pixel(4, 3), 1);
+        assert_eq!(peripherals.video_engine.pixel(2, 3), 0);
+        assert_eq!((chip8.reg_v()[0xF], chip8.pc()), (0, 0x208));
+        chip8.tick_timers(&mut peripherals);
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!(peripherals.video_engine.pixel(1, 2), 0);
+        assert_eq!((chip8.reg_v()[0xF], chip8.pc()), (1, 0x20A));
+    }
+
+    #[test]
+    fn key_skips_follow_the_keypad() {
+        let mut peripherals = Peripherals::new();
+        peripherals.keypad.set_button_state(Key::Key5, true);
+        assert_eq!(run_with(&[0x61, 0x05, 0xE1, 0x9E], 2, &mut peripherals).pc(), 0x206);
+        assert_eq!(run_with(&[0x61, 0x05, 0xE1, 0xA1], 2, &mut peripherals).pc(), 0x204);
+        assert_eq!(run_with(&[0x61, 0x06, 0xE1, 0x9E], 2, &mut peripherals).pc(), 0x204);
+        assert_eq!(run_with(&[0x61, 0x06, 0xE1, 0xA1], 2, &mut peripherals).pc(), 0x206);
+    }
+
+    #[test]
+    fn key_waits_for_a_press_and_release() {
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = run_with(&[0xF1, 0x0A], 1, &mut peripherals);
+        assert_eq!(chip8.pc(), 0x200);
+        peripherals.keypad.set_button_state(Key::Key3, true);
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!(chip8.pc(), 0x200);
+        peripherals.keypad.set_button_state(Key::Key3, false);
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!((chip8.reg_v()[1], chip8.pc()), (3, 0x202));
+    }
+
+    #[test]
+    fn key_ignores_keys_held_before_the_wait() {
+        let mut peripherals = Peripherals::new();
+        peripherals.keypad.set_button_state(Key::Key3, true);
+        peripherals.keypad.end_frame();
+        let mut chip8 = run_with(&[0xF1, 0x0A], 3, &mut peripherals);
+        assert_eq!(chip8.pc(), 0x200);
+        // A second key going down while the first is held is taken
+        peripherals.keypad.set_button_state(Key::Key5, true);
+        chip8.step(&mut peripherals).unwrap();
+        peripherals.keypad.set_button_state(Key::Key5, false);
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!((chip8.reg_v()[1], chip8.pc()), (5, 0x202));
+    }
+
+    #[test]
+    fn key_takes_each_press_once() {
+        // Two waits in a row, with a single tap of key 7
+        let mut peripherals = Peripherals::new();
+        peripherals.keypad.set_button_state(Key::Key7, true);
+        peripherals.keypad.set_button_state(Key::Key7, false);
+        let mut chip8 = run_with(&[0xF1, 0x0A, 0xF2, 0x0A, 0x12, 0x04], 5, &mut peripherals);
+        assert_eq!((chip8.reg_v()[1], chip8.pc()), (7, 0x202));
+        peripherals.keypad.set_button_state(Key::KeyC, true);
+        peripherals.keypad.set_button_state(Key::KeyC, false);
+        chip8.step(&mut peripherals).unwrap();
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!((chip8.reg_v()[2], chip8.pc()), (0xC, 0x204));
+    }
+
+    #[test]
+    fn timers_are_set_and_read() {
+        let chip8 = run(&[0x61, 0x07, 0xF1, 0x15, 0xF2, 0x07, 0xF1, 0x18], 4);
+        assert_eq!(chip8.reg_delay_timer(), 7);
+        assert_eq!(chip8.reg_sound_timer(), 7);
+        assert_eq!(chip8.reg_v()[2], 7);
+    }
+
+    #[test]
+    fn bcd_stores_three_digits() {
+        let chip8 = run(&[0x61, 0xFE, 0xA3, 0x00, 0xF1, 0x33], 3);
+        assert_eq!(&chip8.mem()[0x300..0x303], &[2, 5, 4]);
+        assert_eq!(chip8.reg_i(), 0x300);
+    }
+
+    #[test]
+    fn registers_are_stored_and_loaded() {
+        let chip8 = run(&[0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0xA3, 0x00, 0xF2, 0x55], 5);
+        assert_eq!(&chip8.mem()[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(chip8.reg_i(), 0x303);
+        // 0x208 holds the data loaded into V0-VF
+        let mut rom = vec![0xA2, 0x08, 0xFF, 0x65, 0x12, 0x04, 0x00, 0x00];
+        rom.extend(1..17);
+        let chip8 = run(&rom, 2);
+        assert_eq!(chip8.reg_v()[0], 1);
+        assert_eq!(chip8.reg_v()[0xF], 16);
+        assert_eq!(chip8.reg_i(), 0x218);
+    }
+
+    #[test]
+    fn rpl_flags_are_stored_and_loaded() {
+        let chip8 = run(&[0x60, 0x04, 0x61, 0x05, 0xF1, 0x75, 0x60, 0x00, 0xF1, 0x85], 5);
+        assert_eq!(&chip8.rpl()[0..3], &[4, 5, 0]);
+        assert_eq!(&chip8.reg_v()[0..2], &[4, 5]);
+    }
+
+    #[test]
+    fn schip_screen_instructions() {
+        let mut peripherals = Peripherals::new();
+        run_with(&[0x00, 0xFF], 1, &mut peripherals);
+        assert_eq!(peripherals.video_engine.width(), 128);
+        run_with(&[0x00, 0xFE], 1, &mut peripherals);
+        assert_eq!(peripherals.video_engine.width(), 64);
+        // Scroll a lit pixel down 2, right 4, left 4 and up 1
+        peripherals.video_engine.flip_pixel(0, 0, 1);
+        run_with(&[0x00, 0xC2, 0x00, 0xFB], 2, &mut peripherals);
+        assert_eq!(peripherals.video_engine.pixel(4, 2), 1);
+        run_with(&[0x00, 0xFC, 0x00, 0xD1], 2, &mut peripherals);
+        assert_eq!(peripherals.video_engine.pixel(0, 1), 1);
+    }
+
+    #[test]
+    fn exit_halts() {
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = run_with(&[0x00, 0xFD], 1, &mut peripherals);
+        assert!(chip8.is_halted());
+        assert_eq!(chip8.step(&mut peripherals).unwrap(), StepOutcome::Halted);
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    #[test]
+    fn register_ranges_are_stored_and_loaded() {
+        // V1 = 7, V2 = 9 saved at 0x300, loaded back into V4-V3 reversed
+        let rom = [0x61, 0x07, 0x62, 0x09, 0xA3, 0x00, 0x51, 0x22, 0x54, 0x33];
+        let chip8 = run(&rom, 5);
+        assert_eq!(&chip8.mem()[0x300..0x302], &[7, 9]);
+        assert_eq!((chip8.reg_v()[3], chip8.reg_v()[4]), (9, 7));
+        assert_eq!(chip8.reg_i(), 0x300);
+    }
+
+    #[test]
+    fn long_load_skips_its_address() {
+        let chip8 = run(&[0xF0, 0x00, 0x30, 0x00], 1);
+        assert_eq!((chip8.reg_i(), chip8.pc()), (0x3000, 0x204));
+    }
+
+    #[test]
+    fn planes_select_where_sprites_draw() {
+        let mut peripherals = Peripherals::new();
+        run_with(&[0xF2, 0x01, 0xA0, 0x00, 0xD0, 0x01], 3, &mut peripherals);
+        assert_eq!(peripherals.video_engine.selected_planes(), 2);
+        assert_eq!(peripherals.video_engine.pixel(0, 0), 2);
+    }
+
+    #[test]
+    fn audio_and_pitch_advance() {
+        // i = 0x206, audio, pitch v1, then the pattern itself
+        let mut rom = vec![0xA2, 0x06, 0xF0, 0x02, 0xF1, 0x3A];
+        let pattern = [0xF0, 0x0F, 0xAA, 0x55, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0xFE, 0xFF];
+        rom.extend_from_slice(&pattern);
+        let mut peripherals = Peripherals::new();
+        assert_eq!(peripherals.sound.pattern(), None);
+        let mut chip8 = run_with(&rom, 2, &mut peripherals);
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(peripherals.sound.pattern(), Some(pattern));
+        chip8.reg_v[1] = 0x70;
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!(chip8.pc(), 0x206);
+        assert_eq!(peripherals.sound.pitch(), 0x70);
+    }
+
+    #[test]
+    fn trace_is_off_by_default() {
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = run_with(&[0x61, 0x01, 0x71, 0x01], 2, &mut peripherals);
+        assert!(chip8.trace().is_empty());
+        assert!(chip8.step_back(&mut peripherals).is_none());
+        assert_eq!(chip8.reg_v()[1], 2);
+    }
+
+    #[test]
+    fn step_back_restores_each_previous_state() {
+        // rnd, font, sprite, bcd, store, call, cls, return, hires, scroll
+        let rom = [0xC0, 0x3F, 0xC1, 0x1F, 0xF0, 0x29, 0xD0, 0x15, 0xA3, 0x00, 0xF0, 0x33,
+                   0xF2, 0x55, 0x22, 0x14, 0x00, 0xFF, 0x00, 0xC2, 0x00, 0xE0, 0x00, 0xEE];
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(&rom, Quirks::vip());
+        chip8.set_trace_length(100);
+        let mut states = Vec::new();
+        for _ in 0..12 {
+            states.push(savestate::save(&chip8, &peripherals, 0));
+            chip8.step(&mut peripherals).unwrap();
+        }
+        assert_eq!(chip8.trace().len(), 12);
+        for (steps, state) in states.iter().enumerate().rev() {
+            assert!(chip8.step_back(&mut peripherals).is_some());
+            assert!(savestate::save(&chip8, &peripherals, 0) == *state,
+                    "State {} differs",
+                    steps);
+        }
+        assert!(chip8.step_back(&mut peripherals).is_none());
+    }
+
+    #[test]
+    fn step_back_undoes_self_modifying_code() {
+        // v1 := 1, then store v0 = 0x62 over it, turning it into v2 := 1
+        let rom = [0x61, 0x01, 0x60, 0x62, 0xA2, 0x00, 0xF0, 0x55, 0x12, 0x00];
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(&rom, Quirks::vip());
+        chip8.set_trace_length(100);
+        for _ in 0..6 {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        assert_eq!(chip8.reg_v()[2], 1);
+        // Back to the start, where the original instruction runs again
+        for _ in 0..6 {
+            chip8.step_back(&mut peripherals).unwrap();
+        }
+        assert_eq!((chip8.pc(), chip8.mem()[0x200]), (0x200, 0x61));
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[2]), (1, 0));
+    }
+
+    #[test]
+    fn waiting_is_traced_once() {
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(&[0x61, 0x05, 0xF1, 0x0A], Quirks::vip());
+        chip8.set_trace_length(100);
+        for _ in 0..20 {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        assert_eq!(chip8.trace().len(), 2);
+        assert_eq!(chip8.step_back(&mut peripherals).unwrap().pc, 0x202);
+        assert_eq!(chip8.step_back(&mut peripherals).unwrap().pc, 0x200);
+        assert_eq!((chip8.pc(), chip8.reg_v()[1]), (0x200, 0));
+    }
+
+    #[test]
+    fn self_modifying_code_runs_the_same_without_the_decode_cache() {
+        // v1 := 1, then store v0 = 0x62 over it, turning it into v2 := 1
+        let rom = [0x61, 0x01, 0x60, 0x62, 0xA2, 0x00, 0xF0, 0x55, 0x12, 0x00];
+        let mut peripherals = Peripherals::new();
+        let mut cached = Chip8::new(&rom, Quirks::vip());
+        let mut uncached = cached.clone();
+        uncached.set_decode_cache(false);
+        for _ in 0..12 {
+            cached.step(&mut peripherals).unwrap();
+            uncached.step(&mut peripherals).unwrap();
+            assert_eq!((cached.pc(), cached.reg_v()), (uncached.pc(), uncached.reg_v()));
+        }
+        assert_eq!(uncached.reg_v()[2], 1);
+    }
 }
@@ -0,0 +1,197 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rate of the Chip8 timers and of the display refresh
+pub const FRAME_RATE: u32 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: usize = 10;
+
+/// Frames the pacer may fall behind before it gives up catching up
+const MAX_FRAMES_BEHIND: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockConfig {
+    /// Instructions executed between two 60 Hz frames
+    pub instructions_per_frame: usize,
+    /// When false frames are run back to back, for benchmarking
+    pub throttle: bool,
+}
+
+impl ClockConfig {
+    /// Builds a configuration running roughly `hz` instructions per second
+    pub fn from_hz(hz: u32) -> Self {
+        let per_frame = (hz + FRAME_RATE / 2) / FRAME_RATE;
+        ClockConfig {
+            instructions_per_frame: if per_frame > 0 { per_frame as usize } else { 1 },
+            throttle: true,
+        }
+    }
+
+    pub fn hz(&self) -> u32 {
+        self.instructions_per_frame as u32 * FRAME_RATE
+    }
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            throttle: true,
+        }
+    }
+}
+
+/// Source of time for the `FramePacer`
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// The host's monotonic clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Keeps frames on a 60 Hz wall-clock grid.
+///
+/// Deadlines are computed from the number of frames since the last reset
+/// rather than by adding a rounded frame period, so no drift accumulates. A
+/// late frame simply gets a shorter sleep, and if the host falls too far
+/// behind the grid is re-anchored instead of bursting through the backlog.
+pub struct FramePacer<C: Clock = SystemClock> {
+    clock: C,
+    origin: Instant,
+    frames: u64,
+}
+
+impl FramePacer<SystemClock> {
+    pub fn new() -> Self {
+        FramePacer::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> FramePacer<C> {
+    pub fn with_clock(clock: C) -> Self {
+        FramePacer {
+            origin: clock.now(),
+            clock: clock,
+            frames: 0,
+        }
+    }
+
+    /// Restarts pacing from now, e.g. after the emulation has been paused
+    pub fn reset(&mut self) {
+        self.origin = self.clock.now();
+        self.frames = 0;
+    }
+
+    /// Sleeps until the end of the current frame
+    pub fn wait(&mut self) {
+        self.frames += 1;
+        let deadline = self.origin + frames_to_duration(self.frames);
+        let now = self.clock.now();
+        if deadline > now {
+            self.clock.sleep(deadline - now);
+        } else if now - deadline > frames_to_duration(MAX_FRAMES_BEHIND) {
+            self.reset();
+        }
+    }
+}
+
+fn frames_to_duration(frames: u64) -> Duration {
+    let nanos = frames * 1_000_000_000 / FRAME_RATE as u64;
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clock that only moves when told to, recording the sleeps
+    struct FakeClock {
+        now: Instant,
+        sleeps: Vec<Duration>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration;
+            self.sleeps.push(duration);
+        }
+    }
+
+    fn pacer() -> FramePacer<FakeClock> {
+        FramePacer::with_clock(FakeClock {
+            now: Instant::now(),
+            sleeps: Vec::new(),
+        })
+    }
+
+    /// Runs frames that each take `work` before waiting, and returns the
+    /// sleeps they got
+    fn run(pacer: &mut FramePacer<FakeClock>, work: &[u64]) -> Vec<Duration> {
+        pacer.clock.sleeps.clear();
+        for &millis in work {
+            pacer.clock.now += Duration::from_millis(millis);
+            pacer.wait();
+        }
+        pacer.clock.sleeps.clone()
+    }
+
+    fn micros(duration: Duration) -> u64 {
+        duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1000
+    }
+
+    #[test]
+    fn frames_sleep_out_the_rest_of_their_period() {
+        let mut pacer = pacer();
+        let start = pacer.clock.now;
+        let sleeps = run(&mut pacer, &[5; 60]);
+        assert_eq!(sleeps.len(), 60);
+        assert!(sleeps.iter().all(|sleep| micros(*sleep) == 11666 || micros(*sleep) == 11667),
+                "{:?}",
+                sleeps);
+        // Rounding never adds up: 60 frames take exactly a second
+        assert_eq!(pacer.clock.now - start, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn late_frames_are_made_up_by_the_next_ones() {
+        let mut pacer = pacer();
+        let sleeps = run(&mut pacer, &[20, 5, 5]);
+        let sleeps: Vec<u64> = sleeps.into_iter().map(micros).collect();
+        // The first frame overran by 3.3 ms, the second one only sleeps
+        // until 33.3 ms
+        assert_eq!(sleeps, vec![8333, 11666]);
+    }
+
+    #[test]
+    fn falling_far_behind_restarts_the_grid() {
+        let mut pacer = pacer();
+        assert!(run(&mut pacer, &[100]).is_empty());
+        // No burst of frames to catch up, the next one gets a full period
+        let sleeps = run(&mut pacer, &[0, 0]);
+        assert_eq!(sleeps,
+                   vec![frames_to_duration(1), frames_to_duration(2) - frames_to_duration(1)]);
+    }
+
+    #[test]
+    fn reset_starts_a_new_grid_from_now() {
+        let mut pacer = pacer();
+        run(&mut pacer, &[1, 1]);
+        pacer.clock.now += Duration::from_millis(50);
+        pacer.reset();
+        assert_eq!(run(&mut pacer, &[0]), vec![frames_to_duration(1)]);
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+
+use chip8::Chip8;
+use debugger::expr::Expr;
+
+/// A breakpoint condition, with the text it was parsed from for listings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        Ok(Condition {
+            text: text.trim().into(),
+            expr: Expr::parse(text)?,
+        })
+    }
+
+    pub fn is_true(&self, chip8: &Chip8) -> bool {
+        self.expr.is_true(chip8)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: usize,
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    /// Deleted the first time it stops the machine
+    pub temporary: bool,
+    /// Times the PC reached the breakpoint with its condition true
+    pub hits: usize,
+    /// Hits left to pass over before stopping
+    pub ignore: usize,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:<3} {:<6} {:<4} 0x{:03x}",
+               self.id,
+               if self.temporary { "tbreak" } else { "break" },
+               if self.enabled { "y" } else { "n" },
+               self.addr)?;
+        if let Some(ref condition) = self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        if self.hits > 0 {
+            write!(f, ", hit {} times", self.hits)?;
+        }
+        if self.ignore > 0 {
+            write!(f, ", ignoring the next {} hits", self.ignore)?;
+        }
+        Ok(())
+    }
+}
+
+/// The PC breakpoints, numbered from 1 in the order they were added
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints {
+            list: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Adds a breakpoint and returns its number
+    pub fn add(&mut self, addr: usize, condition: Option<Condition>, temporary: bool) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id: id,
+            addr: addr,
+            condition: condition,
+            enabled: true,
+            temporary: temporary,
+            hits: 0,
+            ignore: 0,
+        });
+        id
+    }
+
+    /// Returns false if there is no breakpoint `id`
+    pub fn delete(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|breakpoint| breakpoint.id != id);
+        self.list.len() != len
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.list.iter_mut().find(|breakpoint| breakpoint.id == id)
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Counts a hit on the enabled breakpoints at the PC whose condition
+    /// holds, and returns the number of the one stopping the machine, if
+    /// any. A temporary breakpoint is deleted when it stops it.
+    pub fn check(&mut self, chip8: &Chip8) -> Option<usize> {
+        let pc = chip8.pc();
+        let mut stop = None;
+        for breakpoint in self.list.iter_mut() {
+            if !breakpoint.enabled || breakpoint.addr != pc {
+                continue;
+            }
+            if let Some(ref condition) = breakpoint.condition {
+                if !condition.is_true(chip8) {
+                    continue;
+                }
+            }
+            breakpoint.hits += 1;
+            if breakpoint.ignore > 0 {
+                breakpoint.ignore -= 1;
+            } else if stop.is_none() {
+                stop = Some((breakpoint.id, breakpoint.temporary));
+            }
+        }
+        match stop {
+            Some((id, temporary)) => {
+                if temporary {
+                    self.delete(id);
+                }
+                Some(id)
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peripherals::Peripherals;
+    use quirks::Quirks;
+
+    /// Counts up in v0 forever: `add v0, 1` at 0x200, `jmp 0x200` at 0x202
+    const ROM: [u8; 4] = [0x70, 0x01, 0x12, 0x00];
+
+    /// Runs the loop `times` times, checking the breakpoints before each
+    /// instruction, and returns what stopped the machine with v0 at the time
+    fn stops(breakpoints: &mut Breakpoints, times: usize) -> Vec<(usize, u8)> {
+        let mut chip8 = Chip8::new(&ROM, Quirks::vip());
+        let mut peripherals = Peripherals::new();
+        let mut stops = Vec::new();
+        for _ in 0..2 * times {
+            if let Some(id) = breakpoints.check(&chip8) {
+                stops.push((id, chip8.reg_v()[0]));
+            }
+            chip8.step(&mut peripherals).unwrap();
+        }
+        stops
+    }
+
+    #[test]
+    fn breakpoints_stop_at_their_address() {
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0x202, None, false);
+        breakpoints.add(0x300, None, false);
+        assert_eq!(stops(&mut breakpoints, 3), vec![(id, 1), (id, 2), (id, 3)]);
+        assert_eq!(breakpoints.list()[0].hits, 3);
+        assert_eq!(breakpoints.list()[1].hits, 0);
+    }
+
+    #[test]
+    fn conditions_filter_hits() {
+        let mut breakpoints = Breakpoints::new();
+        let condition = Condition::parse(" v0 >= 3 && [pc] == 0x70 ").unwrap();
+        let id = breakpoints.add(0x200, Some(condition), false);
+        assert_eq!(stops(&mut breakpoints, 5), vec![(id, 3), (id, 4)]);
+        assert_eq!(breakpoints.list()[0].hits, 2);
+        assert_eq!(breakpoints.list()[0].to_string(),
+                   "1   break  y    0x200 if v0 >= 3 && [pc] == 0x70, hit 2 times");
+    }
+
+    #[test]
+    fn ignored_hits_are_counted_without_stopping() {
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0x200, None, false);
+        breakpoints.get_mut(id).unwrap().ignore = 2;
+        assert_eq!(breakpoints.list()[0].to_string(),
+                   "1   break  y    0x200, ignoring the next 2 hits");
+        assert_eq!(stops(&mut breakpoints, 4), vec![(id, 2), (id, 3)]);
+        assert_eq!((breakpoints.list()[0].hits, breakpoints.list()[0].ignore), (4, 0));
+    }
+
+    #[test]
+    fn temporary_breakpoints_are_deleted_when_they_stop() {
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0x200, None, true);
+        assert_eq!(breakpoints.list()[0].to_string(), "1   tbreak y    0x200");
+        assert_eq!(stops(&mut breakpoints, 3), vec![(id, 0)]);
+        assert!(breakpoints.is_empty());
+    }
+
+    #[test]
+    fn disabled_breakpoints_are_skipped() {
+        let mut breakpoints = Breakpoints::new();
+        let first = breakpoints.add(0x200, None, false);
+        let second = breakpoints.add(0x200, None, false);
+        // Both count the hit, the first one stops
+        assert_eq!(stops(&mut breakpoints, 1), vec![(first, 0)]);
+        assert_eq!(breakpoints.list()[1].hits, 1);
+        breakpoints.get_mut(first).unwrap().enabled = false;
+        assert_eq!(stops(&mut breakpoints, 1), vec![(second, 0)]);
+        assert_eq!(breakpoints.list()[0].hits, 1);
+        assert!(breakpoints.delete(second));
+        assert!(!breakpoints.delete(second));
+        assert_eq!(stops(&mut breakpoints, 1), vec![]);
+    }
+}
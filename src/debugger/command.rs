@@ -1,56 +1,363 @@
 use std::convert::TryFrom;
 
-#[derive(Debug, Copy, Clone)]
+use chip8::Chip8;
+use debugger::breakpoint::Condition;
+use debugger::expr::Operand;
+use debugger::lexer::{tokenize, Token};
+use debugger::watchpoint::WatchKind;
+
+/// A numeric argument, or a register read when the command runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Number(u32),
+    Operand(Operand),
+}
+
+impl Value {
+    pub fn resolve(&self, chip8: &Chip8) -> usize {
+        match *self {
+            Value::Number(n) => n as usize,
+            Value::Operand(operand) => operand.read(chip8) as usize,
+        }
+    }
+}
+
+/// A debugger command. Addresses for goto, break, watch and unwatch are
+/// hexadecimal even without a prefix, so `b 200` breaks at 0x200, while
+/// counts and breakpoint numbers are decimal unless prefixed.
+#[derive(Debug, Clone)]
 pub enum Command {
-    Goto { loc: usize },
+    Goto { loc: Value },
     Dump { count: usize },
     VideoRamDump,
     RegDump,
     StackDump,
     Disasm { count: usize },
-    Break { loc: usize },
+    Break {
+        loc: Value,
+        condition: Option<Condition>,
+        temporary: bool,
+    },
+    InfoBreaks,
+    Delete { id: usize },
+    Enable { id: usize },
+    Disable { id: usize },
+    Ignore { id: usize, count: usize },
+    Watch { addr: Value, kind: WatchKind },
+    Unwatch { addr: Value },
     Step,
+    ReverseStep,
+    History { count: usize },
+    Back { frames: usize },
     Run,
+    Save { path: String },
+    Load { path: String },
     Repeat,
     Quit,
 }
 
 impl TryFrom<String> for Command {
-    type Err = String;
-    fn try_from(text: String) -> Result<Self, Self::Err> {
-        let tokens: Vec<&str> = text.trim().split(' ').collect();
-        if !tokens.is_empty() {
-            match tokens[0] {
-                "goto" | "g" => {
-                    Ok(Command::Goto {
-                        loc: usize::from_str_radix(tokens[1], 16).expect("Cannot parse command"),
-                    })
+    type Error = String;
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let text = text.trim();
+        let (name, rest) = match text.find(char::is_whitespace) {
+            Some(end) => (&text[..end], text[end..].trim()),
+            None => (text, ""),
+        };
+
+        // File names are taken verbatim rather than tokenized
+        match name {
+            "save" => return path_arg(name, rest).map(|path| Command::Save { path: path }),
+            "load" => return path_arg(name, rest).map(|path| Command::Load { path: path }),
+            _ => {}
+        }
+
+        let radix = match name {
+            "goto" | "g" | "break" | "b" | "tbreak" | "tb" | "watch" | "w" | "unwatch" | "uw" => 16,
+            _ => 10,
+        };
+        let mut args = Args::new(name, rest, radix)?;
+        let command = match name {
+            "" => Command::Repeat,
+            "goto" | "g" => Command::Goto { loc: args.value("an address")? },
+            "disasm" | "d" => Command::Disasm { count: args.optional_count(1)? },
+            "vdump" | "vx" => Command::VideoRamDump,
+            "rdump" | "rx" => Command::RegDump,
+            "sdump" | "sx" => Command::StackDump,
+            "dump" | "x" => Command::Dump { count: args.optional_count(1)? },
+            "break" | "b" | "tbreak" | "tb" => {
+                Command::Break {
+                    loc: args.value("an address")?,
+                    condition: args.condition()?,
+                    temporary: name.starts_with('t'),
                 }
-                "disasm" | "d" => Ok(Command::Disasm { count: 1 }),
-                "vdump" | "vx" => Ok(Command::VideoRamDump),
-                "rdump" | "rx" => Ok(Command::RegDump),
-                "sdump" | "sx" => Ok(Command::StackDump),
-                "dump" | "x" => {
-                    let count = if tokens.len() > 1 {
-                        usize::from_str_radix(tokens[1], 10).unwrap_or(0)
-                    } else {
-                        1
-                    };
-                    Ok(Command::Dump { count: count })
+            }
+            "info" | "i" => {
+                match args.word("a topic")?.as_str() {
+                    "breaks" | "b" => Command::InfoBreaks,
+                    topic => return Err(format!("Unknown info topic {}, expected breaks", topic)),
                 }
-                "break" | "b" => {
-                    Ok(Command::Break {
-                        loc: usize::from_str_radix(tokens[1], 16).expect("Cannot parse command"),
-                    })
+            }
+            "delete" | "del" => Command::Delete { id: args.count("a breakpoint number")? },
+            "enable" => Command::Enable { id: args.count("a breakpoint number")? },
+            "disable" => Command::Disable { id: args.count("a breakpoint number")? },
+            "ignore" => {
+                Command::Ignore {
+                    id: args.count("a breakpoint number")?,
+                    count: args.count("an ignore count")?,
+                }
+            }
+            "watch" | "w" => {
+                let addr = args.value("an address")?;
+                // Like most debuggers, only watch writes by default
+                let kind = if args.is_done() {
+                    WatchKind::Write
+                } else {
+                    WatchKind::from_name(&args.word("a watch mode")?)?
+                };
+                Command::Watch {
+                    addr: addr,
+                    kind: kind,
                 }
-                "step" | "s" | "." => Ok(Command::Step),
-                "run" | "r" => Ok(Command::Run),
-                "quit" | "q" => Ok(Command::Quit),
-                "" => Ok(Command::Repeat),
-                _ => Err(format!("Unsupported command {}", text)),
             }
+            "unwatch" | "uw" => Command::Unwatch { addr: args.value("an address")? },
+            "step" | "s" | "." => Command::Step,
+            "rstep" | "rs" => Command::ReverseStep,
+            "history" | "h" => Command::History { count: args.optional_count(10)? },
+            "back" | "bk" => Command::Back { frames: args.optional_count(1)? },
+            "run" | "r" => Command::Run,
+            "quit" | "q" => Command::Quit,
+            _ => return Err(format!("Unknown command {}", name)),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+}
+
+fn path_arg(name: &str, rest: &str) -> Result<String, String> {
+    if rest.is_empty() {
+        Err(format!("Missing file name for {}", name))
+    } else {
+        Ok(rest.into())
+    }
+}
+
+/// The tokenized arguments of a command, consumed from left to right
+struct Args<'a> {
+    command: &'a str,
+    text: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl<'a> Args<'a> {
+    fn new(command: &'a str, text: &'a str, radix: u32) -> Result<Args<'a>, String> {
+        Ok(Args {
+            command: command,
+            text: text,
+            tokens: tokenize(text, radix)?,
+            pos: 0,
+        })
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|&(_, ref token)| token.clone());
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos == self.tokens.len()
+    }
+
+    /// A number or a register name
+    fn value(&mut self, what: &str) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Value::Number(n)),
+            Some(Token::Ident(name)) => {
+                Operand::from_name(&name)
+                    .map(Value::Operand)
+                    .ok_or_else(|| format!("Unknown register {}", name))
+            }
+            Some(token) => Err(self.unexpected(what, &token)),
+            None => Err(format!("Missing {} for {}", what, self.command)),
+        }
+    }
+
+    fn count(&mut self, what: &str) -> Result<usize, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(n as usize),
+            Some(token) => Err(self.unexpected(what, &token)),
+            None => Err(format!("Missing {} for {}", what, self.command)),
+        }
+    }
+
+    fn optional_count(&mut self, default: usize) -> Result<usize, String> {
+        if self.is_done() {
+            Ok(default)
         } else {
-            Ok(Command::Repeat)
+            self.count("a count")
+        }
+    }
+
+    fn word(&mut self, what: &str) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(word)) => Ok(word),
+            Some(token) => Err(self.unexpected(what, &token)),
+            None => Err(format!("Missing {} for {}", what, self.command)),
+        }
+    }
+
+    /// An optional `if <expression>`, which takes the rest of the arguments
+    fn condition(&mut self) -> Result<Option<Condition>, String> {
+        let offset = match self.tokens.get(self.pos) {
+            None => return Ok(None),
+            Some(&(offset, Token::Ident(ref word))) if word == "if" => offset,
+            Some(&(_, ref token)) => return Err(self.unexpected("if", token)),
+        };
+        self.pos = self.tokens.len();
+        Condition::parse(&self.text[offset + "if".len()..]).map(Some)
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        match self.tokens.get(self.pos) {
+            Some(&(_, ref token)) => {
+                Err(format!("Unexpected argument {} for {}", token, self.command))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn unexpected(&self, what: &str, token: &Token) -> String {
+        format!("Expected {} for {} but found {}", what, self.command, token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    fn parse(text: &str) -> Command {
+        Command::try_from(text.to_string()).unwrap()
+    }
+
+    fn address(text: &str) -> Value {
+        match parse(text) {
+            Command::Goto { loc } |
+            Command::Break { loc, .. } => loc,
+            Command::Watch { addr, .. } |
+            Command::Unwatch { addr } => addr,
+            command => panic!("{:?} has no address", command),
+        }
+    }
+
+    #[test]
+    fn bare_addresses_are_hexadecimal() {
+        assert_eq!(address("b 200"), Value::Number(0x200));
+        assert_eq!(address("tb 2a4"), Value::Number(0x2a4));
+        assert_eq!(address("goto fe"), Value::Number(0xfe));
+        assert_eq!(address("w 3E0 rw"), Value::Number(0x3e0));
+        assert_eq!(address("uw c"), Value::Number(0xc));
+        assert_eq!(address("g 0x2a4"), Value::Number(0x2a4));
+        assert_eq!(address("g $2A4"), Value::Number(0x2a4));
+    }
+
+    #[test]
+    fn addresses_can_be_registers() {
+        assert_eq!(address("  goto   I "), Value::Operand(Operand::I));
+        assert_eq!(address("b v3"), Value::Operand(Operand::V(3)));
+        assert_eq!(address("w dt"), Value::Operand(Operand::Delay));
+        let chip8 = Chip8::new(&[], Quirks::default());
+        assert_eq!(address("g pc").resolve(&chip8), 0x200);
+    }
+
+    #[test]
+    fn counts_are_decimal() {
+        match parse("d 10") {
+            Command::Disasm { count: 10 } => {}
+            command => panic!("{:?}", command),
+        }
+        match parse("x 0x10") {
+            Command::Dump { count: 16 } => {}
+            command => panic!("{:?}", command),
+        }
+        match parse("ignore 2 12") {
+            Command::Ignore { id: 2, count: 12 } => {}
+            command => panic!("{:?}", command),
+        }
+        assert!(Command::try_from("x 1f".to_string()).is_err());
+    }
+
+    #[test]
+    fn optional_arguments_have_defaults() {
+        match parse("d") {
+            Command::Disasm { count: 1 } => {}
+            command => panic!("{:?}", command),
+        }
+        match parse("h") {
+            Command::History { count: 10 } => {}
+            command => panic!("{:?}", command),
+        }
+        match parse("bk") {
+            Command::Back { frames: 1 } => {}
+            command => panic!("{:?}", command),
+        }
+        match parse("w 300") {
+            Command::Watch { kind: WatchKind::Write, .. } => {}
+            command => panic!("{:?}", command),
+        }
+        match parse("") {
+            Command::Repeat => {}
+            command => panic!("{:?}", command),
+        }
+    }
+
+    #[test]
+    fn conditions_take_the_rest_of_the_line() {
+        match parse("b 200 if [i] == $ff && v3 > 10") {
+            Command::Break { loc: Value::Number(0x200), condition: Some(condition), .. } => {
+                assert_eq!(condition.to_string(), "[i] == $ff && v3 > 10");
+            }
+            command => panic!("{:?}", command),
+        }
+        match parse("tb 2a4") {
+            Command::Break { condition: None, temporary: true, .. } => {}
+            command => panic!("{:?}", command),
+        }
+    }
+
+    #[test]
+    fn file_names_are_verbatim() {
+        match parse("save my state.bin") {
+            Command::Save { ref path } if path == "my state.bin" => {}
+            command => panic!("{:?}", command),
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        for text in &["b",
+                      "b zz",
+                      "g",
+                      "g v10",
+                      "g 1g",
+                      "x abc",
+                      "x -1",
+                      "x 99999999999",
+                      "d 1 2",
+                      "s 1",
+                      "save",
+                      "frob",
+                      "info x",
+                      "ignore x 1",
+                      "w 200 q",
+                      "b 200 if v3 ==",
+                      "b 200 v3",
+                      "g é"] {
+            assert!(Command::try_from(text.to_string()).is_err(), "{} was accepted", text);
         }
     }
 }
@@ -1,36 +1,51 @@
 use std::io;
 use std::io::prelude::*;
-use std::collections::HashSet;
 use std::thread;
 use std::time::Duration;
 use std::convert::TryFrom;
+use debugger::breakpoint::{Breakpoints, Condition};
 use debugger::command::Command;
+use debugger::watchpoint::WatchHit;
 use chip8::Chip8;
 use instruction::Instruction;
-use video_engine;
 use peripherals::Peripherals;
+use screenshot;
 use std::sync::mpsc::Receiver;
 
+/// Commands acting on what the emulator owns, such as the rewind history
+/// and the frame counter, handed over through `take_request`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Back { frames: usize },
+    Save { path: String },
+    Load { path: String },
+}
+
 pub struct Debugger {
-    breakpoints: HashSet<usize>,
+    breakpoints: Breakpoints,
     cursor: usize,
     last_command: Option<Command>,
+    request: Option<Request>,
     exit: bool,
 }
 
 impl Debugger {
     pub fn new() -> Self {
         Debugger {
-            breakpoints: HashSet::new(),
+            breakpoints: Breakpoints::new(),
             cursor: 0,
             last_command: None,
+            request: None,
             exit: false,
         }
     }
 
     fn step(&mut self, chip8: &mut Chip8, peripherals: &mut Peripherals) {
         self.disam_instr(chip8, chip8.pc());
-        chip8.step(peripherals);
+        if let Err(fault) = chip8.step(peripherals) {
+            println!("{}", fault);
+        }
+        report_watch_hits(chip8.take_watch_hits());
         thread::sleep(Duration::from_millis(10));
     }
 
@@ -66,42 +81,101 @@ impl Debugger {
                        -> bool {
         match cmd {
             Command::Repeat => {}
-            _ => self.last_command = Some(cmd),
+            _ => self.last_command = Some(cmd.clone()),
         };
         match cmd {
-            Command::Break { loc } => {
-                self.add_breakpoint(loc);
+            Command::Break { loc, condition, temporary } => {
+                self.add_breakpoint(loc.resolve(chip8), condition, temporary);
+                true
+            }
+            Command::InfoBreaks => {
+                self.list_breakpoints(chip8);
+                true
+            }
+            Command::Delete { id } => {
+                if self.breakpoints.delete(id) {
+                    println!("Breakpoint {} deleted", id);
+                } else {
+                    println!("No breakpoint {}", id);
+                }
+                true
+            }
+            Command::Enable { id } => {
+                self.set_enabled(id, true);
+                true
+            }
+            Command::Disable { id } => {
+                self.set_enabled(id, false);
+                true
+            }
+            Command::Ignore { id, count } => {
+                match self.breakpoints.get_mut(id) {
+                    Some(breakpoint) => {
+                        breakpoint.ignore = count;
+                        println!("Will ignore the next {} hits of breakpoint {}", count, id);
+                    }
+                    None => println!("No breakpoint {}", id),
+                }
+                true
+            }
+            Command::Watch { addr, kind } => {
+                let addr = addr.resolve(chip8);
+                chip8.add_watchpoint(addr, kind);
+                println!("Watchpoint ({}) installed at 0x{:03x}", kind, addr);
+                true
+            }
+            Command::Unwatch { addr } => {
+                let addr = addr.resolve(chip8);
+                if chip8.remove_watchpoint(addr) {
+                    println!("Watchpoint at 0x{:03x} removed", addr);
+                } else {
+                    println!("No watchpoint at 0x{:03x}", addr);
+                }
                 true
             }
             Command::Step => {
                 self.step(chip8, peripherals);
                 true
             }
+            Command::ReverseStep => {
+                match chip8.step_back(peripherals) {
+                    Some(entry) => println!("Undid {}", entry),
+                    None => println!("No instruction to undo"),
+                }
+                true
+            }
+            Command::History { count } => {
+                for entry in chip8.trace().last(count) {
+                    println!("{}", entry);
+                }
+                true
+            }
+            Command::Back { frames } => {
+                self.request = Some(Request::Back { frames: frames });
+                false
+            }
             Command::Run => false,
+            Command::Save { path } => {
+                self.request = Some(Request::Save { path: path });
+                false
+            }
+            Command::Load { path } => {
+                self.request = Some(Request::Load { path: path });
+                false
+            }
             Command::Quit => {
                 self.exit = true;
                 false
             }
             Command::Dump { count } => {
-                for i in 0..count {
-                    let pos = self.cursor + i;
+                let end = (self.cursor + count).min(chip8.mem().len());
+                for pos in self.cursor..end {
                     println!("[0x{:03x}] 0x{:x}", pos, chip8.mem()[pos])
                 }
                 true
             }
             Command::VideoRamDump => {
-                let vram = peripherals.video_engine.vram();
-                for (i, vram_i) in vram.iter().enumerate() {
-                    if i > 0 && i % 64 == 0 {
-                        println!();
-                    }
-                    match *vram_i {
-                        video_engine::BACK_COLOR => print!("0"),
-                        video_engine::FRONT_COLOR => print!("1"),
-                        other => panic!("Color {:x} not supported", other),
-                    }
-                }
-                println!();
+                print!("{}", screenshot::ascii(&peripherals.video_engine));
                 true
             }
             Command::RegDump => {
@@ -131,16 +205,18 @@ impl Debugger {
             Command::Disasm { count } => {
                 for i in 0..count {
                     let mem_pos = self.cursor + 2 * i;
-                    self.disam_instr(chip8, mem_pos)
+                    if !self.disam_instr(chip8, mem_pos) {
+                        break;
+                    }
                 }
                 true
             }
             Command::Goto { loc } => {
-                self.cursor = loc;
+                self.cursor = loc.resolve(chip8);
                 true
             }
             Command::Repeat => {
-                match self.last_command {
+                match self.last_command.clone() {
                     None => true,
                     Some(last_command) => self.execute_command(last_command, chip8, peripherals),
                 }
@@ -148,27 +224,84 @@ impl Debugger {
         }
     }
 
-    fn disam_instr(&self, chip8: &Chip8, mem_pos: usize) {
-        let hi_nibble = chip8.mem()[mem_pos] as u16;
-        let lo_nibble = chip8.mem()[mem_pos + 1] as u16;
+    /// Returns false past the end of memory
+    fn disam_instr(&self, chip8: &Chip8, mem_pos: usize) -> bool {
+        let mem = chip8.mem();
+        let (hi_nibble, lo_nibble) = match (mem.get(mem_pos), mem.get(mem_pos + 1)) {
+            (Some(&hi), Some(&lo)) => (hi as u16, lo as u16),
+            _ => {
+                println!("0x{:03x} is past the end of memory", mem_pos);
+                return false;
+            }
+        };
         let opcode = (hi_nibble << 8) | lo_nibble;
         match Instruction::try_from(opcode) {
             Ok(instruction) => println!("0x{0:03x} {1:?}", mem_pos, instruction),
             Err(_) => println!("0x{0:03x} dw 0x{1:x}", mem_pos, opcode),
         }
+        true
+    }
+
+    fn add_breakpoint(&mut self, loc: usize, condition: Option<Condition>, temporary: bool) {
+        let id = self.breakpoints.add(loc, condition, temporary);
+        println!("Breakpoint {} installed at 0x{:03x}", id, loc);
+    }
+
+    fn set_enabled(&mut self, id: usize, enabled: bool) {
+        match self.breakpoints.get_mut(id) {
+            Some(breakpoint) => breakpoint.enabled = enabled,
+            None => println!("No breakpoint {}", id),
+        }
     }
 
-    fn add_breakpoint(&mut self, loc: usize) {
-        println!("Breakpoint installed at 0x{:03x}", loc);
-        self.breakpoints.insert(loc);
+    fn list_breakpoints(&self, chip8: &Chip8) {
+        let mut watchpoints: Vec<_> = chip8.watchpoints().iter().collect();
+        watchpoints.sort_by_key(|&(addr, _)| *addr);
+        if self.breakpoints.is_empty() && watchpoints.is_empty() {
+            println!("No breakpoints or watchpoints");
+            return;
+        }
+        if !self.breakpoints.is_empty() {
+            println!("Num Type   Enb  Address");
+            for breakpoint in self.breakpoints.list() {
+                println!("{}", breakpoint);
+            }
+        }
+        for (addr, kind) in watchpoints {
+            println!("    watch  {:<4} 0x{:03x}", kind, addr);
+        }
     }
 
-    pub fn must_break(&self, chip8: &Chip8) -> bool {
-        let loc = chip8.pc();
-        (!self.breakpoints.is_empty() && self.breakpoints.contains(&loc))
+    /// Checks whether to stop after an instruction, reporting the watched
+    /// accesses it made
+    pub fn must_break(&mut self, chip8: &mut Chip8) -> bool {
+        let watched = report_watch_hits(chip8.take_watch_hits());
+        if self.breakpoints.is_empty() {
+            return watched;
+        }
+        match self.breakpoints.check(chip8) {
+            Some(id) => {
+                println!("Breakpoint {} hit at 0x{:03x}", id, chip8.pc());
+                true
+            }
+            None => watched,
+        }
+    }
+
+    /// The last `back`, `save` or `load` command, if not handled yet
+    pub fn take_request(&mut self) -> Option<Request> {
+        self.request.take()
     }
 
     pub fn is_exit(&self) -> bool {
         self.exit
     }
 }
+
+/// Prints the hits, returning true if there were any
+fn report_watch_hits(hits: Vec<WatchHit>) -> bool {
+    for hit in &hits {
+        println!("{}", hit);
+    }
+    !hits.is_empty()
+}
@@ -0,0 +1,287 @@
+use chip8::Chip8;
+use debugger::lexer::{tokenize, Token};
+
+/// Binary operators by increasing precedence
+const PRECEDENCE: [&'static [(&'static str, BinOp)]; 6] =
+    [&[("||", BinOp::Or)],
+     &[("&&", BinOp::And)],
+     &[("==", BinOp::Eq),
+       ("!=", BinOp::Ne),
+       ("<=", BinOp::Le),
+       (">=", BinOp::Ge),
+       ("<", BinOp::Lt),
+       (">", BinOp::Gt)],
+     &[("|", BinOp::BitOr)],
+     &[("&", BinOp::BitAnd)],
+     &[("+", BinOp::Add), ("-", BinOp::Sub)]];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+/// Machine state an expression can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+}
+
+impl Operand {
+    /// Resolves `v0`-`vf`, `i`, `pc`, `sp`, `dt` and `st`
+    pub fn from_name(name: &str) -> Option<Operand> {
+        let operand = match name {
+            "i" => Operand::I,
+            "pc" => Operand::Pc,
+            "sp" => Operand::Sp,
+            "dt" => Operand::Delay,
+            "st" => Operand::Sound,
+            _ if name.len() == 2 && name.starts_with('v') => {
+                match usize::from_str_radix(&name[1..], 16) {
+                    Ok(idx) => Operand::V(idx),
+                    Err(_) => return None,
+                }
+            }
+            _ => return None,
+        };
+        Some(operand)
+    }
+
+    pub fn read(&self, chip8: &Chip8) -> i64 {
+        match *self {
+            Operand::V(idx) => chip8.reg_v()[idx] as i64,
+            Operand::I => chip8.reg_i() as i64,
+            Operand::Pc => chip8.pc() as i64,
+            Operand::Sp => chip8.sp() as i64,
+            Operand::Delay => chip8.reg_delay_timer() as i64,
+            Operand::Sound => chip8.reg_sound_timer() as i64,
+        }
+    }
+}
+
+/// An expression over registers, timers and memory bytes, e.g.
+/// `v3 == 0x10 && [i + 1] > 4`. Comparisons and logical operators give 1
+/// or 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(u32),
+    Operand(Operand),
+    /// The memory byte at an address, 0 past the end of memory
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens: Vec<Token> = tokenize(text, 10)?.into_iter().map(|(_, token)| token).collect();
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expr = parser.parse_level(0)?;
+        match parser.next() {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected {} in expression", token)),
+        }
+    }
+
+    pub fn eval(&self, chip8: &Chip8) -> i64 {
+        match *self {
+            Expr::Number(n) => n as i64,
+            Expr::Operand(operand) => operand.read(chip8),
+            Expr::Memory(ref addr) => {
+                let addr = addr.eval(chip8);
+                if addr < 0 {
+                    return 0;
+                }
+                chip8.mem().get(addr as usize).map_or(0, |byte| *byte as i64)
+            }
+            Expr::Not(ref expr) => (expr.eval(chip8) == 0) as i64,
+            Expr::Negate(ref expr) => expr.eval(chip8).wrapping_neg(),
+            Expr::Binary(BinOp::Or, ref lhs, ref rhs) => {
+                (lhs.eval(chip8) != 0 || rhs.eval(chip8) != 0) as i64
+            }
+            Expr::Binary(BinOp::And, ref lhs, ref rhs) => {
+                (lhs.eval(chip8) != 0 && rhs.eval(chip8) != 0) as i64
+            }
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let (lhs, rhs) = (lhs.eval(chip8), rhs.eval(chip8));
+                match op {
+                    BinOp::Eq => (lhs == rhs) as i64,
+                    BinOp::Ne => (lhs != rhs) as i64,
+                    BinOp::Lt => (lhs < rhs) as i64,
+                    BinOp::Le => (lhs <= rhs) as i64,
+                    BinOp::Gt => (lhs > rhs) as i64,
+                    BinOp::Ge => (lhs >= rhs) as i64,
+                    BinOp::BitOr => lhs | rhs,
+                    BinOp::BitAnd => lhs & rhs,
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                    BinOp::Or | BinOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// True when the expression evaluates to anything but 0
+    pub fn is_true(&self, chip8: &Chip8) -> bool {
+        self.eval(chip8) != 0
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if *token == expected => Ok(()),
+            Some(token) => Err(format!("Expected {} but found {}", expected, token)),
+            None => Err(format!("Expected {} at the end of the expression", expected)),
+        }
+    }
+
+    fn parse_level(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+        let mut lhs = self.parse_level(level + 1)?;
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(&Token::Op(text)) => {
+                    match PRECEDENCE[level].iter().find(|&&(op_text, _)| op_text == text) {
+                        Some(&(_, op)) => op,
+                        None => break,
+                    }
+                }
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.parse_level(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(&Token::Op("!")) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(&Token::Op("-")) => Ok(Expr::Negate(Box::new(self.parse_unary()?))),
+            Some(&Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(&Token::Ident(ref name)) => {
+                Operand::from_name(name)
+                    .map(Expr::Operand)
+                    .ok_or_else(|| format!("Unknown register {}", name))
+            }
+            Some(&Token::LParen) => {
+                let expr = self.parse_level(0)?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(&Token::LBracket) => {
+                let addr = self.parse_level(0)?;
+                self.expect(Token::RBracket)?;
+                Ok(Expr::Memory(Box::new(addr)))
+            }
+            Some(token) => Err(format!("Unexpected {} in expression", token)),
+            None => Err("Unexpected end of expression".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peripherals::Peripherals;
+    use quirks::Quirks;
+
+    /// A machine with v3 = 16, vf = 1 and I = 0x200, the start of its ROM
+    fn machine() -> Chip8 {
+        let rom = [0x63, 0x10, 0x6F, 0x01, 0xA2, 0x00];
+        let mut chip8 = Chip8::new(&rom, Quirks::vip());
+        let mut peripherals = Peripherals::new();
+        for _ in 0..3 {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        chip8
+    }
+
+    fn eval(text: &str) -> i64 {
+        Expr::parse(text).unwrap_or_else(|err| panic!("{}: {}", text, err)).eval(&machine())
+    }
+
+    fn error(text: &str) -> String {
+        Expr::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        let number = |n| Box::new(Expr::Number(n));
+        assert_eq!(Expr::parse("1 || 2 && 3").unwrap(),
+                   Expr::Binary(BinOp::Or,
+                                number(1),
+                                Box::new(Expr::Binary(BinOp::And, number(2), number(3)))));
+        // Bitwise operators bind tighter than comparisons, unlike in C
+        assert_eq!(eval("2 | 1 == 3"), 1);
+        assert_eq!(eval("1 + 2 & 3"), 3);
+        assert_eq!(eval("0 || 1 && 0"), 0);
+        assert_eq!(eval("v3 - 1 - 1"), 14);
+        assert_eq!(eval("v3 - (1 - 1)"), 16);
+        assert_eq!(eval("!0 + -v3"), -15);
+    }
+
+    #[test]
+    fn operands_read_the_machine() {
+        assert_eq!(eval("v3 == 0x10 && vF == 1"), 1);
+        assert_eq!(eval("i"), 0x200);
+        assert_eq!(eval("pc"), 0x206);
+        assert_eq!(eval("sp + dt + st"), 0);
+        assert_eq!(eval("v3 > 15 && v3 >= 16 && v3 < 17 && v3 <= 16 && v3 != 0"), 1);
+    }
+
+    #[test]
+    fn brackets_read_memory() {
+        assert_eq!(eval("[i]"), 0x63);
+        assert_eq!(eval("[i + 1]"), 0x10);
+        assert_eq!(eval("[[i + 1] + 0x1f0]"), 0x63);
+        assert_eq!(eval("[-1]"), 0);
+        assert_eq!(eval("[0xffffff]"), 0);
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        assert_eq!(error("vg == 1"), "Unknown register vg");
+        assert_eq!(error("v0 == 1 2"), "Unexpected 2 in expression");
+        assert_eq!(error("v0 == 1)"), "Unexpected ) in expression");
+        assert_eq!(error("v0 =="), "Unexpected end of expression");
+        assert_eq!(error("(v0"), "Expected ) at the end of the expression");
+        assert_eq!(error("[i)"), "Expected ] but found )");
+        assert_eq!(error("v0 = 1"), "Unexpected character '='");
+        assert_eq!(error(""), "Unexpected end of expression");
+    }
+}
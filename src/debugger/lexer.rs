@@ -0,0 +1,94 @@
+use std::fmt;
+
+/// Operators, longest first so that `<=` is not read as `<` and `=`
+const OPERATORS: [&'static str; 13] =
+    ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "&", "+", "-", "!"];
+
+/// A token of a debugger command or expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Number(u32),
+    /// A lowercased name: a register, keyword or mode
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(ref name) => f.write_str(name),
+            Token::Op(op) => f.write_str(op),
+            Token::LParen => f.write_str("("),
+            Token::RParen => f.write_str(")"),
+            Token::LBracket => f.write_str("["),
+            Token::RBracket => f.write_str("]"),
+        }
+    }
+}
+
+/// Splits text into tokens, each with its byte offset. Unprefixed numbers are
+/// in the given radix, prefixed ones hexadecimal with `0x` or `$`. In radix
+/// 16, words made only of hexadecimal digits, such as `fe`, are numbers too.
+pub fn tokenize(text: &str, radix: u32) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    while let Some(c) = text[offset..].chars().next() {
+        let rest = &text[offset..];
+        if c.is_whitespace() {
+            offset += c.len_utf8();
+            continue;
+        }
+        let (token, len) = if c.is_alphanumeric() || c == '_' || c == '$' {
+            let start = c.len_utf8();
+            let end = rest[start..].find(|c: char| !c.is_alphanumeric() && c != '_');
+            let len = end.map_or(rest.len(), |end| start + end);
+            let word = &rest[..len];
+            let is_hex_word = radix == 16 && word.chars().all(|c| c.is_digit(16));
+            if c == '$' || c.is_digit(10) || is_hex_word {
+                (Token::Number(parse_number(word, radix)?), len)
+            } else {
+                (Token::Ident(word.to_lowercase()), len)
+            }
+        } else {
+            match c {
+                '(' => (Token::LParen, 1),
+                ')' => (Token::RParen, 1),
+                '[' => (Token::LBracket, 1),
+                ']' => (Token::RBracket, 1),
+                _ => {
+                    match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                        Some(op) => (Token::Op(*op), op.len()),
+                        None => return Err(format!("Unexpected character '{}'", c)),
+                    }
+                }
+            }
+        };
+        tokens.push((offset, token));
+        offset += len;
+    }
+    Ok(tokens)
+}
+
+/// Parses `0x1f`, `$1f`, or `31` in radix 10 and `1f` in radix 16
+pub fn parse_number(word: &str, radix: u32) -> Result<u32, String> {
+    let parsed = if word.starts_with("0x") || word.starts_with("0X") {
+        u32::from_str_radix(&word[2..], 16)
+    } else if word.starts_with('$') {
+        u32::from_str_radix(&word[1..], 16)
+    } else {
+        u32::from_str_radix(word, radix)
+    };
+    parsed.map_err(|_| {
+        let is_hex = word.chars().all(|c| c.is_digit(16));
+        if radix == 10 && is_hex && !word.chars().all(|c| c.is_digit(10)) {
+            format!("Invalid number {}, hexadecimal needs a 0x or $ prefix", word)
+        } else {
+            format!("Invalid number {}", word)
+        }
+    })
+}
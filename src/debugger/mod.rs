@@ -1,2 +1,6 @@
 pub mod debugger;
+pub mod breakpoint;
 pub mod command;
+pub mod expr;
+pub mod lexer;
+pub mod watchpoint;
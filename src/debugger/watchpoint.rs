@@ -0,0 +1,173 @@
+use std::fmt;
+
+/// A memory access made by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// The accesses a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// Parses the `r`, `w` and `rw` watch modes
+    pub fn from_name(name: &str) -> Result<WatchKind, String> {
+        match name {
+            "r" => Ok(WatchKind::Read),
+            "w" => Ok(WatchKind::Write),
+            "rw" => Ok(WatchKind::ReadWrite),
+            _ => Err(format!("Unknown watch mode {}, expected r, w or rw", name)),
+        }
+    }
+
+    pub fn matches(&self, access: Access) -> bool {
+        match (*self, access) {
+            (WatchKind::ReadWrite, _) |
+            (WatchKind::Read, Access::Read) |
+            (WatchKind::Write, Access::Write) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            WatchKind::Read => "r",
+            WatchKind::Write => "w",
+            WatchKind::ReadWrite => "rw",
+        })
+    }
+}
+
+/// An access that triggered a watchpoint. Reads have the same old and new
+/// value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Address of the instruction that made the access
+    pub pc: usize,
+    pub addr: usize,
+    pub access: Access,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Read => {
+                write!(f,
+                       "Watchpoint 0x{:03x}: read 0x{:02x} at 0x{:03x}",
+                       self.addr,
+                       self.old,
+                       self.pc)
+            }
+            Access::Write => {
+                write!(f,
+                       "Watchpoint 0x{:03x}: write 0x{:02x} -> 0x{:02x} at 0x{:03x}",
+                       self.addr,
+                       self.old,
+                       self.new,
+                       self.pc)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::Chip8;
+    use peripherals::Peripherals;
+    use quirks::Quirks;
+
+    /// Stores v0-v1 = 5, 7 at 0x300, loads them back, then draws the byte
+    /// at 0x300 as a sprite
+    const ROM: [u8; 16] = [0xA3, 0x00, 0x60, 0x05, 0x61, 0x07, 0xF1, 0x55, 0xA3, 0x00, 0xF1,
+                           0x65, 0xA3, 0x00, 0xD0, 0x11];
+
+    /// Runs the whole ROM with 0x200 and 0x300 to 0x301 watched
+    fn hits(kind: WatchKind) -> Vec<WatchHit> {
+        let mut chip8 = Chip8::new(&ROM, Quirks::vip());
+        let mut peripherals = Peripherals::new();
+        for addr in &[0x200, 0x300, 0x301] {
+            chip8.add_watchpoint(*addr, kind);
+        }
+        for _ in 0..ROM.len() / 2 {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        chip8.take_watch_hits()
+    }
+
+    fn hit(pc: usize, addr: usize, access: Access, old: u8, new: u8) -> WatchHit {
+        WatchHit {
+            pc: pc,
+            addr: addr,
+            access: access,
+            old: old,
+            new: new,
+        }
+    }
+
+    #[test]
+    fn writes_report_the_old_and_new_values() {
+        assert_eq!(hits(WatchKind::Write),
+                   vec![hit(0x206, 0x300, Access::Write, 0, 5),
+                        hit(0x206, 0x301, Access::Write, 0, 7)]);
+    }
+
+    #[test]
+    fn reads_come_from_loads_and_sprites_but_not_fetches() {
+        assert_eq!(hits(WatchKind::Read),
+                   vec![hit(0x20a, 0x300, Access::Read, 5, 5),
+                        hit(0x20a, 0x301, Access::Read, 7, 7),
+                        hit(0x20e, 0x300, Access::Read, 5, 5)]);
+    }
+
+    #[test]
+    fn read_write_watches_see_both() {
+        let hits = hits(WatchKind::ReadWrite);
+        let accesses: Vec<(usize, Access)> = hits.iter().map(|hit| (hit.pc, hit.access)).collect();
+        assert_eq!(accesses,
+                   vec![(0x206, Access::Write),
+                        (0x206, Access::Write),
+                        (0x20a, Access::Read),
+                        (0x20a, Access::Read),
+                        (0x20e, Access::Read)]);
+        assert_eq!(hits[0].to_string(), "Watchpoint 0x300: write 0x00 -> 0x05 at 0x206");
+        assert_eq!(hits[4].to_string(), "Watchpoint 0x300: read 0x05 at 0x20e");
+    }
+
+    #[test]
+    fn removed_watchpoints_stop_reporting() {
+        let mut chip8 = Chip8::new(&ROM, Quirks::vip());
+        let mut peripherals = Peripherals::new();
+        chip8.add_watchpoint(0x300, WatchKind::Write);
+        // Replacing the watchpoint changes its mode
+        chip8.add_watchpoint(0x300, WatchKind::Read);
+        assert!(chip8.remove_watchpoint(0x300));
+        assert!(!chip8.remove_watchpoint(0x300));
+        for _ in 0..ROM.len() / 2 {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        assert!(chip8.take_watch_hits().is_empty());
+    }
+
+    #[test]
+    fn modes_match_their_accesses() {
+        assert!(WatchKind::Read.matches(Access::Read) && !WatchKind::Read.matches(Access::Write));
+        assert!(WatchKind::Write.matches(Access::Write) && !WatchKind::Write.matches(Access::Read));
+        assert!(WatchKind::ReadWrite.matches(Access::Read) &&
+                WatchKind::ReadWrite.matches(Access::Write));
+        for kind in &[WatchKind::Read, WatchKind::Write, WatchKind::ReadWrite] {
+            assert_eq!(WatchKind::from_name(&kind.to_string()), Ok(*kind));
+        }
+        assert!(WatchKind::from_name("x").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use instruction::Instruction;
+
+/// Address ROMs are loaded at, where execution starts
+pub const ROM_START: usize = 0x200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Byte {
+    Data,
+    /// First byte of an instruction
+    Code,
+    /// Rest of an instruction, including the address word after `lmvi`
+    Operand,
+}
+
+/// Kinds of labels, by increasing priority when an address has several
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Label,
+    Loop,
+    Sub,
+}
+
+impl LabelKind {
+    fn prefix(&self) -> &'static str {
+        match *self {
+            LabelKind::Data => "data",
+            LabelKind::Label => "lbl",
+            LabelKind::Loop => "loop",
+            LabelKind::Sub => "sub",
+        }
+    }
+}
+
+/// A ROM split into code and data by following the control flow from
+/// `ROM_START`. Displaying it gives assembler source for the same bytes.
+pub struct Disassembly {
+    rom: Vec<u8>,
+    bytes: Vec<Byte>,
+    labels: BTreeMap<usize, LabelKind>,
+}
+
+impl Disassembly {
+    pub fn new(rom: &[u8]) -> Disassembly {
+        let mut disassembly = Disassembly {
+            rom: rom.to_vec(),
+            bytes: vec![Byte::Data; rom.len()],
+            labels: BTreeMap::new(),
+        };
+        disassembly.trace();
+        disassembly
+    }
+
+    /// Follows every path from the entry point. Computed jumps (`BNNN`)
+    /// cannot be followed, so their targets stay data.
+    fn trace(&mut self) {
+        let mut pending = vec![ROM_START];
+        let mut references = Vec::new();
+        while let Some(addr) = pending.pop() {
+            let instruction = match self.word(addr).and_then(decode) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let len = instruction_len(&instruction);
+            if !self.is_unclaimed(addr, len) {
+                continue;
+            }
+            let offset = addr - ROM_START;
+            self.bytes[offset] = Byte::Code;
+            for byte in &mut self.bytes[offset + 1..offset + len] {
+                *byte = Byte::Operand;
+            }
+
+            let next = addr + len;
+            match instruction {
+                Instruction::Jmp { addr: target } => {
+                    let kind = if target <= addr { LabelKind::Loop } else { LabelKind::Label };
+                    references.push((target, kind));
+                    pending.push(target);
+                }
+                Instruction::Jsr { addr: target } => {
+                    references.push((target, LabelKind::Sub));
+                    pending.push(target);
+                    pending.push(next);
+                }
+                Instruction::Ret | Instruction::Exit | Instruction::Jmi { .. } => {}
+                Instruction::Skeq { .. } |
+                Instruction::Skne { .. } |
+                Instruction::Skeqr { .. } |
+                Instruction::Skner { .. } |
+                Instruction::Skp { .. } |
+                Instruction::Sknp { .. } => {
+                    // Skips jump over the whole of a following `lmvi`
+                    let skipped = if self.word(next) == Some(0xF000) { 4 } else { 2 };
+                    pending.push(next + skipped);
+                    pending.push(next);
+                }
+                Instruction::Mvi { k } => {
+                    references.push((k as usize, LabelKind::Data));
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+
+        for (target, kind) in references {
+            if self.can_label(target) {
+                let label = self.labels.entry(target).or_insert(kind);
+                if kind > *label {
+                    *label = kind;
+                }
+            }
+        }
+    }
+
+    fn word(&self, addr: usize) -> Option<u16> {
+        if addr < ROM_START || addr + 2 > ROM_START + self.rom.len() {
+            return None;
+        }
+        let offset = addr - ROM_START;
+        Some(((self.rom[offset] as u16) << 8) | self.rom[offset + 1] as u16)
+    }
+
+    fn is_unclaimed(&self, addr: usize, len: usize) -> bool {
+        let offset = addr - ROM_START;
+        offset + len <= self.bytes.len() &&
+        self.bytes[offset..offset + len].iter().all(|byte| *byte == Byte::Data)
+    }
+
+    /// Labels can only go on lines of their own: instructions and data bytes
+    fn can_label(&self, addr: usize) -> bool {
+        addr >= ROM_START && addr < ROM_START + self.rom.len() &&
+        self.bytes[addr - ROM_START] != Byte::Operand
+    }
+
+    fn label(&self, addr: usize) -> Option<String> {
+        self.labels.get(&addr).map(|kind| format!("{}_{:03x}", kind.prefix(), addr))
+    }
+
+    /// Number of ROM bytes found to be code
+    pub fn code_len(&self) -> usize {
+        self.bytes.iter().filter(|byte| **byte != Byte::Data).count()
+    }
+
+    /// The instruction mnemonic, with labels for the addresses that have one
+    fn instruction_text(&self, instruction: &Instruction) -> String {
+        let labelled = match *instruction {
+            Instruction::Jmp { addr } => self.label(addr).map(|label| format!("jmp    {}", label)),
+            Instruction::Jsr { addr } => self.label(addr).map(|label| format!("jsr    {}", label)),
+            Instruction::Mvi { k } => {
+                self.label(k as usize).map(|label| format!("mvi    {}", label))
+            }
+            _ => None,
+        };
+        labelled.unwrap_or_else(|| format!("{:?}", instruction))
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code_len = self.code_len();
+        writeln!(f,
+                 "; {} bytes of code, {} bytes of data",
+                 code_len,
+                 self.rom.len() - code_len)?;
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let addr = ROM_START + offset;
+            if let Some(label) = self.label(addr) {
+                writeln!(f, "{}:", label)?;
+            }
+            let instruction = match self.bytes[offset] {
+                Byte::Code => self.word(addr).and_then(decode),
+                _ => None,
+            };
+            match instruction {
+                Some(instruction) => {
+                    writeln!(f, "    {}", self.instruction_text(&instruction))?;
+                    if let Instruction::Lmvi = instruction {
+                        let target = self.word(addr + 2).expect("Traced with its address word");
+                        writeln!(f, "    dw     0x{:04x}", target)?;
+                    }
+                    offset += instruction_len(&instruction);
+                }
+                None => {
+                    let byte = self.rom[offset];
+                    writeln!(f, "    db     0x{:02x} ; {}", byte, sprite_row(byte))?;
+                    offset += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode(opcode: u16) -> Option<Instruction> {
+    Instruction::try_from(opcode).ok()
+}
+
+fn instruction_len(instruction: &Instruction) -> usize {
+    match *instruction {
+        Instruction::Lmvi => 4,
+        _ => 2,
+    }
+}
+
+/// A byte drawn as one row of a sprite
+fn sprite_row(byte: u8) -> String {
+    (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::assemble;
+    use rand::{Rng, SeedableRng};
+    use rng::XorShift64;
+
+    #[test]
+    fn random_roms_reassemble_to_the_same_bytes() {
+        for seed in 0..200 {
+            let mut rng = XorShift64::from_seed(seed);
+            let mut rom = vec![0; 1 + rng.gen_range(0, 600)];
+            rng.fill_bytes(&mut rom);
+            let source = Disassembly::new(&rom).to_string();
+            let assembled = assemble("random.asm", &source)
+                .unwrap_or_else(|err| panic!("seed {}: {}\n{}", seed, err, source));
+            assert!(assembled == rom, "seed {} disassembles to\n{}", seed, source);
+        }
+    }
+}
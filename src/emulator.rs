@@ -1,17 +1,50 @@
-
-use minifb::{WindowOptions, Scale, Window, Key, KeyRepeat};
 use std::io;
 use std::io::prelude::*;
 use std::io::stdin;
 use std::sync::mpsc::{channel, Receiver};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time;
 
 use chip8::Chip8;
-use debugger::debugger::Debugger;
-use peripherals;
+use clock::{ClockConfig, FramePacer};
+use debugger::debugger::{Debugger, Request};
+use fault::Fault;
+use input;
+use input::InputSource;
+use movie::{Movie, MovieError, MoviePlayer};
 use peripherals::Peripherals;
+use quirks::Quirks;
+use rewind::Rewind;
+use savestate;
+use savestate::SaveStateError;
+use sound::AudioSink;
+use trace::DEFAULT_TRACE_LENGTH;
+use video_engine::VideoEngine;
+
+/// Emulator functions a frontend can bind to a host key
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Hotkey {
+    Debug,
+    SaveState,
+    LoadState,
+    /// Reported for as long as it is held, rewinding one frame per frame
+    Rewind,
+}
+
+/// Everything the emulator needs from the host: a place to show the
+/// framebuffer and a source of key presses.
+pub trait Frontend: InputSource {
+    /// Returns false once the user asked to close the emulator
+    fn is_open(&self) -> bool;
+
+    /// Shows the current framebuffer to the user
+    fn present(&mut self, video: &VideoEngine);
+
+    /// Keeps the frontend responsive while the emulation is paused
+    fn idle(&mut self);
+
+    fn is_hotkey_pressed(&mut self, hotkey: Hotkey) -> bool;
+}
 
 #[derive(PartialEq, Eq)]
 enum Mode {
@@ -19,110 +52,484 @@ enum Mode {
     Debugging,
 }
 
-pub struct Emulator {
+pub struct Emulator<F: Frontend> {
     chip8: Chip8,
-    window: Window,
+    frontend: F,
     peripherals: Peripherals,
+    debugger: Debugger,
+    input_sources: Vec<Box<InputSource>>,
 
     mode: Mode,
-    stdin_receiver: Receiver<String>,
-    _stdin_thread: JoinHandle<()>,
+    clock: ClockConfig,
+    frame_cycles: usize,
+    frame: u64,
+    state_path: Option<String>,
+    recording: Option<Movie>,
+    replay: Option<MoviePlayer>,
+    rewind: Rewind,
 }
 
-impl Emulator {
-    pub fn new(rom: &[u8]) -> Self {
-        let window_options = WindowOptions {
-            borderless: false,
-            title: true,
-            resize: false,
-            scale: Scale::X16,
-        };
-
-        let (stdin_sender, stdin_receiver) = channel();
-        let stdin_thread = thread::spawn(move || loop {
-            stdin_sender.send(read_stdin()).unwrap();
-        });
-
+impl<F: Frontend> Emulator<F> {
+    pub fn new(rom: &[u8], frontend: F) -> Self {
         Emulator {
-            chip8: Chip8::new(rom),
-            window: Window::new("RUST Chip8 Emulator", 64, 32, window_options).unwrap(),
+            chip8: Chip8::new(rom, Quirks::default()),
+            frontend: frontend,
             peripherals: Peripherals::new(),
+            debugger: Debugger::new(),
+            input_sources: Vec::new(),
 
             mode: Mode::Running,
-            stdin_receiver: stdin_receiver,
-            _stdin_thread: stdin_thread,
+            clock: ClockConfig::default(),
+            frame_cycles: 0,
+            frame: 0,
+            state_path: None,
+            recording: None,
+            replay: None,
+            rewind: Rewind::default(),
         }
     }
 
+    /// Runs the emulation interactively until the frontend is closed, with
+    /// the debugger reading commands from stdin
     pub fn run(&mut self) {
-        let mut debugger = Debugger::new();
-        while self.window.is_open() && !self.window.is_key_down(Key::Escape) &&
-              !debugger.is_exit() {
-            {
-                match self.mode {
-                    Mode::Running => {
-                        let mut start_debugger = false;
-
-                        self.chip8.step(&mut self.peripherals);
-                        if debugger.must_break(&self.chip8) {
-                            start_debugger = true;
-                        }
-
-                        if start_debugger {
-                            self.mode = Mode::Debugging;
+        let (mut stdin_receiver, _stdin_thread) = spawn_stdin_reader();
+        let mut pacer = FramePacer::new();
+        while self.frontend.is_open() && !self.debugger.is_exit() && !self.chip8.is_halted() {
+            match self.mode {
+                Mode::Running => {
+                    self.handle_state_hotkeys();
+                    if self.frontend.is_hotkey_pressed(Hotkey::Rewind) {
+                        self.rewind(1);
+                        self.frontend.present(&self.peripherals.video_engine);
+                    } else if self.frontend.is_hotkey_pressed(Hotkey::Debug) {
+                        self.enter_debugger();
+                    } else {
+                        match self.run_frame() {
+                            Ok(false) => {}
+                            Ok(true) => self.enter_debugger(),
+                            Err(fault) => {
+                                println!("{}, entering the debugger", fault);
+                                self.enter_debugger();
+                            }
                         }
                     }
-                    Mode::Debugging => {
-                        print!("[0x{:2x}]> ", self.chip8.pc());
-                        io::stdout().flush().expect("Could not flush stdout");
-                        while debugger.manage_cli(&mut self.stdin_receiver,
-                                                  &mut self.chip8,
-                                                  &mut self.peripherals) {
-                            self.window.update();
-                        }
-                        self.mode = Mode::Running
+                }
+                Mode::Debugging => {
+                    print!("[0x{:2x}]> ", self.chip8.pc());
+                    io::stdout().flush().expect("Could not flush stdout");
+                    while self.debugger.manage_cli(&mut stdin_receiver,
+                                                   &mut self.chip8,
+                                                   &mut self.peripherals) {
+                        self.frontend.idle();
+                    }
+                    if let Some(request) = self.debugger.take_request() {
+                        // Stay in the debugger, at the new position if any
+                        self.handle_request(request);
+                    } else {
+                        self.mode = Mode::Running;
                     }
+                    self.frontend.present(&self.peripherals.video_engine);
+                    pacer.reset();
                 }
             }
 
-            self.window.update_with_buffer(self.peripherals.video_engine.vram());
+            if self.clock.throttle {
+                pacer.wait();
+            }
+        }
+    }
 
-            if let Mode::Running = self.mode {
-                self.update_keys();
-                if self.window.is_key_pressed(Key::F12, KeyRepeat::No) {
-                    self.mode = Mode::Debugging;
-                }
+    /// Switches to the debugger, which needs the trace for `rstep` and
+    /// `history`. Unless set otherwise it is only turned on from here.
+    fn enter_debugger(&mut self) {
+        if !self.chip8.trace().is_enabled() {
+            self.chip8.set_trace_length(DEFAULT_TRACE_LENGTH);
+        }
+        self.mode = Mode::Debugging;
+    }
+
+    /// Runs the rest of the current 60 Hz frame: reads the input sources,
+    /// executes the configured number of instructions, ticks the timers and
+    /// presents the framebuffer. Returns true if a breakpoint or watchpoint
+    /// stopped the frame early, or the fault that did; calling it again
+    /// resumes the same frame.
+    pub fn run_frame(&mut self) -> Result<bool, Fault> {
+        if self.frame_cycles == 0 {
+            self.rewind.snapshot(self.frame, &self.chip8, &self.peripherals);
+            self.update_keys();
+        }
+        let mut hit_breakpoint = false;
+        while self.frame_cycles < self.clock.instructions_per_frame {
+            if let Err(fault) = self.chip8.step(&mut self.peripherals) {
+                self.frontend.present(&self.peripherals.video_engine);
+                return Err(fault);
             }
-            thread::sleep(time::Duration::from_millis(3));
+            self.frame_cycles += 1;
+            if self.debugger.must_break(&mut self.chip8) {
+                hit_breakpoint = true;
+                break;
+            }
+        }
+        if !hit_breakpoint {
+            self.frame_cycles = 0;
+            self.frame += 1;
+            self.chip8.tick_timers(&mut self.peripherals);
+            self.peripherals.keypad.end_frame();
         }
+        self.frontend.present(&self.peripherals.video_engine);
+        Ok(hit_breakpoint)
     }
+
+    /// Merges the frontend keys with the other input sources, or takes them
+    /// from the movie being replayed
     fn update_keys(&mut self) {
-        self.update_key(peripherals::Key::Key0, Key::X);
-        self.update_key(peripherals::Key::Key1, Key::NumPad1);
-        self.update_key(peripherals::Key::Key2, Key::NumPad2);
-        self.update_key(peripherals::Key::Key3, Key::NumPad3);
-        self.update_key(peripherals::Key::Key4, Key::Q);
-        self.update_key(peripherals::Key::Key5, Key::W);
-        self.update_key(peripherals::Key::Key6, Key::E);
-        self.update_key(peripherals::Key::Key7, Key::A);
-        self.update_key(peripherals::Key::Key8, Key::S);
-        self.update_key(peripherals::Key::Key9, Key::D);
-        self.update_key(peripherals::Key::KeyA, Key::Z);
-        self.update_key(peripherals::Key::KeyB, Key::C);
-        self.update_key(peripherals::Key::KeyC, Key::NumPad4);
-        self.update_key(peripherals::Key::KeyD, Key::R);
-        self.update_key(peripherals::Key::KeyE, Key::F);
-        self.update_key(peripherals::Key::KeyF, Key::V);
-    }
-
-    fn update_key(&mut self, target_key: peripherals::Key, mapped_key: Key) {
-        let keypad = &mut self.peripherals.keypad;
-        keypad.set_button_state(target_key, self.window.is_key_down(mapped_key));
+        let mut sources: Vec<&mut InputSource> = Vec::new();
+        match self.replay {
+            // Live input would make the replay diverge
+            Some(ref mut player) => sources.push(player),
+            None => {
+                sources.push(&mut self.frontend);
+                for source in self.input_sources.iter_mut() {
+                    sources.push(&mut **source);
+                }
+            }
+        }
+        input::update_keypad(&mut sources, &mut self.peripherals.keypad);
+        if let Some(ref mut movie) = self.recording {
+            movie.record(self.frame, self.peripherals.keypad.state_mask());
+        }
+    }
+
+    /// Goes back up to `frames` frames, to the start of a frame. Returns the
+    /// number of frames actually rewound. A movie being recorded loses the
+    /// rewound frames; rewinding is not possible while replaying one.
+    pub fn rewind(&mut self, frames: usize) -> usize {
+        if self.replay.is_some() {
+            return 0;
+        }
+        let mut rewound = 0;
+        while rewound < frames {
+            match self.rewind.step_back(&mut self.chip8, &mut self.peripherals) {
+                Some(frame) => self.frame = frame,
+                None => break,
+            }
+            rewound += 1;
+        }
+        if rewound > 0 {
+            self.frame_cycles = 0;
+            if let Some(ref mut movie) = self.recording {
+                movie.truncate(self.frame);
+            }
+        }
+        rewound
+    }
+
+    /// Sets how many instructions are traced from now on, 0 disabling the
+    /// trace until the debugger is entered
+    pub fn set_trace_length(&mut self, length: usize) {
+        self.chip8.set_trace_length(length);
     }
+
+    /// Sets how many frames can be rewound, 0 disabling the snapshots
+    pub fn set_rewind_frames(&mut self, frames: usize) {
+        self.rewind.set_max_snapshots(frames);
+    }
+
+    /// Starts recording the keypad into a movie. Movies are replayed from
+    /// power on, so this has to be called before running the first frame.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Movie::new(&self.chip8, self.clock.instructions_per_frame));
+    }
+
+    /// Stops recording and returns the movie recorded so far
+    pub fn take_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    /// Replays a movie from power on, restoring the seed, quirks and clock it
+    /// was recorded with. Like `start_recording`, it has to be called before
+    /// running the first frame.
+    pub fn start_replay(&mut self, movie: Movie) -> Result<(), MovieError> {
+        movie.check_rom(&self.chip8)?;
+        self.chip8.set_seed(movie.seed());
+        self.chip8.set_quirks(movie.quirks());
+        self.clock.instructions_per_frame = movie.instructions_per_frame();
+        self.replay = Some(MoviePlayer::new(movie));
+        Ok(())
+    }
+
+    /// True once a replayed movie has run out of frames
+    pub fn is_replay_finished(&self) -> bool {
+        self.replay.as_ref().map_or(false, |player| player.is_finished())
+    }
+
+    /// Number of frames completed since power on
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    fn handle_request(&mut self, request: Request) {
+        match request {
+            Request::Back { frames } => {
+                match self.rewind(frames) {
+                    0 => println!("Nothing to rewind"),
+                    rewound => println!("Rewound {} frames to frame {}", rewound, self.frame),
+                }
+            }
+            Request::Save { path } => self.save_state(&path),
+            Request::Load { path } => self.load_state(&path),
+        }
+    }
+
+    fn handle_state_hotkeys(&mut self) {
+        let save = self.frontend.is_hotkey_pressed(Hotkey::SaveState);
+        let load = self.frontend.is_hotkey_pressed(Hotkey::LoadState);
+        if !save && !load {
+            return;
+        }
+        let path = match self.state_path {
+            Some(ref path) => path.clone(),
+            None => {
+                println!("No save state file configured");
+                return;
+            }
+        };
+        if save {
+            self.save_state(&path);
+        }
+        if load {
+            self.load_state(&path);
+        }
+    }
+
+    fn save_state(&self, path: &str) {
+        match savestate::save_to_file(path, &self.chip8, &self.peripherals, self.frame) {
+            Ok(()) => println!("State saved to {}", path),
+            Err(e) => println!("Could not save state to {}: {}", path, e),
+        }
+    }
+
+    fn load_state(&mut self, path: &str) {
+        match self.restore_state(path) {
+            Ok(()) => println!("State loaded from {} at frame {}", path, self.frame),
+            Err(e) => println!("Could not load state from {}: {}", path, e),
+        }
+    }
+
+    /// Loads a save state along with its frame counter. The rewind history
+    /// belongs to the timeline being left, so it is dropped. A movie being
+    /// recorded or replayed could not reach the loaded state from power on,
+    /// so loading is refused until it is done.
+    pub fn restore_state(&mut self, path: &str) -> Result<(), SaveStateError> {
+        if self.recording.is_some() || self.replay.is_some() {
+            return Err(SaveStateError::MovieInProgress);
+        }
+        self.frame = savestate::load_from_file(path, &mut self.chip8, &mut self.peripherals)?;
+        self.frame_cycles = 0;
+        self.rewind.clear();
+        Ok(())
+    }
+
+    /// Sets the file used by the save and load state hotkeys
+    pub fn set_state_path(&mut self, path: &str) {
+        self.state_path = Some(path.into());
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.chip8.set_quirks(quirks);
+    }
+
+    pub fn set_clock(&mut self, clock: ClockConfig) {
+        self.clock = clock;
+    }
+
+    /// Seeds the random generator used by `CXNN`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.chip8.set_seed(seed);
+    }
+
+    /// Adds a source of key presses besides the frontend, e.g. a gamepad
+    pub fn add_input_source(&mut self, source: Box<InputSource>) {
+        self.input_sources.push(source);
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Box<AudioSink>) {
+        self.peripherals.sound.set_sink(sink);
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    pub fn peripherals(&self) -> &Peripherals {
+        &self.peripherals
+    }
+
+    pub fn frontend(&self) -> &F {
+        &self.frontend
+    }
+
+    pub fn frontend_mut(&mut self) -> &mut F {
+        &mut self.frontend
+    }
+}
+
+/// Forwards stdin lines to the debugger until stdin is closed
+fn spawn_stdin_reader() -> (Receiver<String>, JoinHandle<()>) {
+    let (stdin_sender, stdin_receiver) = channel();
+    let stdin_thread = thread::spawn(move || loop {
+        let mut input = String::new();
+        match stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                if stdin_sender.send(input.trim().into()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    (stdin_receiver, stdin_thread)
 }
 
-fn read_stdin() -> String {
-    let mut input = String::new();
-    stdin().read_line(&mut input).unwrap();
-    input.trim().into()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use headless::{HeadlessFrontend, KeyScript};
+
+    fn emulator(rom: &[u8], instructions_per_frame: usize) -> Emulator<HeadlessFrontend> {
+        let mut emulator = Emulator::new(rom, HeadlessFrontend::new(KeyScript::new()));
+        emulator.set_clock(ClockConfig {
+            instructions_per_frame: instructions_per_frame,
+            throttle: false,
+        });
+        emulator
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame_at_any_instruction_rate() {
+        // Both timers := 60, then spin
+        let rom = [0x61, 0x3C, 0xF1, 0x15, 0xF1, 0x18, 0x12, 0x06];
+        for &instructions_per_frame in &[3, 10, 1000] {
+            let mut emulator = emulator(&rom, instructions_per_frame);
+            for _ in 0..10 {
+                emulator.run_frame().unwrap();
+            }
+            assert_eq!(emulator.chip8().reg_delay_timer(), 50);
+            assert_eq!(emulator.chip8().reg_sound_timer(), 50);
+        }
+    }
+
+    #[test]
+    fn loading_a_state_restores_the_frame_and_drops_the_rewind_history() {
+        let path = env::temp_dir().join("chip8emu-emulator-test.state");
+        let path = path.to_str().unwrap();
+        // Count frames in v1
+        let mut emulator = emulator(&[0x71, 0x01, 0x12, 0x00], 2);
+        for _ in 0..5 {
+            emulator.run_frame().unwrap();
+        }
+        emulator.save_state(path);
+        for _ in 0..5 {
+            emulator.run_frame().unwrap();
+        }
+        // Stop halfway through a frame
+        emulator.chip8.step(&mut emulator.peripherals).unwrap();
+        emulator.frame_cycles = 1;
+
+        emulator.restore_state(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(emulator.frame(), 5);
+        assert_eq!(emulator.chip8().reg_v()[1], 5);
+        assert_eq!(emulator.rewind(1), 0);
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.frame(), 6);
+        assert_eq!(emulator.chip8().reg_v()[1], 6);
+    }
+
+    #[test]
+    fn loading_a_state_is_refused_while_recording() {
+        let path = env::temp_dir().join("chip8emu-emulator-recording-test.state");
+        let path = path.to_str().unwrap();
+        let mut emulator = emulator(&[0x71, 0x01, 0x12, 0x00], 2);
+        emulator.start_recording();
+        for _ in 0..5 {
+            emulator.run_frame().unwrap();
+        }
+        emulator.save_state(path);
+        for _ in 0..5 {
+            emulator.run_frame().unwrap();
+        }
+
+        let result = emulator.restore_state(path);
+        fs::remove_file(path).unwrap();
+        match result {
+            Err(SaveStateError::MovieInProgress) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(emulator.frame(), 10);
+        let movie = emulator.take_recording().unwrap();
+        assert_eq!(Movie::decode(&movie.encode()).unwrap().frames(), 10);
+    }
+
+    #[test]
+    fn loading_a_state_is_refused_while_replaying() {
+        let path = env::temp_dir().join("chip8emu-emulator-replay-test.state");
+        let path = path.to_str().unwrap();
+        let rom = [0x71, 0x01, 0x12, 0x00];
+        let mut recorder = emulator(&rom, 2);
+        recorder.start_recording();
+        for _ in 0..10 {
+            recorder.run_frame().unwrap();
+        }
+        let movie = recorder.take_recording().unwrap();
+
+        let mut emulator = emulator(&rom, 2);
+        emulator.start_replay(movie).unwrap();
+        for _ in 0..5 {
+            emulator.run_frame().unwrap();
+        }
+        emulator.save_state(path);
+        emulator.run_frame().unwrap();
+
+        let result = emulator.restore_state(path);
+        fs::remove_file(path).unwrap();
+        match result {
+            Err(SaveStateError::MovieInProgress) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(emulator.frame(), 6);
+        for _ in 0..4 {
+            emulator.run_frame().unwrap();
+        }
+        assert!(emulator.is_replay_finished());
+        assert_eq!(emulator.chip8().reg_v()[1], recorder.chip8().reg_v()[1]);
+    }
+
+    #[test]
+    fn debugger_back_requests_rewind_whole_frames() {
+        // Count frames in v1
+        let mut emulator = emulator(&[0x71, 0x01, 0x12, 0x00], 2);
+        for _ in 0..10 {
+            emulator.run_frame().unwrap();
+        }
+        emulator.handle_request(Request::Back { frames: 4 });
+        assert_eq!(emulator.frame(), 6);
+        assert_eq!(emulator.chip8().reg_v()[1], 6);
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.chip8().reg_v()[1], 7);
+    }
+
+    #[test]
+    fn instructions_do_not_tick_the_timers() {
+        let rom = [0x61, 0x3C, 0xF1, 0x15, 0x12, 0x04];
+        let mut emulator = emulator(&rom, 100);
+        for _ in 0..50 {
+            emulator.chip8.step(&mut emulator.peripherals).unwrap();
+        }
+        assert_eq!(emulator.chip8().reg_delay_timer(), 60);
+        emulator.chip8.tick_timers(&mut emulator.peripherals);
+        assert_eq!(emulator.chip8().reg_delay_timer(), 59);
+    }
 }
@@ -0,0 +1,63 @@
+use std::error;
+use std::fmt;
+
+/// What a successful `Chip8::step` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction ran, or is waiting for a key or the vertical blank
+    Executed,
+    /// The machine is halted by `00FD` and no longer runs instructions
+    Halted,
+}
+
+/// An instruction the machine cannot execute. The machine is left as it was
+/// before the faulting instruction, with the PC pointing at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    InvalidOpcode { pc: usize, opcode: u16 },
+    /// `2NNN` with all the stack levels in use
+    StackOverflow { pc: usize },
+    /// `00EE` with an empty stack
+    StackUnderflow { pc: usize },
+    /// Access to `addr`, past the end of memory
+    MemoryOutOfBounds { pc: usize, addr: usize },
+    /// The PC itself left memory
+    PcOutOfRange { pc: usize },
+}
+
+impl Fault {
+    /// Address of the faulting instruction
+    pub fn pc(&self) -> usize {
+        match *self {
+            Fault::InvalidOpcode { pc, .. } |
+            Fault::StackOverflow { pc } |
+            Fault::StackUnderflow { pc } |
+            Fault::MemoryOutOfBounds { pc, .. } |
+            Fault::PcOutOfRange { pc } => pc,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::InvalidOpcode { pc, opcode } => {
+                write!(f, "Invalid opcode 0x{:04x} at 0x{:03x}", opcode, pc)
+            }
+            Fault::StackOverflow { pc } => write!(f, "Stack overflow at 0x{:03x}", pc),
+            Fault::StackUnderflow { pc } => {
+                write!(f, "Return with an empty stack at 0x{:03x}", pc)
+            }
+            Fault::MemoryOutOfBounds { pc, addr } => {
+                write!(f, "Memory access out of bounds (0x{:x}) at 0x{:03x}", addr, pc)
+            }
+            Fault::PcOutOfRange { pc } => write!(f, "PC out of memory at 0x{:x}", pc),
+        }
+    }
+}
+
+impl error::Error for Fault {
+    fn description(&self) -> &str {
+        "machine fault"
+    }
+}
@@ -0,0 +1,199 @@
+use std::convert::TryFrom;
+
+use emulator::{Frontend, Hotkey};
+use input::{InputSource, KeyStates};
+use peripherals::{Key, NUM_KEYS};
+use video_engine::VideoEngine;
+
+/// A key state change scheduled for a given frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: Key,
+    pub pressed: bool,
+}
+
+/// Scripted keypad input for unattended runs.
+///
+/// The text form has one event per line, `<frame> <key> down|up`, with the
+/// key given as a hex digit. Blank lines and `#` comments are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+}
+
+impl KeyScript {
+    pub fn new() -> Self {
+        KeyScript { events: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut script = KeyScript::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("line {}: expected <frame> <key> down|up", line_no + 1));
+            }
+            let frame = fields[0]
+                .parse()
+                .map_err(|_| format!("line {}: invalid frame {}", line_no + 1, fields[0]))?;
+            let key = parse_key(fields[1]).map_err(|e| format!("line {}: {}", line_no + 1, e))?;
+            let pressed = match fields[2] {
+                "down" | "press" => true,
+                "up" | "release" => false,
+                other => return Err(format!("line {}: invalid key action {}", line_no + 1, other)),
+            };
+            script.push(KeyEvent {
+                frame: frame,
+                key: key,
+                pressed: pressed,
+            });
+        }
+        Ok(script)
+    }
+
+    pub fn push(&mut self, event: KeyEvent) {
+        self.events.push(event);
+        self.events.sort_by_key(|e| e.frame);
+    }
+
+    /// Holds `key` down for `frames` frames starting at `frame`
+    pub fn press(&mut self, frame: u64, key: Key, frames: u64) {
+        self.push(KeyEvent {
+            frame: frame,
+            key: key,
+            pressed: true,
+        });
+        self.push(KeyEvent {
+            frame: frame + frames,
+            key: key,
+            pressed: false,
+        });
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+}
+
+/// Parses a keypad key written as a single hex digit
+pub fn parse_key(text: &str) -> Result<Key, String> {
+    u8::from_str_radix(text, 16)
+        .map_err(|_| format!("invalid key {}", text))
+        .and_then(Key::try_from)
+}
+
+/// Frontend for runs without a display: input comes from a `KeyScript` and
+/// frames are only counted.
+pub struct HeadlessFrontend {
+    script: KeyScript,
+    next_event: usize,
+    keys: KeyStates,
+    frame: u64,
+}
+
+impl HeadlessFrontend {
+    pub fn new(script: KeyScript) -> Self {
+        HeadlessFrontend {
+            script: script,
+            next_event: 0,
+            keys: [false; NUM_KEYS],
+            frame: 0,
+        }
+    }
+
+    /// Number of frames presented so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+}
+
+impl Frontend for HeadlessFrontend {
+    fn is_open(&self) -> bool {
+        true
+    }
+
+    fn present(&mut self, _video: &VideoEngine) {
+        self.frame += 1;
+    }
+
+    fn idle(&mut self) {}
+
+    fn is_hotkey_pressed(&mut self, _hotkey: Hotkey) -> bool {
+        false
+    }
+}
+
+impl InputSource for HeadlessFrontend {
+    fn poll(&mut self, keys: &mut KeyStates) {
+        let events = self.script.events();
+        while self.next_event < events.len() && events[self.next_event].frame <= self.frame {
+            let event = events[self.next_event];
+            self.keys[u8::from(event.key) as usize] = event.pressed;
+            self.next_event += 1;
+        }
+        for (key, is_down) in keys.iter_mut().zip(self.keys.iter()) {
+            *key |= *is_down;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(frame: u64, key: u8, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            frame: frame,
+            key: Key::try_from(key).unwrap(),
+            pressed: pressed,
+        }
+    }
+
+    #[test]
+    fn scripts_are_parsed_and_sorted_by_frame() {
+        let script = KeyScript::parse("# Start the game
+                                       30 5 down
+                                       10 a press   # menu
+
+                                       12 A release
+                                       31  5  up")
+            .unwrap();
+        assert_eq!(script.events(),
+                   &[event(10, 0xA, true),
+                     event(12, 0xA, false),
+                     event(30, 5, true),
+                     event(31, 5, false)]);
+    }
+
+    #[test]
+    fn script_errors_name_the_line() {
+        let error = |text| KeyScript::parse(text).unwrap_err();
+        assert_eq!(error("1 5 down\n2 5"), "line 2: expected <frame> <key> down|up");
+        assert_eq!(error("-1 5 down"), "line 1: invalid frame -1");
+        assert_eq!(error("1 g down"), "line 1: invalid key g");
+        assert_eq!(error("1 10 down").split(": ").next(), Some("line 1"));
+        assert_eq!(error("1 5 hold"), "line 1: invalid key action hold");
+    }
+
+    #[test]
+    fn keys_are_held_from_their_frame() {
+        let mut script = KeyScript::new();
+        script.press(1, Key::Key7, 2);
+        let mut frontend = HeadlessFrontend::new(script);
+        let video = VideoEngine::new();
+        let mut held = Vec::new();
+        for _ in 0..4 {
+            let mut keys = [false; NUM_KEYS];
+            frontend.poll(&mut keys);
+            held.push(keys[7]);
+            frontend.present(&video);
+        }
+        assert_eq!(held, vec![false, true, true, false]);
+        assert_eq!(frontend.frame(), 4);
+    }
+}
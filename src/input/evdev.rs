@@ -0,0 +1,222 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+
+use libc;
+
+use input::gamepad::{Axis, Button, GamepadDevice, GamepadEvent};
+
+/// Size of a `struct input_event`: a `struct timeval`, then type, code and value
+const EVENT_SIZE: usize = mem::size_of::<libc::timeval>() + 8;
+
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_RX: u16 = 0x03;
+const ABS_RY: u16 = 0x04;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+
+const BUTTON_CODES: [(u16, Button); 14] = [(0x130, Button::South),
+                                           (0x131, Button::East),
+                                           (0x133, Button::North),
+                                           (0x134, Button::West),
+                                           (0x136, Button::L1),
+                                           (0x137, Button::R1),
+                                           (0x138, Button::L2),
+                                           (0x139, Button::R2),
+                                           (0x13a, Button::Select),
+                                           (0x13b, Button::Start),
+                                           (0x220, Button::DpadUp),
+                                           (0x221, Button::DpadDown),
+                                           (0x222, Button::DpadLeft),
+                                           (0x223, Button::DpadRight)];
+
+/// Directory where udev lists input devices by name
+const BY_ID_DIR: &'static str = "/dev/input/by-id";
+
+/// A gamepad read from a Linux evdev node such as `/dev/input/event5`.
+///
+/// The node is read directly, so the user needs read access to it (usually
+/// by being in the `input` group). Stick ranges cannot be queried without
+/// an ioctl, so they default to the signed 16 bit range most pads use.
+pub struct EvdevDevice {
+    file: File,
+    pending: Vec<u8>,
+    axis_min: i32,
+    axis_max: i32,
+}
+
+impl EvdevDevice {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+        Ok(EvdevDevice {
+            file: file,
+            pending: Vec::new(),
+            axis_min: i16::min_value() as i32,
+            axis_max: i16::max_value() as i32,
+        })
+    }
+
+    /// Path of the first joystick udev knows about, if any
+    pub fn find() -> Option<String> {
+        let mut paths: Vec<String> = match fs::read_dir(BY_ID_DIR) {
+            Ok(entries) => {
+                entries.filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path().to_string_lossy().into_owned())
+                    .filter(|path| path.ends_with("-event-joystick"))
+                    .collect()
+            }
+            Err(_) => return None,
+        };
+        paths.sort();
+        paths.into_iter().next()
+    }
+
+    /// Sets the raw values reported at both ends of the sticks
+    pub fn set_axis_range(&mut self, min: i32, max: i32) {
+        self.axis_min = min;
+        self.axis_max = max;
+    }
+
+    fn translate(&self, event_type: u16, code: u16, value: i32, events: &mut Vec<GamepadEvent>) {
+        match event_type {
+            EV_KEY => {
+                if let Some(&(_, button)) = BUTTON_CODES.iter().find(|&&(c, _)| c == code) {
+                    // 2 is an autorepeat, the button is still down
+                    events.push(GamepadEvent::Button(button, value != 0));
+                }
+            }
+            EV_ABS => {
+                match code {
+                    ABS_X => events.push(GamepadEvent::Axis(Axis::LeftX, self.scale(value))),
+                    ABS_Y => events.push(GamepadEvent::Axis(Axis::LeftY, self.scale(value))),
+                    ABS_RX => events.push(GamepadEvent::Axis(Axis::RightX, self.scale(value))),
+                    ABS_RY => events.push(GamepadEvent::Axis(Axis::RightY, self.scale(value))),
+                    // Many pads report their d-pad as a hat going from -1 to 1
+                    ABS_HAT0X => {
+                        events.push(GamepadEvent::Button(Button::DpadLeft, value < 0));
+                        events.push(GamepadEvent::Button(Button::DpadRight, value > 0));
+                    }
+                    ABS_HAT0Y => {
+                        events.push(GamepadEvent::Button(Button::DpadUp, value < 0));
+                        events.push(GamepadEvent::Button(Button::DpadDown, value > 0));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn scale(&self, value: i32) -> f32 {
+        let range = (self.axis_max - self.axis_min) as f32;
+        if range <= 0.0 {
+            return 0.0;
+        }
+        let value = (value - self.axis_min) as f32 / range * 2.0 - 1.0;
+        value.max(-1.0).min(1.0)
+    }
+}
+
+impl GamepadDevice for EvdevDevice {
+    fn read_events(&mut self) -> io::Result<Vec<GamepadEvent>> {
+        let mut buffer = [0u8; EVENT_SIZE * 64];
+        loop {
+            match self.file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => self.pending.extend_from_slice(&buffer[..read]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut events = Vec::new();
+        let complete = self.pending.len() / EVENT_SIZE * EVENT_SIZE;
+        for raw in self.pending[..complete].chunks(EVENT_SIZE) {
+            let fields = &raw[EVENT_SIZE - 8..];
+            let event_type = native_u16(&fields[0..2]);
+            let code = native_u16(&fields[2..4]);
+            let value = native_u32(&fields[4..8]) as i32;
+            self.translate(event_type, code, value, &mut events);
+        }
+        self.pending.drain(..complete);
+        Ok(events)
+    }
+}
+
+fn native_u16(bytes: &[u8]) -> u16 {
+    native_u32(bytes) as u16
+}
+
+/// Reads an integer of `bytes.len()` bytes in the host byte order
+fn native_u32(bytes: &[u8]) -> u32 {
+    if cfg!(target_endian = "little") {
+        bytes.iter().rev().fold(0, |acc, byte| acc << 8 | *byte as u32)
+    } else {
+        bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use input::InputSource;
+    use input::gamepad::{Gamepad, GamepadProfile};
+    use peripherals::NUM_KEYS;
+
+    /// An event as the kernel writes it, with a zero timestamp
+    fn raw_event(event_type: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut raw = vec![0; EVENT_SIZE - 8];
+        raw.extend(native_bytes(event_type as u32, 2));
+        raw.extend(native_bytes(code as u32, 2));
+        raw.extend(native_bytes(value as u32, 4));
+        raw
+    }
+
+    fn native_bytes(value: u32, len: usize) -> Vec<u8> {
+        let mut bytes: Vec<u8> = (0..len).map(|i| (value >> (8 * i)) as u8).collect();
+        if cfg!(target_endian = "big") {
+            bytes.reverse();
+        }
+        bytes
+    }
+
+    fn held_keys(gamepad: &mut Gamepad<EvdevDevice>) -> Vec<usize> {
+        let mut keys = [false; NUM_KEYS];
+        gamepad.poll(&mut keys);
+        (0..NUM_KEYS).filter(|&idx| keys[idx]).collect()
+    }
+
+    #[test]
+    fn events_of_a_fake_node_are_mapped_to_keys() {
+        let path = env::temp_dir().join("chip8emu-evdev-test");
+        let path = path.to_str().unwrap();
+        let start = raw_event(EV_KEY, 0x13b, 1);
+        {
+            let mut node = File::create(path).unwrap();
+            // South pressed, hat up, left stick fully right and a sync
+            node.write_all(&raw_event(EV_KEY, 0x130, 1)).unwrap();
+            node.write_all(&raw_event(EV_ABS, ABS_HAT0Y, -1)).unwrap();
+            node.write_all(&raw_event(EV_ABS, ABS_X, 32767)).unwrap();
+            node.write_all(&raw_event(0, 0, 0)).unwrap();
+            // Half of a start press
+            node.write_all(&start[..5]).unwrap();
+        }
+        let device = EvdevDevice::open(path).unwrap();
+        let mut gamepad = Gamepad::new(device, GamepadProfile::default());
+        assert_eq!(held_keys(&mut gamepad), vec![5, 6, 9]);
+
+        OpenOptions::new().append(true).open(path).unwrap().write_all(&start[5..]).unwrap();
+        let held = held_keys(&mut gamepad);
+        fs::remove_file(path).unwrap();
+        assert_eq!(held, vec![1, 5, 6, 9]);
+    }
+}
@@ -0,0 +1,356 @@
+use std::io;
+
+use input::{InputSource, KeyStates};
+use keymap::Keymap;
+use peripherals::Key;
+
+/// Prefix of gamepad control names in a `Keymap`
+pub const CONTROL_PREFIX: &'static str = "Pad";
+
+/// How far an axis has to be pushed before it counts as a key press
+pub const DEFAULT_AXIS_THRESHOLD: f32 = 0.5;
+
+/// Gamepad buttons, named after their position on the pad
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    South,
+    East,
+    West,
+    North,
+    L1,
+    R1,
+    L2,
+    R2,
+    Select,
+    Start,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+}
+
+const BUTTONS: [(Button, &'static str); 14] = [(Button::South, "South"),
+                                              (Button::East, "East"),
+                                              (Button::West, "West"),
+                                              (Button::North, "North"),
+                                              (Button::L1, "L1"),
+                                              (Button::R1, "R1"),
+                                              (Button::L2, "L2"),
+                                              (Button::R2, "R2"),
+                                              (Button::Select, "Select"),
+                                              (Button::Start, "Start"),
+                                              (Button::DpadUp, "Up"),
+                                              (Button::DpadDown, "Down"),
+                                              (Button::DpadLeft, "Left"),
+                                              (Button::DpadRight, "Right")];
+
+/// Analog stick axes, negative values being up and left
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+}
+
+const AXES: [(Axis, &'static str); 4] = [(Axis::LeftX, "LeftX"),
+                                         (Axis::LeftY, "LeftY"),
+                                         (Axis::RightX, "RightX"),
+                                         (Axis::RightY, "RightY")];
+
+/// What a keypad key can be bound to on a gamepad
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Control {
+    Button(Button),
+    AxisNegative(Axis),
+    AxisPositive(Axis),
+}
+
+impl Control {
+    /// Parses a keymap name: `PadSouth`, `PadUp`, `PadLeftX-`, `PadLeftY+`...
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        if !name.starts_with(CONTROL_PREFIX) {
+            return Err(format!("Not a gamepad control \"{}\"", name));
+        }
+        let control = &name[CONTROL_PREFIX.len()..];
+        if let Some(&(button, _)) = BUTTONS.iter().find(|&&(_, n)| n == control) {
+            return Ok(Control::Button(button));
+        }
+        if control.len() > 1 {
+            let (axis_name, sign) = control.split_at(control.len() - 1);
+            if let Some(&(axis, _)) = AXES.iter().find(|&&(_, n)| n == axis_name) {
+                match sign {
+                    "-" => return Ok(Control::AxisNegative(axis)),
+                    "+" => return Ok(Control::AxisPositive(axis)),
+                    _ => {}
+                }
+            }
+        }
+        Err(format!("Unknown gamepad control \"{}\"", name))
+    }
+}
+
+/// A state change reported by a gamepad. Axis values are scaled to -1..1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GamepadEvent {
+    Button(Button, bool),
+    Axis(Axis, f32),
+}
+
+/// A source of gamepad events, such as an evdev device
+pub trait GamepadDevice {
+    /// Returns the events that arrived since the last call, without blocking
+    fn read_events(&mut self) -> io::Result<Vec<GamepadEvent>>;
+}
+
+/// Gamepad controls bound to keypad keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct GamepadProfile {
+    bindings: Vec<(Control, Key)>,
+    axis_threshold: f32,
+}
+
+impl GamepadProfile {
+    pub fn new() -> Self {
+        GamepadProfile {
+            bindings: Vec::new(),
+            axis_threshold: DEFAULT_AXIS_THRESHOLD,
+        }
+    }
+
+    /// Picks the `Pad...` names from a keymap, so that per-ROM keymap
+    /// overrides apply to gamepads too. A keymap without any gamepad
+    /// control gets the default profile.
+    pub fn from_keymap(keymap: &Keymap) -> Result<Self, String> {
+        let mut profile = GamepadProfile::new();
+        for (key, hosts) in keymap.iter() {
+            for host in hosts.iter().filter(|host| host.starts_with(CONTROL_PREFIX)) {
+                profile.bind(Control::from_name(host)?, key);
+            }
+        }
+        if profile.bindings.is_empty() {
+            Ok(GamepadProfile::default())
+        } else {
+            Ok(profile)
+        }
+    }
+
+    pub fn bind(&mut self, control: Control, key: Key) {
+        self.bindings.push((control, key));
+    }
+
+    pub fn set_axis_threshold(&mut self, threshold: f32) {
+        self.axis_threshold = threshold;
+    }
+}
+
+impl Default for GamepadProfile {
+    /// D-pad and left stick on 5/7/8/9 (the WASD keys of the standard
+    /// keymap), face buttons on 4/6 and start on 1
+    fn default() -> Self {
+        let mut profile = GamepadProfile::new();
+        profile.bind(Control::Button(Button::DpadUp), Key::Key5);
+        profile.bind(Control::Button(Button::DpadLeft), Key::Key7);
+        profile.bind(Control::Button(Button::DpadDown), Key::Key8);
+        profile.bind(Control::Button(Button::DpadRight), Key::Key9);
+        profile.bind(Control::AxisNegative(Axis::LeftY), Key::Key5);
+        profile.bind(Control::AxisNegative(Axis::LeftX), Key::Key7);
+        profile.bind(Control::AxisPositive(Axis::LeftY), Key::Key8);
+        profile.bind(Control::AxisPositive(Axis::LeftX), Key::Key9);
+        profile.bind(Control::Button(Button::South), Key::Key6);
+        profile.bind(Control::Button(Button::West), Key::Key4);
+        profile.bind(Control::Button(Button::Start), Key::Key1);
+        profile
+    }
+}
+
+/// Turns the events of a gamepad device into keypad key presses
+pub struct Gamepad<D: GamepadDevice> {
+    device: D,
+    profile: GamepadProfile,
+    buttons: Vec<Button>,
+    axes: Vec<(Axis, f32)>,
+    failed: bool,
+}
+
+impl<D: GamepadDevice> Gamepad<D> {
+    pub fn new(device: D, profile: GamepadProfile) -> Self {
+        Gamepad {
+            device: device,
+            profile: profile,
+            buttons: Vec::new(),
+            axes: Vec::new(),
+            failed: false,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    fn handle_event(&mut self, event: GamepadEvent) {
+        match event {
+            GamepadEvent::Button(button, true) => {
+                if !self.buttons.contains(&button) {
+                    self.buttons.push(button);
+                }
+            }
+            GamepadEvent::Button(button, false) => self.buttons.retain(|b| *b != button),
+            GamepadEvent::Axis(axis, value) => {
+                self.axes.retain(|&(a, _)| a != axis);
+                self.axes.push((axis, value));
+            }
+        }
+    }
+
+    fn axis_value(&self, axis: Axis) -> f32 {
+        self.axes.iter().find(|&&(a, _)| a == axis).map(|&(_, value)| value).unwrap_or(0.0)
+    }
+
+    fn is_active(&self, control: Control) -> bool {
+        match control {
+            Control::Button(button) => self.buttons.contains(&button),
+            Control::AxisNegative(axis) => self.axis_value(axis) <= -self.profile.axis_threshold,
+            Control::AxisPositive(axis) => self.axis_value(axis) >= self.profile.axis_threshold,
+        }
+    }
+}
+
+impl<D: GamepadDevice> InputSource for Gamepad<D> {
+    fn poll(&mut self, keys: &mut KeyStates) {
+        if self.failed {
+            return;
+        }
+        match self.device.read_events() {
+            Ok(events) => {
+                for event in events {
+                    self.handle_event(event);
+                }
+            }
+            Err(e) => {
+                // An unplugged pad simply stops holding keys
+                println!("Gamepad error, ignoring it from now on: {}", e);
+                self.failed = true;
+                return;
+            }
+        }
+        for &(control, key) in &self.profile.bindings {
+            if self.is_active(control) {
+                keys[u8::from(key) as usize] = true;
+            }
+        }
+    }
+}
+
+/// A gamepad driven by code, for tests and scripted input
+#[derive(Debug, Clone, Default)]
+pub struct FakeDevice {
+    pending: Vec<GamepadEvent>,
+    disconnected: bool,
+}
+
+impl FakeDevice {
+    pub fn new() -> Self {
+        FakeDevice::default()
+    }
+
+    /// Queues an event for the next poll
+    pub fn push(&mut self, event: GamepadEvent) {
+        self.pending.push(event);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.push(GamepadEvent::Button(button, true));
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.push(GamepadEvent::Button(button, false));
+    }
+
+    pub fn move_axis(&mut self, axis: Axis, value: f32) {
+        self.push(GamepadEvent::Axis(axis, value));
+    }
+
+    /// Makes the next read fail like an unplugged device
+    pub fn disconnect(&mut self) {
+        self.disconnected = true;
+    }
+}
+
+impl GamepadDevice for FakeDevice {
+    fn read_events(&mut self) -> io::Result<Vec<GamepadEvent>> {
+        if self.disconnected {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "device disconnected"));
+        }
+        Ok(self.pending.drain(..).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peripherals::NUM_KEYS;
+
+    fn held_keys(gamepad: &mut Gamepad<FakeDevice>) -> Vec<usize> {
+        let mut keys = [false; NUM_KEYS];
+        gamepad.poll(&mut keys);
+        (0..NUM_KEYS).filter(|&idx| keys[idx]).collect()
+    }
+
+    #[test]
+    fn control_names() {
+        assert_eq!(Control::from_name("PadSouth"), Ok(Control::Button(Button::South)));
+        assert_eq!(Control::from_name("PadUp"), Ok(Control::Button(Button::DpadUp)));
+        assert_eq!(Control::from_name("PadRightX+"), Ok(Control::AxisPositive(Axis::RightX)));
+        assert_eq!(Control::from_name("PadLeftY-"), Ok(Control::AxisNegative(Axis::LeftY)));
+        assert!(Control::from_name("PadLeftZ+").is_err());
+        assert!(Control::from_name("PadLeftX").is_err());
+        assert!(Control::from_name("South").is_err());
+    }
+
+    #[test]
+    fn buttons_hold_keys_until_released() {
+        let mut gamepad = Gamepad::new(FakeDevice::new(), GamepadProfile::default());
+        gamepad.device_mut().press(Button::DpadUp);
+        gamepad.device_mut().press(Button::South);
+        assert_eq!(held_keys(&mut gamepad), vec![5, 6]);
+        assert_eq!(held_keys(&mut gamepad), vec![5, 6]);
+        gamepad.device_mut().release(Button::DpadUp);
+        assert_eq!(held_keys(&mut gamepad), vec![6]);
+    }
+
+    #[test]
+    fn axes_hold_keys_past_the_threshold() {
+        let mut gamepad = Gamepad::new(FakeDevice::new(), GamepadProfile::default());
+        gamepad.device_mut().move_axis(Axis::LeftX, -0.3);
+        assert_eq!(held_keys(&mut gamepad), Vec::<usize>::new());
+        gamepad.device_mut().move_axis(Axis::LeftX, -0.9);
+        gamepad.device_mut().move_axis(Axis::LeftY, 1.0);
+        assert_eq!(held_keys(&mut gamepad), vec![7, 8]);
+    }
+
+    #[test]
+    fn profiles_come_from_the_keymap() {
+        let mut keymap = Keymap::standard();
+        assert_eq!(GamepadProfile::from_keymap(&keymap), Ok(GamepadProfile::default()));
+        keymap.bind(Key::Key2, vec!["PadEast".into()]);
+        let profile = GamepadProfile::from_keymap(&keymap).unwrap();
+        let mut gamepad = Gamepad::new(FakeDevice::new(), profile);
+        gamepad.device_mut().press(Button::East);
+        gamepad.device_mut().press(Button::DpadUp);
+        assert_eq!(held_keys(&mut gamepad), vec![2]);
+    }
+
+    #[test]
+    fn a_disconnected_device_holds_nothing() {
+        let mut gamepad = Gamepad::new(FakeDevice::new(), GamepadProfile::default());
+        gamepad.device_mut().press(Button::Start);
+        assert_eq!(held_keys(&mut gamepad), vec![1]);
+        gamepad.device_mut().disconnect();
+        assert_eq!(held_keys(&mut gamepad), Vec::<usize>::new());
+    }
+}
@@ -0,0 +1,27 @@
+pub mod gamepad;
+#[cfg(target_os = "linux")]
+pub mod evdev;
+
+use peripherals::{key_from_index, Keypad, NUM_KEYS};
+
+/// Held state of the 16 keypad keys, indexed by key code
+pub type KeyStates = [bool; NUM_KEYS];
+
+/// Anything that can hold keypad keys down: a keyboard, a gamepad, a script.
+///
+/// The emulator polls every source once per frame and a key is down if any
+/// source holds it, so a source only ever sets entries in `keys`.
+pub trait InputSource {
+    fn poll(&mut self, keys: &mut KeyStates);
+}
+
+/// Polls all `sources` and copies the merged state into the keypad
+pub fn update_keypad(sources: &mut [&mut InputSource], keypad: &mut Keypad) {
+    let mut keys = [false; NUM_KEYS];
+    for source in sources.iter_mut() {
+        source.poll(&mut keys);
+    }
+    for (idx, is_down) in keys.iter().enumerate() {
+        keypad.set_button_state(key_from_index(idx), *is_down);
+    }
+}
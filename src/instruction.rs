@@ -1,77 +1,128 @@
 use std::convert::TryFrom;
 use std::fmt;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Cls,
     Ret,
+    Sys { addr: usize },
+    Scd { n: usize },
+    Scu { n: usize },
+    Scr,
+    Scl,
+    Exit,
+    Low,
+    High,
     Jmp { addr: usize },
     Jsr { addr: usize },
     Skeq { vr: usize, k: u8 },
     Skne { vr: usize, k: u8 },
+    Skeqr { vr: usize, vy: usize },
+    Sreg { vr: usize, vy: usize },
+    Lreg { vr: usize, vy: usize },
     Mov { vr: usize, k: u8 },
     Movr { vr: usize, vy: usize },
+    Or { vr: usize, vy: usize },
     And { vr: usize, vy: usize },
     Xor { vr: usize, vy: usize },
-    Shl { vr: usize },
-    Shr { vr: usize },
+    Shl { vr: usize, vy: usize },
+    Shr { vr: usize, vy: usize },
     Skner { vr: usize, vy: usize },
     Add { vr: usize, k: u8 },
     Addr { vr: usize, vy: usize },
     Subr { vr: usize, vy: usize },
     Subn { vr: usize, vy: usize },
     Mvi { k: u16 },
+    Lmvi,
+    Jmi { addr: usize },
     Rnd { vr: usize, k: u8 },
     Sprite { rx: usize, ry: usize, s: usize },
-    Skp { k: u8 },
-    Sknp { k: u8 },
+    Skp { vr: usize },
+    Sknp { vr: usize },
     Key { vr: usize },
     Sdelay { vr: usize },
     Gdelay { vr: usize },
+    Ssound { vr: usize },
     Adi { vr: usize },
     Font { vr: usize },
+    Xfont { vr: usize },
     Bcd { vr: usize },
     Str { vr: usize },
     Ldr { vr: usize },
+    Rstr { vr: usize },
+    Rldr { vr: usize },
+    Plane { n: usize },
+    Audio,
+    Pitch { vr: usize },
 }
 
 impl TryFrom<u16> for Instruction {
-    type Err = String;
-    fn try_from(opcode: u16) -> Result<Self, Self::Err> {
+    type Error = String;
+    fn try_from(opcode: u16) -> Result<Self, Self::Error> {
         match opcode & 0xF000 {
             0x0000 => {
-                match opcode & 0x00FF {
+                match opcode {
                     0x00E0 => Ok(Instruction::Cls),
                     0x00EE => Ok(Instruction::Ret),
-                    _ => {
-                        Err(format!("Opcode 0x{:x} not yet implemented (in 0x0000 branch)",
-                                    opcode))
+                    0x00FB => Ok(Instruction::Scr),
+                    0x00FC => Ok(Instruction::Scl),
+                    0x00FD => Ok(Instruction::Exit),
+                    0x00FE => Ok(Instruction::Low),
+                    0x00FF => Ok(Instruction::High),
+                    _ if opcode & 0xFFF0 == 0x00C0 => {
+                        Ok(Instruction::Scd { n: (opcode & 0x000F) as usize })
+                    }
+                    _ if opcode & 0xFFF0 == 0x00D0 => {
+                        Ok(Instruction::Scu { n: (opcode & 0x000F) as usize })
                     }
+                    _ => k_op(opcode, |addr| Instruction::Sys { addr: addr as usize }),
                 }
             }
             0x1000 => k_op(opcode, |addr| Instruction::Jmp { addr: addr as usize }),
             0x2000 => k_op(opcode, |addr| Instruction::Jsr { addr: addr as usize }),
             0x3000 => vr_k_op(opcode, |vr, k| Instruction::Skeq { vr: vr, k: k }),
             0x4000 => vr_k_op(opcode, |vr, k| Instruction::Skne { vr: vr, k: k }),
+            0x5000 => {
+                match opcode & 0xF00F {
+                    0x5000 => vr_vy_op(opcode, |vr, vy| Instruction::Skeqr { vr: vr, vy: vy }),
+                    0x5002 => vr_vy_op(opcode, |vr, vy| Instruction::Sreg { vr: vr, vy: vy }),
+                    0x5003 => vr_vy_op(opcode, |vr, vy| Instruction::Lreg { vr: vr, vy: vy }),
+                    _ => {
+                        Err(format!("Opcode 0x{:x} not yet implemented (in 0x5000 branch)",
+                                    opcode))
+                    }
+                }
+            }
             0x6000 => vr_k_op(opcode, |vr, k| Instruction::Mov { vr: vr, k: k }),
             0x7000 => vr_k_op(opcode, |vr, k| Instruction::Add { vr: vr, k: k }),
             0x8000 => {
                 match opcode & 0xF00F {
                     0x8000 => vr_vy_op(opcode, |vr, vy| Instruction::Movr { vr: vr, vy: vy }),
+                    0x8001 => vr_vy_op(opcode, |vr, vy| Instruction::Or { vr: vr, vy: vy }),
                     0x8002 => vr_vy_op(opcode, |vr, vy| Instruction::And { vr: vr, vy: vy }),
                     0x8003 => vr_vy_op(opcode, |vr, vy| Instruction::Xor { vr: vr, vy: vy }),
                     0x8004 => vr_vy_op(opcode, |vr, vy| Instruction::Addr { vr: vr, vy: vy }),
                     0x8005 => vr_vy_op(opcode, |vr, vy| Instruction::Subr { vr: vr, vy: vy }),
-                    0x8006 => vr_op(opcode, |vr| Instruction::Shr { vr: vr }),
+                    0x8006 => vr_vy_op(opcode, |vr, vy| Instruction::Shr { vr: vr, vy: vy }),
                     0x8007 => vr_vy_op(opcode, |vr, vy| Instruction::Subn { vr: vr, vy: vy }),
-                    0x800E => vr_op(opcode, |vr| Instruction::Shl { vr: vr }),
+                    0x800E => vr_vy_op(opcode, |vr, vy| Instruction::Shl { vr: vr, vy: vy }),
                     _ => {
                         Err(format!("Opcode 0x{:x} not yet implemented (in 0x8000 branch)",
                                     opcode))
                     }
                 }
             }
-            0x9000 => vr_vy_op(opcode, |vr, vy| Instruction::Skner { vr: vr, vy: vy }),
+            0x9000 => {
+                match opcode & 0xF00F {
+                    0x9000 => vr_vy_op(opcode, |vr, vy| Instruction::Skner { vr: vr, vy: vy }),
+                    _ => {
+                        Err(format!("Opcode 0x{:x} not yet implemented (in 0x9000 branch)",
+                                    opcode))
+                    }
+                }
+            }
             0xA000 => k_op(opcode, |k| Instruction::Mvi { k: k }),
+            0xB000 => k_op(opcode, |addr| Instruction::Jmi { addr: addr as usize }),
             0xC000 => vr_k_op(opcode, |vr, k| Instruction::Rnd { vr: vr, k: k }),
             0xD000 => {
                 let s = (opcode & 0x000F) as usize;
@@ -85,8 +136,8 @@ impl TryFrom<u16> for Instruction {
             }
             0xE000 => {
                 match opcode & 0xF0FF {
-                    0xE09E => vr_op(opcode, |k| Instruction::Skp { k: k as u8 }),
-                    0xE0A1 => vr_op(opcode, |k| Instruction::Sknp { k: k as u8 }),
+                    0xE09E => vr_op(opcode, |vr| Instruction::Skp { vr: vr }),
+                    0xE0A1 => vr_op(opcode, |vr| Instruction::Sknp { vr: vr }),
                     _ => {
                         Err(format!("Opcode 0x{:x} not yet implemented (in 0xE000 branch)",
                                     opcode))
@@ -95,14 +146,22 @@ impl TryFrom<u16> for Instruction {
             }
             0xF000 => {
                 match opcode & 0xF0FF {
+                    0xF000 if opcode == 0xF000 => Ok(Instruction::Lmvi),
+                    0xF001 => vr_op(opcode, |n| Instruction::Plane { n: n }),
+                    0xF002 if opcode == 0xF002 => Ok(Instruction::Audio),
                     0xF00A => vr_op(opcode, |vr| Instruction::Key { vr: vr }),
                     0xF007 => vr_op(opcode, |vr| Instruction::Gdelay { vr: vr }),
                     0xF015 => vr_op(opcode, |vr| Instruction::Sdelay { vr: vr }),
+                    0xF018 => vr_op(opcode, |vr| Instruction::Ssound { vr: vr }),
                     0xF01E => vr_op(opcode, |vr| Instruction::Adi { vr: vr }),
                     0xF029 => vr_op(opcode, |vr| Instruction::Font { vr: vr }),
+                    0xF030 => vr_op(opcode, |vr| Instruction::Xfont { vr: vr }),
                     0xF033 => vr_op(opcode, |vr| Instruction::Bcd { vr: vr }),
+                    0xF03A => vr_op(opcode, |vr| Instruction::Pitch { vr: vr }),
                     0xF055 => vr_op(opcode, |vr| Instruction::Str { vr: vr }),
                     0xF065 => vr_op(opcode, |vr| Instruction::Ldr { vr: vr }),
+                    0xF075 => vr_op(opcode, |vr| Instruction::Rstr { vr: vr }),
+                    0xF085 => vr_op(opcode, |vr| Instruction::Rldr { vr: vr }),
                     _ => {
                         Err(format!("Opcode 0x{:x} not yet implemented (in 0xF000 branch)",
                                     opcode))
@@ -144,39 +203,155 @@ fn k_op<F>(opcode: u16, f: F) -> Result<Instruction, String>
     Ok(f(k))
 }
 
+/// Encodes an instruction back into its opcode. Fields wider than their
+/// place in the opcode are truncated.
+impl From<Instruction> for u16 {
+    fn from(instruction: Instruction) -> u16 {
+        match instruction {
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Sys { addr } => k_opcode(0x0000, addr as u16),
+            Instruction::Scd { n } => 0x00C0 | (n as u16 & 0xF),
+            Instruction::Scu { n } => 0x00D0 | (n as u16 & 0xF),
+            Instruction::Scr => 0x00FB,
+            Instruction::Scl => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Jmp { addr } => k_opcode(0x1000, addr as u16),
+            Instruction::Jsr { addr } => k_opcode(0x2000, addr as u16),
+            Instruction::Skeq { vr, k } => vr_k_opcode(0x3000, vr, k),
+            Instruction::Skne { vr, k } => vr_k_opcode(0x4000, vr, k),
+            Instruction::Skeqr { vr, vy } => vr_vy_opcode(0x5000, vr, vy),
+            Instruction::Sreg { vr, vy } => vr_vy_opcode(0x5002, vr, vy),
+            Instruction::Lreg { vr, vy } => vr_vy_opcode(0x5003, vr, vy),
+            Instruction::Mov { vr, k } => vr_k_opcode(0x6000, vr, k),
+            Instruction::Add { vr, k } => vr_k_opcode(0x7000, vr, k),
+            Instruction::Movr { vr, vy } => vr_vy_opcode(0x8000, vr, vy),
+            Instruction::Or { vr, vy } => vr_vy_opcode(0x8001, vr, vy),
+            Instruction::And { vr, vy } => vr_vy_opcode(0x8002, vr, vy),
+            Instruction::Xor { vr, vy } => vr_vy_opcode(0x8003, vr, vy),
+            Instruction::Addr { vr, vy } => vr_vy_opcode(0x8004, vr, vy),
+            Instruction::Subr { vr, vy } => vr_vy_opcode(0x8005, vr, vy),
+            Instruction::Shr { vr, vy } => vr_vy_opcode(0x8006, vr, vy),
+            Instruction::Subn { vr, vy } => vr_vy_opcode(0x8007, vr, vy),
+            Instruction::Shl { vr, vy } => vr_vy_opcode(0x800E, vr, vy),
+            Instruction::Skner { vr, vy } => vr_vy_opcode(0x9000, vr, vy),
+            Instruction::Mvi { k } => k_opcode(0xA000, k),
+            Instruction::Jmi { addr } => k_opcode(0xB000, addr as u16),
+            Instruction::Rnd { vr, k } => vr_k_opcode(0xC000, vr, k),
+            Instruction::Sprite { rx, ry, s } => vr_vy_opcode(0xD000, rx, ry) | (s as u16 & 0xF),
+            Instruction::Skp { vr } => vr_opcode(0xE09E, vr),
+            Instruction::Sknp { vr } => vr_opcode(0xE0A1, vr),
+            Instruction::Lmvi => 0xF000,
+            Instruction::Plane { n } => vr_opcode(0xF001, n),
+            Instruction::Audio => 0xF002,
+            Instruction::Gdelay { vr } => vr_opcode(0xF007, vr),
+            Instruction::Key { vr } => vr_opcode(0xF00A, vr),
+            Instruction::Sdelay { vr } => vr_opcode(0xF015, vr),
+            Instruction::Ssound { vr } => vr_opcode(0xF018, vr),
+            Instruction::Adi { vr } => vr_opcode(0xF01E, vr),
+            Instruction::Font { vr } => vr_opcode(0xF029, vr),
+            Instruction::Xfont { vr } => vr_opcode(0xF030, vr),
+            Instruction::Bcd { vr } => vr_opcode(0xF033, vr),
+            Instruction::Pitch { vr } => vr_opcode(0xF03A, vr),
+            Instruction::Str { vr } => vr_opcode(0xF055, vr),
+            Instruction::Ldr { vr } => vr_opcode(0xF065, vr),
+            Instruction::Rstr { vr } => vr_opcode(0xF075, vr),
+            Instruction::Rldr { vr } => vr_opcode(0xF085, vr),
+        }
+    }
+}
+
+fn vr_opcode(base: u16, vr: usize) -> u16 {
+    base | ((vr as u16 & 0xF) << 8)
+}
+
+fn vr_k_opcode(base: u16, vr: usize, k: u8) -> u16 {
+    vr_opcode(base, vr) | k as u16
+}
+
+fn vr_vy_opcode(base: u16, vr: usize, vy: usize) -> u16 {
+    vr_opcode(base, vr) | ((vy as u16 & 0xF) << 4)
+}
+
+fn k_opcode(base: u16, k: u16) -> u16 {
+    base | (k & 0x0FFF)
+}
+
 impl fmt::Debug for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Instruction::Cls => write!(f, "cls"),
             Instruction::Ret => write!(f, "ret"),
+            Instruction::Sys { addr } => write!(f, "sys    0x{:x}", addr),
+            Instruction::Scd { n } => write!(f, "scd    0x{:x}", n),
+            Instruction::Scu { n } => write!(f, "scu    0x{:x}", n),
+            Instruction::Scr => write!(f, "scr"),
+            Instruction::Scl => write!(f, "scl"),
+            Instruction::Exit => write!(f, "exit"),
+            Instruction::Low => write!(f, "low"),
+            Instruction::High => write!(f, "high"),
             Instruction::Jmp { addr } => write!(f, "jmp    0x{:x}", addr),
             Instruction::Jsr { addr } => write!(f, "jsr    0x{:x}", addr),
             Instruction::Skeq { vr, k } => write!(f, "skeq   v{}, 0x{:x}", vr, k),
             Instruction::Skne { vr, k } => write!(f, "skne   v{}, 0x{:x}", vr, k),
+            Instruction::Skeqr { vr, vy } => write!(f, "skeq   v{}, v{}", vr, vy),
+            Instruction::Sreg { vr, vy } => write!(f, "sreg   v{}-v{}", vr, vy),
+            Instruction::Lreg { vr, vy } => write!(f, "lreg   v{}-v{}", vr, vy),
             Instruction::Mov { vr, k } => write!(f, "mov    v{}, 0x{:x}", vr, k),
             Instruction::Movr { vr, vy } => write!(f, "mov    v{}, v{}", vr, vy),
+            Instruction::Or { vr, vy } => write!(f, "or     v{}, v{}", vr, vy),
             Instruction::And { vr, vy } => write!(f, "and    v{}, v{}", vr, vy),
             Instruction::Xor { vr, vy } => write!(f, "xor    v{}, v{}", vr, vy),
-            Instruction::Shr { vr } => write!(f, "shr    v{}", vr),
-            Instruction::Shl { vr } => write!(f, "shl    v{}", vr),
+            Instruction::Shr { vr, vy } => write!(f, "shr    v{}, v{}", vr, vy),
+            Instruction::Shl { vr, vy } => write!(f, "shl    v{}, v{}", vr, vy),
             Instruction::Skner { vr, vy } => write!(f, "skne   v{}, v{}", vr, vy),
             Instruction::Add { vr, k } => write!(f, "add    v{}, 0x{:x}", vr, k),
             Instruction::Addr { vr, vy } => write!(f, "add    v{}, v{}", vr, vy),
             Instruction::Subr { vr, vy } => write!(f, "sub    v{}, v{}", vr, vy),
             Instruction::Subn { vr, vy } => write!(f, "subn   v{}, v{}", vr, vy),
             Instruction::Mvi { k } => write!(f, "mvi    0x{:x}", k),
+            Instruction::Lmvi => write!(f, "lmvi"),
+            Instruction::Jmi { addr } => write!(f, "jmi    0x{:x}", addr),
             Instruction::Rnd { vr, k } => write!(f, "rnd    v{}, 0x{:x}", vr, k),
             Instruction::Sprite { rx, ry, s } => write!(f, "sprite {},{},{}", rx, ry, s),
-            Instruction::Skp { k } => write!(f, "skp    0x{:x}", k),
-            Instruction::Sknp { k } => write!(f, "sknp   0x{:x}", k),
+            Instruction::Skp { vr } => write!(f, "skp    v{}", vr),
+            Instruction::Sknp { vr } => write!(f, "sknp   v{}", vr),
             Instruction::Key { vr } => write!(f, "key    v{}", vr),
             Instruction::Sdelay { vr } => write!(f, "sdelay  v{}", vr),
             Instruction::Gdelay { vr } => write!(f, "gdelay  v{}", vr),
+            Instruction::Ssound { vr } => write!(f, "ssound v{}", vr),
             Instruction::Adi { vr } => write!(f, "adi    v{}", vr),
             Instruction::Font { vr } => write!(f, "font   v{}", vr),
+            Instruction::Xfont { vr } => write!(f, "xfont  v{}", vr),
             Instruction::Bcd { vr } => write!(f, "bcd    v{}", vr),
             Instruction::Str { vr } => write!(f, "str    v0-v{}", vr),
             Instruction::Ldr { vr } => write!(f, "ldr    v0-v{}", vr),
+            Instruction::Rstr { vr } => write!(f, "rstr   v0-v{}", vr),
+            Instruction::Rldr { vr } => write!(f, "rldr   v0-v{}", vr),
+            Instruction::Plane { n } => write!(f, "plane  {}", n),
+            Instruction::Audio => write!(f, "audio"),
+            Instruction::Pitch { vr } => write!(f, "pitch  v{}", vr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_decodable_opcode_encodes_back_to_itself() {
+        for opcode in 0..0x10000u32 {
+            let opcode = opcode as u16;
+            if let Ok(instruction) = Instruction::try_from(opcode) {
+                assert!(u16::from(instruction) == opcode,
+                        "{:04x} decodes to {:?}, which encodes to {:04x}",
+                        opcode,
+                        instruction,
+                        u16::from(instruction));
+            }
         }
     }
 }
@@ -0,0 +1,403 @@
+use std::fmt;
+
+use peripherals::{key_from_index, Key, NUM_KEYS};
+
+pub const PRESET_NAMES: [&'static str; 2] = ["standard", "numpad"];
+
+/// Host keys bound to each keypad key, as consecutive rows of the usual
+/// 1 2 3 C / 4 5 6 D / 7 8 9 E / A 0 B F keypad layout
+const KEYPAD_LAYOUT: [u8; NUM_KEYS] = [0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9,
+                                       0xE, 0xA, 0x0, 0xB, 0xF];
+const STANDARD_LAYOUT: [&'static str; NUM_KEYS] = ["Key1", "Key2", "Key3", "Key4", "Q", "W",
+                                                   "E", "R", "A", "S", "D", "F", "Z", "X",
+                                                   "C", "V"];
+/// Host keys for keypad keys 0 to F
+const NUMPAD_KEYS: [&'static str; NUM_KEYS] = ["NumPad0", "NumPad1", "NumPad2", "NumPad3",
+                                               "NumPad4", "NumPad5", "NumPad6", "NumPad7",
+                                               "NumPad8", "NumPad9", "NumPadSlash",
+                                               "NumPadAsterisk", "NumPadMinus", "NumPadPlus",
+                                               "NumPadEnter", "NumPadDot"];
+
+/// Binds each Chip8 keypad key to any number of host keys. Host keys are
+/// plain names; it is up to the frontend to resolve them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    bindings: Vec<Vec<String>>,
+}
+
+impl Keymap {
+    pub fn empty() -> Self {
+        Keymap { bindings: vec![Vec::new(); NUM_KEYS] }
+    }
+
+    /// The 1234/QWER/ASDF/ZXCV block mirroring the original keypad
+    pub fn standard() -> Self {
+        let mut keymap = Keymap::empty();
+        for (code, host) in KEYPAD_LAYOUT.iter().zip(STANDARD_LAYOUT.iter()) {
+            keymap.bindings[*code as usize].push((*host).into());
+        }
+        keymap
+    }
+
+    /// Digits on the numeric keypad, A to F on the operators around it
+    pub fn numpad() -> Self {
+        let mut keymap = Keymap::empty();
+        for (code, host) in NUMPAD_KEYS.iter().enumerate() {
+            keymap.bindings[code].push((*host).into());
+        }
+        keymap
+    }
+
+    pub fn preset(name: &str) -> Result<Self, String> {
+        match name {
+            "standard" => Ok(Keymap::standard()),
+            "numpad" => Ok(Keymap::numpad()),
+            _ => {
+                Err(format!("Unknown keymap preset \"{}\" (expected one of {})",
+                            name,
+                            PRESET_NAMES.join(", ")))
+            }
+        }
+    }
+
+    /// Replaces the host keys bound to `key`
+    pub fn bind(&mut self, key: Key, hosts: Vec<String>) {
+        self.bindings[u8::from(key) as usize] = hosts;
+    }
+
+    pub fn bindings(&self, key: Key) -> &[String] {
+        &self.bindings[u8::from(key) as usize]
+    }
+
+    /// Every keypad key with its host keys
+    pub fn iter(&self) -> Vec<(Key, &[String])> {
+        (0..NUM_KEYS).map(|idx| (key_from_index(idx), &self.bindings[idx][..])).collect()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::standard()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeymapError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Key bindings read from a config file, with optional per-ROM overrides.
+///
+/// The file uses a small subset of TOML:
+///
+/// ```text
+/// # Applies to every ROM
+/// preset = "standard"
+/// 5 = ["W", "Up"]
+///
+/// # Applies on top of the above when running pong.ch8
+/// [rom."pong.ch8"]
+/// 1 = "Up"
+/// 4 = "Down"
+/// ```
+///
+/// Keypad keys are single hex digits. A `preset` entry has to come before
+/// the bindings of its section, as it replaces all of them. Within a
+/// section, a keypad key can only be bound once and a host key only to one
+/// keypad key. Names starting with `Pad` are gamepad controls, see
+/// `input::gamepad::Control`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeymapConfig {
+    default: Keymap,
+    roms: Vec<(String, Vec<Entry>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    Preset(Keymap),
+    Bind(Key, Vec<String>),
+}
+
+impl KeymapConfig {
+    pub fn parse(text: &str) -> Result<Self, KeymapError> {
+        let mut config = KeymapConfig {
+            default: Keymap::standard(),
+            roms: Vec::new(),
+        };
+        let mut bound = Bindings::new();
+        for (idx, raw_line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let error = |message: String| {
+                KeymapError {
+                    line: line_no,
+                    message: message,
+                }
+            };
+            let line = strip_comment(raw_line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let rom = parse_section(line).map_err(&error)?;
+                config.roms.push((rom, Vec::new()));
+                bound = Bindings::new();
+                continue;
+            }
+
+            let entry = parse_entry(line).map_err(&error)?;
+            if let Entry::Bind(key, ref hosts) = entry {
+                bound.add(key, hosts, line_no).map_err(&error)?;
+            }
+            match config.roms.last_mut() {
+                Some(&mut (_, ref mut entries)) => entries.push(entry),
+                None => apply(&mut config.default, entry),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The bindings to use for a ROM, identified by its file name
+    pub fn for_rom(&self, rom_name: &str) -> Keymap {
+        let mut keymap = self.default.clone();
+        for &(ref name, ref entries) in &self.roms {
+            if name == rom_name {
+                for entry in entries {
+                    apply(&mut keymap, entry.clone());
+                }
+            }
+        }
+        keymap
+    }
+}
+
+/// The lines binding each keypad key and host key in the current section
+struct Bindings {
+    keys: Vec<Option<usize>>,
+    hosts: Vec<(String, Key, usize)>,
+}
+
+impl Bindings {
+    fn new() -> Self {
+        Bindings {
+            keys: vec![None; NUM_KEYS],
+            hosts: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, key: Key, hosts: &[String], line: usize) -> Result<(), String> {
+        let code = u8::from(key);
+        if let Some(first) = self.keys[code as usize] {
+            return Err(format!("Key {:X} is already bound on line {}", code, first));
+        }
+        self.keys[code as usize] = Some(line);
+        for host in hosts {
+            if let Some(&(_, other, first)) = self.hosts.iter().find(|entry| entry.0 == *host) {
+                return Err(format!("\"{}\" is already bound to key {:X} on line {}",
+                                   host,
+                                   u8::from(other),
+                                   first));
+            }
+            self.hosts.push((host.clone(), key, line));
+        }
+        Ok(())
+    }
+}
+
+fn apply(keymap: &mut Keymap, entry: Entry) {
+    match entry {
+        Entry::Preset(preset) => *keymap = preset,
+        Entry::Bind(key, hosts) => keymap.bind(key, hosts),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parses `[rom."<file name>"]`
+fn parse_section(line: &str) -> Result<String, String> {
+    if !line.ends_with(']') {
+        return Err(format!("Unterminated section header {}", line));
+    }
+    let inner = line[1..line.len() - 1].trim();
+    if !inner.starts_with("rom.") {
+        return Err(format!("Unknown section [{}], expected [rom.\"<file name>\"]", inner));
+    }
+    let name = inner["rom.".len()..].trim();
+    if name.len() >= 2 && name.starts_with('"') && name.ends_with('"') {
+        Ok(name[1..name.len() - 1].into())
+    } else if !name.is_empty() && !name.contains('"') {
+        Ok(name.into())
+    } else {
+        Err(format!("Invalid ROM name {}", name))
+    }
+}
+
+fn parse_entry(line: &str) -> Result<Entry, String> {
+    let mut parts = line.splitn(2, '=');
+    let name = parts.next().unwrap_or("").trim();
+    let value = match parts.next() {
+        Some(value) => value.trim(),
+        None => return Err(format!("Expected <key> = <value>, got {}", line)),
+    };
+
+    if name == "preset" {
+        let preset = parse_string(value)?;
+        return Keymap::preset(&preset).map(Entry::Preset);
+    }
+
+    let key = if name.len() == 1 {
+        u8::from_str_radix(name, 16).ok()
+    } else {
+        None
+    };
+    let key = match key {
+        Some(code) => key_from_index(code as usize),
+        None => {
+            return Err(format!("Invalid keypad key \"{}\", expected a hex digit 0-F or preset",
+                               name))
+        }
+    };
+
+    let hosts = if value.starts_with('[') {
+        if !value.ends_with(']') {
+            return Err(format!("Unterminated list {}", value));
+        }
+        let inner = value[1..value.len() - 1].trim();
+        let mut hosts = Vec::new();
+        if !inner.is_empty() {
+            for item in inner.split(',') {
+                let item = item.trim();
+                // Allow a trailing comma
+                if !item.is_empty() {
+                    hosts.push(parse_string(item)?);
+                }
+            }
+        }
+        hosts
+    } else {
+        vec![parse_string(value)?]
+    };
+    Ok(Entry::Bind(key, hosts))
+}
+
+fn parse_string(value: &str) -> Result<String, String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let inner = &value[1..value.len() - 1];
+        if inner.contains('"') {
+            Err(format!("Invalid string {}", value))
+        } else if inner.is_empty() {
+            Err("Empty key name".into())
+        } else {
+            Ok(inner.into())
+        }
+    } else {
+        Err(format!("Expected a quoted string, got {}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| (*name).into()).collect()
+    }
+
+    fn error(text: &str) -> String {
+        KeymapConfig::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn presets_follow_the_keypad_layout() {
+        let standard = Keymap::preset("standard").unwrap();
+        assert_eq!(standard, Keymap::default());
+        assert_eq!(standard.bindings(Key::Key1), &hosts(&["Key1"])[..]);
+        assert_eq!(standard.bindings(Key::KeyC), &hosts(&["Key4"])[..]);
+        assert_eq!(standard.bindings(Key::Key0), &hosts(&["X"])[..]);
+        assert_eq!(standard.bindings(Key::KeyF), &hosts(&["V"])[..]);
+        let numpad = Keymap::preset("numpad").unwrap();
+        assert_eq!(numpad.bindings(Key::Key7), &hosts(&["NumPad7"])[..]);
+        assert_eq!(numpad.bindings(Key::KeyA), &hosts(&["NumPadSlash"])[..]);
+        assert_eq!(Keymap::preset("dvorak").unwrap_err(),
+                   "Unknown keymap preset \"dvorak\" (expected one of standard, numpad)");
+    }
+
+    #[test]
+    fn files_bind_keys_on_top_of_a_preset() {
+        let config = KeymapConfig::parse("# Laptop friendly
+                                          preset = \"numpad\"
+                                          5 = [\"W\", \"Up\",]   # trailing comma
+                                          a = \"Space\"
+                                          F = []")
+            .unwrap();
+        let keymap = config.for_rom("any.ch8");
+        assert_eq!(keymap.bindings(Key::Key5), &hosts(&["W", "Up"])[..]);
+        assert_eq!(keymap.bindings(Key::KeyA), &hosts(&["Space"])[..]);
+        assert!(keymap.bindings(Key::KeyF).is_empty());
+        assert_eq!(keymap.bindings(Key::Key4), &hosts(&["NumPad4"])[..]);
+    }
+
+    #[test]
+    fn rom_sections_override_the_defaults() {
+        let config = KeymapConfig::parse("5 = \"W\"
+                                          [rom.\"pong.ch8\"]
+                                          1 = \"Up\"
+                                          5 = \"Space\"
+                                          [rom.tetris.ch8]
+                                          preset = \"numpad\"")
+            .unwrap();
+        let pong = config.for_rom("pong.ch8");
+        assert_eq!(pong.bindings(Key::Key1), &hosts(&["Up"])[..]);
+        assert_eq!(pong.bindings(Key::Key5), &hosts(&["Space"])[..]);
+        assert_eq!(pong.bindings(Key::Key2), &hosts(&["Key2"])[..]);
+        assert_eq!(config.for_rom("tetris.ch8"), Keymap::numpad());
+        let other = config.for_rom("brix.ch8");
+        assert_eq!(other.bindings(Key::Key5), &hosts(&["W"])[..]);
+        assert_eq!(other.bindings(Key::Key1), &hosts(&["Key1"])[..]);
+    }
+
+    #[test]
+    fn bad_keys_are_reported_with_their_line() {
+        assert_eq!(error("\n G = \"Q\""),
+                   "line 2: Invalid keypad key \"G\", expected a hex digit 0-F or preset");
+        assert_eq!(error("10 = \"Q\""),
+                   "line 1: Invalid keypad key \"10\", expected a hex digit 0-F or preset");
+        assert_eq!(error("1 = Q"), "line 1: Expected a quoted string, got Q");
+        assert_eq!(error("1 = \"\""), "line 1: Empty key name");
+        assert_eq!(error("1 = [\"Q\""), "line 1: Unterminated list [\"Q\"");
+        assert_eq!(error("1"), "line 1: Expected <key> = <value>, got 1");
+        assert_eq!(error("preset = \"dvorak\""),
+                   "line 1: Unknown keymap preset \"dvorak\" (expected one of standard, numpad)");
+        assert_eq!(error("[rom.\"pong.ch8\""),
+                   "line 1: Unterminated section header [rom.\"pong.ch8\"");
+        assert_eq!(error("[keys]"),
+                   "line 1: Unknown section [keys], expected [rom.\"<file name>\"]");
+    }
+
+    #[test]
+    fn duplicate_bindings_are_reported() {
+        assert_eq!(error("5 = \"W\"\n\n5 = \"Up\""),
+                   "line 3: Key 5 is already bound on line 1");
+        assert_eq!(error("5 = \"W\"\n6 = [\"E\", \"W\"]"),
+                   "line 2: \"W\" is already bound to key 5 on line 1");
+        // Each section starts afresh, overriding the defaults
+        assert!(KeymapConfig::parse("5 = \"W\"\n[rom.pong.ch8]\n5 = \"W\"\n6 = \"Up\"").is_ok());
+    }
+}
@@ -0,0 +1,44 @@
+extern crate rand;
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+pub mod assembler;
+pub mod chip8;
+pub mod clock;
+pub mod debugger;
+pub mod disasm;
+pub mod emulator;
+pub mod fault;
+pub mod headless;
+pub mod input;
+pub mod instruction;
+pub mod keymap;
+pub mod movie;
+pub mod octo;
+pub mod peripherals;
+pub mod program;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod screenshot;
+pub mod sound;
+pub mod timer;
+pub mod trace;
+pub mod video_engine;
+
+pub use chip8::Chip8;
+pub use clock::ClockConfig;
+pub use emulator::{Emulator, Frontend, Hotkey};
+pub use fault::{Fault, StepOutcome};
+pub use headless::HeadlessFrontend;
+pub use input::InputSource;
+pub use instruction::Instruction;
+pub use keymap::{Keymap, KeymapConfig};
+pub use movie::Movie;
+pub use peripherals::{Key, Keypad, Peripherals};
+pub use program::Program;
+pub use quirks::Quirks;
+pub use sound::{AudioSink, NullSink, WavSink};
+pub use timer::Timers;
+pub use video_engine::VideoEngine;
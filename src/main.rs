@@ -1,36 +1,254 @@
-#![feature(try_from)]
+extern crate chip8emu_rs;
 extern crate minifb;
-extern crate rand;
 
 use std::env;
+use std::process;
 
-mod emulator;
-mod instruction;
-mod chip8;
-mod video_engine;
-mod debugger;
-mod peripherals;
+mod window;
 
-use emulator::Emulator;
+use chip8emu_rs::{ClockConfig, Emulator, Keymap, KeymapConfig, Movie, Quirks, WavSink};
+use chip8emu_rs::{clock, keymap, octo, quirks, rewind, trace};
+#[cfg(target_os = "linux")]
+use chip8emu_rs::input::evdev::EvdevDevice;
+#[cfg(target_os = "linux")]
+use chip8emu_rs::input::gamepad::{Gamepad, GamepadProfile};
+use chip8emu_rs::InputSource;
+use window::WindowFrontend;
 
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
+
+struct Options {
+    rom_path: String,
+    wav_path: Option<String>,
+    clock: ClockConfig,
+    quirks: Quirks,
+    keymap_path: Option<String>,
+    keys_preset: Option<String>,
+    gamepad_path: Option<String>,
+    record_path: Option<String>,
+    replay_path: Option<String>,
+    rewind_seconds: Option<u32>,
+    trace_length: Option<usize>,
+}
 
 fn main() {
     println!("RUST Chip8 Emulator");
 
-    let rom_path = env::args().nth(1).expect("Please provide a path to a Chip8 ROM");
-    let rom = load_rom(&rom_path);
-    let mut emulator = Emulator::new(&rom);
+    let options = parse_args();
+    let rom = load_rom(&options.rom_path);
+    let keymap = load_keymap(&options);
+    let frontend = WindowFrontend::new(&keymap).unwrap_or_else(|e| {
+        println!("Invalid key bindings: {}", e);
+        process::exit(1)
+    });
+    let mut emulator = Emulator::new(&rom, frontend);
+    emulator.set_clock(options.clock);
+    emulator.set_quirks(options.quirks);
+    emulator.set_state_path(&format!("{}.state", options.rom_path));
+    if let Some(seconds) = options.rewind_seconds {
+        emulator.set_rewind_frames(seconds as usize * clock::FRAME_RATE as usize);
+    }
+    if let Some(length) = options.trace_length {
+        emulator.set_trace_length(length);
+    }
+    if let Some(ref wav_path) = options.wav_path {
+        let sink = WavSink::create(wav_path)
+            .expect(&format!("Cannot create WAV file {}", wav_path));
+        emulator.set_audio_sink(Box::new(sink));
+    }
+    if let Some(ref gamepad_path) = options.gamepad_path {
+        emulator.add_input_source(open_gamepad(gamepad_path, &keymap));
+    }
+    if let Some(ref replay_path) = options.replay_path {
+        let movie = Movie::load_from_file(replay_path).unwrap_or_else(|e| {
+            println!("Cannot replay {}: {}", replay_path, e);
+            process::exit(1)
+        });
+        emulator.start_replay(movie).unwrap_or_else(|e| {
+            println!("Cannot replay {}: {}", replay_path, e);
+            process::exit(1)
+        });
+    }
+    if options.record_path.is_some() {
+        emulator.start_recording();
+    }
     emulator.run();
 
+    if let Some(ref record_path) = options.record_path {
+        let movie = emulator.take_recording().expect("Recording was started");
+        match movie.save_to_file(record_path) {
+            Ok(()) => println!("Movie of {} frames saved to {}", movie.frames(), record_path),
+            Err(e) => println!("Could not save movie to {}: {}", record_path, e),
+        }
+    }
+
+}
+
+fn parse_args() -> Options {
+    let mut rom_path = None;
+    let mut wav_path = None;
+    let mut clock = ClockConfig::default();
+    let mut quirks = Quirks::default();
+    let mut keymap_path = None;
+    let mut keys_preset = None;
+    let mut gamepad_path = None;
+    let mut record_path = None;
+    let mut replay_path = None;
+    let mut rewind_seconds = None;
+    let mut trace_length = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wav" => wav_path = Some(option_value(&mut args, "--wav")),
+            "--hz" => {
+                let throttle = clock.throttle;
+                clock = ClockConfig::from_hz(numeric_option_value(&mut args, "--hz"));
+                clock.throttle = throttle;
+            }
+            "--ipf" => {
+                let ipf = numeric_option_value(&mut args, "--ipf");
+                clock.instructions_per_frame = if ipf > 0 { ipf as usize } else { 1 };
+            }
+            "--unthrottled" => clock.throttle = false,
+            "--quirks" => {
+                let preset = option_value(&mut args, "--quirks");
+                quirks = Quirks::from_name(&preset).unwrap_or_else(|e| {
+                    println!("{}", e);
+                    usage()
+                });
+            }
+            "--keymap" => keymap_path = Some(option_value(&mut args, "--keymap")),
+            "--keys" => keys_preset = Some(option_value(&mut args, "--keys")),
+            "--gamepad" => gamepad_path = Some(option_value(&mut args, "--gamepad")),
+            "--record" => record_path = Some(option_value(&mut args, "--record")),
+            "--replay" => replay_path = Some(option_value(&mut args, "--replay")),
+            "--rewind" => rewind_seconds = Some(numeric_option_value(&mut args, "--rewind")),
+            "--trace" => {
+                trace_length = Some(numeric_option_value(&mut args, "--trace") as usize)
+            }
+            "--help" | "-h" => usage(),
+            _ if arg.starts_with("--") => {
+                println!("Unknown option {}", arg);
+                usage();
+            }
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    Options {
+        rom_path: rom_path.expect("Please provide a path to a Chip8 ROM"),
+        wav_path: wav_path,
+        clock: clock,
+        quirks: quirks,
+        keymap_path: keymap_path,
+        keys_preset: keys_preset,
+        gamepad_path: gamepad_path,
+        record_path: record_path,
+        replay_path: replay_path,
+        rewind_seconds: rewind_seconds,
+        trace_length: trace_length,
+    }
+}
+
+/// `--keys` wins over the keymap file, which wins over the standard layout
+fn load_keymap(options: &Options) -> Keymap {
+    if let Some(ref preset) = options.keys_preset {
+        return Keymap::preset(preset).unwrap_or_else(|e| {
+            println!("{}", e);
+            usage()
+        });
+    }
+
+    match options.keymap_path {
+        Some(ref path) => {
+            let mut text = String::new();
+            File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut text))
+                .expect(&format!("Cannot read keymap file {}", path));
+            let config = KeymapConfig::parse(&text).unwrap_or_else(|e| {
+                println!("Invalid keymap file {}: {}", path, e);
+                process::exit(1)
+            });
+            let rom_name = Path::new(&options.rom_path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            config.for_rom(&rom_name)
+        }
+        None => Keymap::default(),
+    }
 }
 
+/// Opens an evdev gamepad, `auto` picking the first joystick found
+#[cfg(target_os = "linux")]
+fn open_gamepad(path: &str, keymap: &Keymap) -> Box<InputSource> {
+    let profile = GamepadProfile::from_keymap(keymap).unwrap_or_else(|e| {
+        println!("Invalid key bindings: {}", e);
+        process::exit(1)
+    });
+    let path = if path == "auto" {
+        EvdevDevice::find().unwrap_or_else(|| {
+            println!("No gamepad found");
+            process::exit(1)
+        })
+    } else {
+        path.into()
+    };
+    let device = EvdevDevice::open(&path).expect(&format!("Cannot open gamepad {}", path));
+    println!("Using gamepad {}", path);
+    Box::new(Gamepad::new(device, profile))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_gamepad(_path: &str, _keymap: &Keymap) -> Box<InputSource> {
+    println!("Gamepads are only supported on Linux");
+    process::exit(1)
+}
+
+fn option_value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> String {
+    args.next().expect(&format!("Missing value for {}", name))
+}
+
+fn numeric_option_value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> u32 {
+    let value = option_value(args, name);
+    value.parse().expect(&format!("Invalid value {} for {}", value, name))
+}
+
+fn usage() -> ! {
+    println!("Usage: chip8emu-rs [options] <rom>");
+    println!();
+    println!("ROMs ending in .8o are compiled from Octo source first.");
+    println!();
+    println!("Options:");
+    println!("  --wav <file>      Record the buzzer to a WAV file");
+    println!("  --hz <n>          Run roughly n instructions per second");
+    println!("  --ipf <n>         Run n instructions per 60 Hz frame (default {})",
+             ClockConfig::default().instructions_per_frame);
+    println!("  --unthrottled     Run frames as fast as possible");
+    println!("  --quirks <preset> Interpreter quirks: {} (default vip)",
+             quirks::PRESET_NAMES.join(", "));
+    println!("  --keymap <file>   Load key bindings from a keymap file");
+    println!("  --keys <preset>   Key bindings preset: {} (default standard)",
+             keymap::PRESET_NAMES.join(", "));
+    println!("  --gamepad <dev>   Read a gamepad from an evdev node, or auto");
+    println!("  --record <file>   Record the keypad into a movie file");
+    println!("  --replay <file>   Replay a movie file, ignoring live input");
+    println!("  --rewind <s>      Seconds Backspace can rewind, 0 to disable (default {})",
+             rewind::DEFAULT_REWIND_FRAMES / clock::FRAME_RATE as usize);
+    println!("  --trace <n>       Trace the last n instructions from the start, instead of");
+    println!("                    the last {} once the debugger is entered",
+             trace::DEFAULT_TRACE_LENGTH);
+    process::exit(1);
+}
 
 fn load_rom(path: &str) -> Vec<u8> {
-    let mut f: File = File::open(path).expect(&format!("Cannot open file {}", path));
-    let mut buf: Vec<u8> = Vec::new();
-    let read = f.read_to_end(&mut buf).expect(&format!("Cannot read from file {}", path));
-    println!("Loaded {} bytes", read);
-    buf
+    let rom = octo::load_rom(path).unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(1)
+    });
+    println!("Loaded {} bytes", rom.len());
+    rom
 }
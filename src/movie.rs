@@ -0,0 +1,338 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use chip8::Chip8;
+use input::{InputSource, KeyStates};
+use peripherals::NUM_KEYS;
+use quirks::Quirks;
+use savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Movie files start with this magic number and the format version. The
+/// header then holds everything that makes a run reproducible: the random
+/// seed, the ROM hash, the quirks and the instructions per frame.
+pub const MAGIC: &'static [u8; 4] = b"C8MV";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u64, found: u64 },
+    Corrupt(String),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Io(ref e) => write!(f, "I/O error: {}", e),
+            MovieError::BadMagic => write!(f, "Not a movie file"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported movie version {} (expected {})", version, VERSION)
+            }
+            MovieError::RomMismatch { expected, found } => {
+                write!(f,
+                       "Movie was recorded with another ROM (hash {:016x}, loaded ROM is {:016x})",
+                       found,
+                       expected)
+            }
+            MovieError::Corrupt(ref msg) => write!(f, "Corrupt movie: {}", msg),
+        }
+    }
+}
+
+impl error::Error for MovieError {
+    fn description(&self) -> &str {
+        "invalid movie"
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+impl From<SaveStateError> for MovieError {
+    fn from(e: SaveStateError) -> Self {
+        match e {
+            SaveStateError::Io(e) => MovieError::Io(e),
+            SaveStateError::Corrupt(msg) => MovieError::Corrupt(msg),
+            other => MovieError::Corrupt(other.to_string()),
+        }
+    }
+}
+
+/// The keypad state from a given frame on, bit N being key N
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyChange {
+    pub frame: u64,
+    pub keys: u16,
+}
+
+/// A recorded session: the header needed to restart the machine the same
+/// way, and every change of the keypad state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    seed: u64,
+    rom_hash: u64,
+    quirks: Quirks,
+    instructions_per_frame: u32,
+    frames: u64,
+    changes: Vec<KeyChange>,
+}
+
+impl Movie {
+    /// Starts an empty recording for a machine at power on
+    pub fn new(chip8: &Chip8, instructions_per_frame: usize) -> Self {
+        Movie {
+            seed: chip8.seed(),
+            rom_hash: chip8.rom_hash(),
+            quirks: chip8.quirks(),
+            instructions_per_frame: instructions_per_frame as u32,
+            frames: 0,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Records the keypad state used by `frame`, frames being counted from 0
+    pub fn record(&mut self, frame: u64, keys: u16) {
+        let last_keys = self.changes.last().map(|change| change.keys).unwrap_or(0);
+        if keys != last_keys {
+            self.changes.push(KeyChange {
+                frame: frame,
+                keys: keys,
+            });
+        }
+        if frame >= self.frames {
+            self.frames = frame + 1;
+        }
+    }
+
+    /// Forgets everything from `frame` on, for when the recorded session is
+    /// rewound
+    pub fn truncate(&mut self, frame: u64) {
+        self.changes.retain(|change| change.frame < frame);
+        if self.frames > frame {
+            self.frames = frame;
+        }
+    }
+
+    /// Checks the movie was recorded with the ROM loaded in `chip8`
+    pub fn check_rom(&self, chip8: &Chip8) -> Result<(), MovieError> {
+        if self.rom_hash != chip8.rom_hash() {
+            return Err(MovieError::RomMismatch {
+                expected: chip8.rom_hash(),
+                found: self.rom_hash,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn instructions_per_frame(&self) -> usize {
+        self.instructions_per_frame as usize
+    }
+
+    /// Number of frames recorded
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn changes(&self) -> &[KeyChange] {
+        &self.changes
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.put_bytes(MAGIC);
+        writer.put_u16(VERSION);
+        writer.put_u64(self.seed);
+        writer.put_u64(self.rom_hash);
+        self.quirks.save_state(&mut writer);
+        writer.put_u32(self.instructions_per_frame);
+        writer.put_u64(self.frames);
+        writer.put_u32(self.changes.len() as u32);
+        for change in &self.changes {
+            writer.put_u64(change.frame);
+            writer.put_u16(change.keys);
+        }
+        writer.into_inner()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, MovieError> {
+        let mut reader = StateReader::new(data);
+        if reader.get_bytes(MAGIC.len())? != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = reader.get_u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let mut movie = Movie {
+            seed: reader.get_u64()?,
+            rom_hash: reader.get_u64()?,
+            quirks: Quirks::default(),
+            instructions_per_frame: 0,
+            frames: 0,
+            changes: Vec::new(),
+        };
+        movie.quirks.load_state(&mut reader)?;
+        movie.instructions_per_frame = reader.get_u32()?;
+        movie.frames = reader.get_u64()?;
+        let num_changes = reader.get_u32()?;
+        for _ in 0..num_changes {
+            let change = KeyChange {
+                frame: reader.get_u64()?,
+                keys: reader.get_u16()?,
+            };
+            let in_order = movie.changes.last().map_or(true, |last| last.frame < change.frame);
+            if !in_order || change.frame >= movie.frames {
+                return Err(MovieError::Corrupt(format!("key change at frame {} out of order",
+                                                       change.frame)));
+            }
+            movie.changes.push(change);
+        }
+        if !reader.is_empty() {
+            return Err(MovieError::Corrupt("trailing data".into()));
+        }
+        Ok(movie)
+    }
+
+    pub fn save_to_file(&self, path: &str) -> Result<(), MovieError> {
+        let mut f = File::create(path)?;
+        f.write_all(&self.encode())?;
+        Ok(())
+    }
+
+    pub fn load_from_file(path: &str) -> Result<Self, MovieError> {
+        let mut f = File::open(path)?;
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        Movie::decode(&data)
+    }
+}
+
+/// Plays the keypad states of a movie back, one frame per poll
+pub struct MoviePlayer {
+    movie: Movie,
+    next_change: usize,
+    frame: u64,
+    keys: u16,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        MoviePlayer {
+            movie: movie,
+            next_change: 0,
+            frame: 0,
+            keys: 0,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// True once every recorded frame has been played
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames
+    }
+}
+
+impl InputSource for MoviePlayer {
+    fn poll(&mut self, keys: &mut KeyStates) {
+        let changes = &self.movie.changes;
+        while self.next_change < changes.len() && changes[self.next_change].frame <= self.frame {
+            self.keys = changes[self.next_change].keys;
+            self.next_change += 1;
+        }
+        self.frame += 1;
+        for idx in 0..NUM_KEYS {
+            if self.keys & (1 << idx) != 0 {
+                keys[idx] = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::Emulator;
+    use headless::{HeadlessFrontend, KeyScript};
+    use peripherals::Key;
+
+    /// Draws a random digit at a random place, clearing the screen first
+    /// while key 5 is held
+    const ROM: [u8; 16] = [0xC0, 0x3F, 0xC1, 0x1F, 0x62, 0x05, 0xE2, 0xA1, 0x00, 0xE0, 0xF0,
+                           0x29, 0xD0, 0x15, 0x12, 0x00];
+
+    fn emulator(script: KeyScript) -> Emulator<HeadlessFrontend> {
+        Emulator::new(&ROM, HeadlessFrontend::new(script))
+    }
+
+    fn record(frames: usize) -> (Movie, Vec<u32>) {
+        let mut script = KeyScript::new();
+        script.press(10, Key::Key5, 3);
+        script.press(40, Key::Key5, 1);
+        let mut emulator = emulator(script);
+        emulator.start_recording();
+        for _ in 0..frames {
+            emulator.run_frame().unwrap();
+        }
+        let vram = emulator.peripherals().video_engine.vram().clone();
+        (emulator.take_recording().unwrap(), vram)
+    }
+
+    #[test]
+    fn replay_draws_the_same_frames() {
+        let (movie, vram) = record(100);
+        assert_eq!(movie.frames(), 100);
+        assert_eq!(movie.changes().len(), 4);
+
+        let mut replay = emulator(KeyScript::new());
+        replay.start_replay(Movie::decode(&movie.encode()).unwrap()).unwrap();
+        for _ in 0..100 {
+            replay.run_frame().unwrap();
+        }
+        assert!(replay.is_replay_finished());
+        assert_eq!(replay.chip8().seed(), movie.seed());
+        assert_eq!(replay.peripherals().video_engine.vram(), &vram);
+    }
+
+    #[test]
+    fn replay_needs_the_recorded_rom() {
+        let (movie, _) = record(1);
+        let other = Chip8::new(&ROM[..14], Quirks::default());
+        assert!(movie.check_rom(&other).is_err());
+        assert!(movie.check_rom(&Chip8::new(&ROM, Quirks::default())).is_ok());
+    }
+
+    #[test]
+    fn truncate_drops_the_rewound_frames() {
+        let (mut movie, _) = record(50);
+        movie.truncate(12);
+        assert_eq!(movie.frames(), 12);
+        assert_eq!(movie.changes(),
+                   &[KeyChange {
+                         frame: 10,
+                         keys: 1 << 5,
+                     }]);
+    }
+}
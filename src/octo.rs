@@ -0,0 +1,1032 @@
+use std::collections::HashMap;
+use std::f64::consts;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use assembler::AssemblerError;
+use disasm::ROM_START;
+use instruction::Instruction;
+
+/// Deepest nesting of macros invoking other macros
+const MAX_MACRO_DEPTH: usize = 64;
+/// End of XO-CHIP's 64 KiB address space
+const MEMORY_END: usize = 0x10000;
+/// Register clobbered by the `<`, `>`, `<=` and `>=` comparisons
+const COMPARE_TEMP: usize = 0xF;
+
+/// Operators of `:calc` expressions taking two operands
+const BINARY_OPERATORS: [&'static str; 19] = ["+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>",
+                                               "pow", "min", "max", "<", "<=", "==", "!=", ">=",
+                                               ">"];
+
+/// Compiles Octo source, as written for the Octo IDE, into a ROM:
+///
+/// ```text
+/// : main
+///   v0 := 0
+///   loop
+///     v0 += 1
+///     if v0 == 10 then v0 := 0
+///   again
+/// ```
+///
+/// Execution starts at the `main` label. Macros, `:const`, `:calc` and
+/// `:alias` are supported, `:org`, `:next`, `:unpack` and strings are not.
+pub fn compile(name: &str, source: &str) -> Result<Vec<u8>, AssemblerError> {
+    Compiler::new(name, source).compile()
+}
+
+pub fn compile_file(path: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut source = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+        return Err(AssemblerError {
+            file: path.into(),
+            line: 0,
+            message: format!("Cannot read {}: {}", path, e),
+        });
+    }
+    compile(path, &source)
+}
+
+/// Reads a ROM, compiling it first if it is Octo source ending in `.8o`
+pub fn load_rom(path: &str) -> Result<Vec<u8>, String> {
+    if Path::new(path).extension().map_or(false, |ext| ext == "8o") {
+        return compile_file(path).map_err(|e| e.to_string());
+    }
+    let mut rom = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut rom))
+        .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    Ok(rom)
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    /// How many macro expansions produced this token
+    depth: usize,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FixupKind {
+    /// The 12 bit address of an instruction
+    Address,
+    /// The word after `i := long`
+    Long,
+}
+
+/// A reference to a label defined further down
+struct Fixup {
+    offset: usize,
+    kind: FixupKind,
+    label: String,
+    line: usize,
+}
+
+/// An open `loop`, with the offsets of the jumps its `while`s leave through
+struct Loop {
+    start: usize,
+    exits: Vec<usize>,
+    line: usize,
+}
+
+struct Compiler {
+    file: String,
+    /// Tokens left to compile, last one first so macros can push their body
+    tokens: Vec<Token>,
+    /// Line of the last token read, where errors are reported
+    line: usize,
+    rom: Vec<u8>,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, usize>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    /// Jumps of the open `if ... begin` and `else` blocks, with their line
+    branches: Vec<(usize, usize)>,
+    loops: Vec<Loop>,
+    /// Whether the ROM starts with a jump to `main`
+    jump_to_main: bool,
+}
+
+impl Compiler {
+    fn new(name: &str, source: &str) -> Self {
+        let mut tokens = Vec::new();
+        for (idx, line) in source.lines().enumerate() {
+            let code = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            for word in code.split_whitespace() {
+                tokens.push(Token {
+                    text: word.into(),
+                    line: idx + 1,
+                    depth: 0,
+                });
+            }
+        }
+        tokens.reverse();
+        Compiler {
+            file: name.into(),
+            tokens: tokens,
+            line: 0,
+            // Room for the jump to main, dropped if main comes first
+            rom: vec![0, 0],
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            branches: Vec::new(),
+            loops: Vec::new(),
+            jump_to_main: true,
+        }
+    }
+
+    fn error(&self, line: usize, message: String) -> AssemblerError {
+        AssemblerError {
+            file: self.file.clone(),
+            line: line,
+            message: message,
+        }
+    }
+
+    fn compile(mut self) -> Result<Vec<u8>, AssemblerError> {
+        while !self.tokens.is_empty() {
+            if let Err(e) = self.statement() {
+                return Err(self.error(self.line, e));
+            }
+        }
+        if let Some(&(_, line)) = self.branches.last() {
+            return Err(self.error(line, "Missing end for this begin".into()));
+        }
+        if let Some(open) = self.loops.last() {
+            return Err(self.error(open.line, "Missing again for this loop".into()));
+        }
+        if self.here() > MEMORY_END {
+            return Err(self.error(0, "Program does not fit in memory".into()));
+        }
+        for fixup in &self.fixups {
+            let addr = match self.labels.get(&fixup.label) {
+                Some(&addr) => addr,
+                None => {
+                    let message = format!("Undefined label {}", fixup.label);
+                    return Err(self.error(fixup.line, message));
+                }
+            };
+            if fixup.kind == FixupKind::Address && addr > 0xFFF {
+                let message = format!("Label {} at 0x{:x} is out of reach", fixup.label, addr);
+                return Err(self.error(fixup.line, message));
+            }
+            let high = (addr >> 8) as u8;
+            match fixup.kind {
+                FixupKind::Address => self.rom[fixup.offset] |= high & 0xF,
+                FixupKind::Long => self.rom[fixup.offset] = high,
+            }
+            self.rom[fixup.offset + 1] = addr as u8;
+        }
+        if self.jump_to_main {
+            let main = self.labels.get("main").cloned();
+            match main {
+                Some(main) => self.patch_jump(0, main).map_err(|e| self.error(0, e))?,
+                None => return Err(self.error(0, "Missing a main label".into())),
+            }
+        }
+        Ok(self.rom)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.pop().ok_or("Unexpected end of file")?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn peek(&self, ahead: usize) -> Option<&str> {
+        if ahead < self.tokens.len() {
+            Some(&self.tokens[self.tokens.len() - 1 - ahead].text)
+        } else {
+            None
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token.text == text {
+            Ok(())
+        } else {
+            Err(format!("Expected {}, found {}", text, token.text))
+        }
+    }
+
+    fn here(&self) -> usize {
+        ROM_START + self.rom.len()
+    }
+
+    fn push(&mut self, instruction: Instruction) {
+        let opcode = u16::from(instruction);
+        self.rom.push((opcode >> 8) as u8);
+        self.rom.push(opcode as u8);
+    }
+
+    /// Pushes a jump whose target `patch_jump` fills in later
+    fn push_jump(&mut self) -> usize {
+        let offset = self.rom.len();
+        self.push(Instruction::Jmp { addr: 0 });
+        offset
+    }
+
+    fn patch_jump(&mut self, offset: usize, addr: usize) -> Result<(), String> {
+        if addr > 0xFFF {
+            return Err(format!("Jump target 0x{:x} is out of reach", addr));
+        }
+        let opcode = u16::from(Instruction::Jmp { addr: addr });
+        self.rom[offset] = (opcode >> 8) as u8;
+        self.rom[offset + 1] = opcode as u8;
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        let instruction = match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                return self.define_label(name);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number()?;
+                self.constants.insert(name, value);
+                return Ok(());
+            }
+            ":calc" => {
+                let name = self.next()?.text;
+                // Unlike :const, :calc can update its own constants
+                if !self.constants.contains_key(&name) {
+                    self.check_name(&name)?;
+                }
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+                return Ok(());
+            }
+            ":alias" => {
+                let name = self.next()?.text;
+                // Aliases are often moved from register to register
+                if !self.aliases.contains_key(&name) {
+                    self.check_name(&name)?;
+                }
+                let vr = self.register()?;
+                self.aliases.insert(name, vr);
+                return Ok(());
+            }
+            ":macro" => return self.define_macro(),
+            ":byte" => {
+                let value = if self.peek(0) == Some("{") {
+                    self.calc()?
+                } else {
+                    self.number()?
+                };
+                let byte = to_byte(value)?;
+                self.rom.push(byte);
+                return Ok(());
+            }
+            ":call" => Instruction::Jsr { addr: self.address(FixupKind::Address)? },
+            text if text.starts_with(':') => return Err(format!("Unsupported directive {}", text)),
+            ";" | "return" => Instruction::Ret,
+            "clear" => Instruction::Cls,
+            "hires" => Instruction::High,
+            "lores" => Instruction::Low,
+            "scroll-left" => Instruction::Scl,
+            "scroll-right" => Instruction::Scr,
+            "scroll-down" => Instruction::Scd { n: self.nibble()? },
+            "scroll-up" => Instruction::Scu { n: self.nibble()? },
+            "exit" => Instruction::Exit,
+            "plane" => Instruction::Plane { n: self.nibble()? },
+            "audio" => Instruction::Audio,
+            "bcd" => Instruction::Bcd { vr: self.register()? },
+            "save" | "load" => {
+                let x = self.register()?;
+                let range = self.peek(0) == Some("-");
+                match (token.text.as_str(), range) {
+                    ("save", false) => Instruction::Str { vr: x },
+                    ("load", false) => Instruction::Ldr { vr: x },
+                    ("save", true) => {
+                        self.next()?;
+                        Instruction::Sreg { vr: x, vy: self.register()? }
+                    }
+                    _ => {
+                        self.next()?;
+                        Instruction::Lreg { vr: x, vy: self.register()? }
+                    }
+                }
+            }
+            "saveflags" => Instruction::Rstr { vr: self.register()? },
+            "loadflags" => Instruction::Rldr { vr: self.register()? },
+            "sprite" => {
+                let rx = self.register()?;
+                let ry = self.register()?;
+                Instruction::Sprite {
+                    rx: rx,
+                    ry: ry,
+                    s: self.nibble()?,
+                }
+            }
+            "jump" => Instruction::Jmp { addr: self.address(FixupKind::Address)? },
+            "jump0" => Instruction::Jmi { addr: self.address(FixupKind::Address)? },
+            "native" => Instruction::Sys { addr: self.address(FixupKind::Address)? },
+            "delay" => {
+                self.expect(":=")?;
+                Instruction::Sdelay { vr: self.register()? }
+            }
+            "buzzer" => {
+                self.expect(":=")?;
+                Instruction::Ssound { vr: self.register()? }
+            }
+            "pitch" => {
+                self.expect(":=")?;
+                Instruction::Pitch { vr: self.register()? }
+            }
+            "i" => return self.i_statement(),
+            "if" => return self.if_statement(),
+            "else" => {
+                let (offset, _) = self.branches.pop().ok_or("else without if ... begin")?;
+                let jump = self.push_jump();
+                let here = self.here();
+                self.patch_jump(offset, here)?;
+                self.branches.push((jump, token.line));
+                return Ok(());
+            }
+            "end" => {
+                let (offset, _) = self.branches.pop().ok_or("end without if ... begin")?;
+                let here = self.here();
+                return self.patch_jump(offset, here);
+            }
+            "loop" => {
+                let start = self.here();
+                self.loops.push(Loop {
+                    start: start,
+                    exits: Vec::new(),
+                    line: token.line,
+                });
+                return Ok(());
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err("while outside of a loop".into());
+                }
+                self.condition(true)?;
+                let jump = self.push_jump();
+                self.loops.last_mut().expect("Checked above").exits.push(jump);
+                return Ok(());
+            }
+            "again" => {
+                let open = self.loops.pop().ok_or("again without loop")?;
+                let jump = self.push_jump();
+                self.patch_jump(jump, open.start)?;
+                let here = self.here();
+                for exit in open.exits {
+                    self.patch_jump(exit, here)?;
+                }
+                return Ok(());
+            }
+            text => {
+                if let Some(x) = self.register_named(text) {
+                    return self.assignment(x);
+                }
+                if self.macros.contains_key(text) {
+                    return self.expand_macro(&token);
+                }
+                if let Some(value) = parse_number(text) {
+                    let byte = to_byte(value)?;
+                    self.rom.push(byte);
+                    return Ok(());
+                }
+                // Any other name calls a subroutine
+                Instruction::Jsr { addr: self.address_of(token.clone(), FixupKind::Address)? }
+            }
+        };
+        self.push(instruction);
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<(), String> {
+        let op = self.next()?.text;
+        let instruction = match op.as_str() {
+            ":=" => {
+                let next = self.peek(0).unwrap_or("").to_string();
+                match next.as_str() {
+                    "hex" => {
+                        self.next()?;
+                        Instruction::Font { vr: self.register()? }
+                    }
+                    "bighex" => {
+                        self.next()?;
+                        Instruction::Xfont { vr: self.register()? }
+                    }
+                    "long" => {
+                        self.next()?;
+                        self.push(Instruction::Lmvi);
+                        let addr = self.address(FixupKind::Long)?;
+                        self.rom.push((addr >> 8) as u8);
+                        self.rom.push(addr as u8);
+                        return Ok(());
+                    }
+                    _ => Instruction::Mvi { k: self.address(FixupKind::Address)? as u16 },
+                }
+            }
+            "+=" => Instruction::Adi { vr: self.register()? },
+            _ => return Err(format!("Expected := or += after i, found {}", op)),
+        };
+        self.push(instruction);
+        Ok(())
+    }
+
+    /// `if <condition> then <statement>` or `if <condition> begin ... end`
+    fn if_statement(&mut self) -> Result<(), String> {
+        let keyword_at = match self.peek(1) {
+            Some("key") | Some("-key") => 2,
+            _ => 3,
+        };
+        let keyword = self.peek(keyword_at).unwrap_or("").to_string();
+        match keyword.as_str() {
+            "then" => {
+                self.condition(false)?;
+                self.next()?;
+            }
+            "begin" => {
+                self.condition(true)?;
+                self.next()?;
+                let line = self.line;
+                let jump = self.push_jump();
+                self.branches.push((jump, line));
+            }
+            _ => return Err("Expected then or begin after the condition of if".into()),
+        }
+        Ok(())
+    }
+
+    /// Compiles a condition into instructions skipping the next one when it
+    /// is false, or when it is true if `negated`
+    fn condition(&mut self, negated: bool) -> Result<(), String> {
+        let x = self.register()?;
+        let op = self.next()?.text;
+        let op = match (negated, op.as_str()) {
+            (false, op) => op,
+            (true, "==") => "!=",
+            (true, "!=") => "==",
+            (true, "key") => "-key",
+            (true, "-key") => "key",
+            (true, "<") => ">=",
+            (true, ">") => "<=",
+            (true, "<=") => ">",
+            (true, ">=") => "<",
+            (true, op) => op,
+        };
+        let instruction = match op {
+            "key" => Instruction::Sknp { vr: x },
+            "-key" => Instruction::Skp { vr: x },
+            "==" => {
+                match self.try_register() {
+                    Some(y) => Instruction::Skner { vr: x, vy: y },
+                    None => Instruction::Skne { vr: x, k: self.byte()? },
+                }
+            }
+            "!=" => {
+                match self.try_register() {
+                    Some(y) => Instruction::Skeqr { vr: x, vy: y },
+                    None => Instruction::Skeq { vr: x, k: self.byte()? },
+                }
+            }
+            "<" | ">" | "<=" | ">=" => {
+                // Subtracting leaves VF at 1 when nothing was borrowed
+                let load = match self.try_register() {
+                    Some(y) => Instruction::Movr { vr: COMPARE_TEMP, vy: y },
+                    None => Instruction::Mov { vr: COMPARE_TEMP, k: self.byte()? },
+                };
+                self.push(load);
+                if op == ">" || op == "<=" {
+                    self.push(Instruction::Subr { vr: COMPARE_TEMP, vy: x });
+                } else {
+                    self.push(Instruction::Subn { vr: COMPARE_TEMP, vy: x });
+                }
+                if op == ">" || op == "<" {
+                    Instruction::Skne { vr: COMPARE_TEMP, k: 0 }
+                } else {
+                    Instruction::Skeq { vr: COMPARE_TEMP, k: 0 }
+                }
+            }
+            _ => return Err(format!("Expected a comparison, found {}", op)),
+        };
+        self.push(instruction);
+        Ok(())
+    }
+
+    fn assignment(&mut self, x: usize) -> Result<(), String> {
+        let op = self.next()?.text;
+        let y = self.try_register();
+        let instruction = match (op.as_str(), y) {
+            (":=", Some(y)) => Instruction::Movr { vr: x, vy: y },
+            (":=", None) => {
+                let next = self.peek(0).unwrap_or("").to_string();
+                match next.as_str() {
+                    "random" => {
+                        self.next()?;
+                        Instruction::Rnd { vr: x, k: self.byte()? }
+                    }
+                    "key" => {
+                        self.next()?;
+                        Instruction::Key { vr: x }
+                    }
+                    "delay" => {
+                        self.next()?;
+                        Instruction::Gdelay { vr: x }
+                    }
+                    _ => Instruction::Mov { vr: x, k: self.byte()? },
+                }
+            }
+            ("+=", Some(y)) => Instruction::Addr { vr: x, vy: y },
+            ("+=", None) => Instruction::Add { vr: x, k: self.byte()? },
+            ("-=", Some(y)) => Instruction::Subr { vr: x, vy: y },
+            ("-=", None) => Instruction::Add { vr: x, k: self.byte()?.wrapping_neg() },
+            ("=-", Some(y)) => Instruction::Subn { vr: x, vy: y },
+            ("|=", Some(y)) => Instruction::Or { vr: x, vy: y },
+            ("&=", Some(y)) => Instruction::And { vr: x, vy: y },
+            ("^=", Some(y)) => Instruction::Xor { vr: x, vy: y },
+            (">>=", Some(y)) => Instruction::Shr { vr: x, vy: y },
+            ("<<=", Some(y)) => Instruction::Shl { vr: x, vy: y },
+            ("=-", None) | ("|=", None) | ("&=", None) | ("^=", None) | (">>=", None) |
+            ("<<=", None) => {
+                let found = self.peek(0).unwrap_or("nothing").to_string();
+                return Err(format!("{} needs a register, found {}", op, found));
+            }
+            _ => return Err(format!("Unknown operator {}", op)),
+        };
+        self.push(instruction);
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: String) -> Result<(), String> {
+        self.check_name(&name)?;
+        // Nothing to jump over when main comes first
+        if name == "main" && self.rom.len() == 2 && self.labels.is_empty() {
+            self.rom.clear();
+            self.jump_to_main = false;
+        }
+        let here = self.here();
+        self.labels.insert(name, here);
+        Ok(())
+    }
+
+    /// `:macro name params... { body }`
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut nesting = 0;
+        loop {
+            let token = self.next()
+                .map_err(|_| format!("Missing }} at the end of macro {}", name))?;
+            match token.text.as_str() {
+                "{" => nesting += 1,
+                "}" if nesting == 0 => break,
+                "}" => nesting -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+        self.macros.insert(name,
+                           Macro {
+                               params: params,
+                               body: body,
+                           });
+        Ok(())
+    }
+
+    /// Replaces a macro invocation by its body, with the arguments substituted
+    fn expand_macro(&mut self, invocation: &Token) -> Result<(), String> {
+        if invocation.depth >= MAX_MACRO_DEPTH {
+            return Err(format!("Macros nested more than {} deep", MAX_MACRO_DEPTH));
+        }
+        let param_count = self.macros[&invocation.text].params.len();
+        let mut args = HashMap::new();
+        for idx in 0..param_count {
+            let arg = self.next()
+                .map_err(|_| format!("Missing arguments for macro {}", invocation.text))?;
+            args.insert(self.macros[&invocation.text].params[idx].clone(), arg.text);
+        }
+        let expansion = &self.macros[&invocation.text].body;
+        for token in expansion.iter().rev() {
+            self.tokens.push(Token {
+                text: args.get(&token.text).unwrap_or(&token.text).clone(),
+                line: token.line,
+                depth: invocation.depth + 1,
+            });
+        }
+        Ok(())
+    }
+
+    /// A name for a label, constant, alias or macro
+    fn name(&mut self) -> Result<String, String> {
+        let name = self.next()?.text;
+        self.check_name(&name)?;
+        Ok(name)
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), String> {
+        if parse_number(name).is_some() {
+            return Err(format!("Expected a name, found {}", name));
+        }
+        if self.register_named(name).is_some() {
+            return Err(format!("{} is a register name", name));
+        }
+        if self.labels.contains_key(name) || self.constants.contains_key(name) ||
+           self.macros.contains_key(name) {
+            return Err(format!("{} is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn register_named(&self, name: &str) -> Option<usize> {
+        if let Some(&vr) = self.aliases.get(name) {
+            return Some(vr);
+        }
+        if name.len() == 2 && (name.starts_with('v') || name.starts_with('V')) {
+            usize::from_str_radix(&name[1..], 16).ok()
+        } else {
+            None
+        }
+    }
+
+    fn register(&mut self) -> Result<usize, String> {
+        let token = self.next()?;
+        self.register_named(&token.text)
+            .ok_or_else(|| format!("Expected a register, found {}", token.text))
+    }
+
+    /// Reads a register if that is what comes next
+    fn try_register(&mut self) -> Option<usize> {
+        let vr = self.peek(0).and_then(|text| self.register_named(text));
+        if vr.is_some() {
+            self.tokens.pop();
+        }
+        vr
+    }
+
+    /// A number, constant or label defined earlier
+    fn number(&mut self) -> Result<f64, String> {
+        let token = self.next()?;
+        self.resolve(&token.text).ok_or_else(|| format!("Undefined name {}", token.text))
+    }
+
+    fn resolve(&self, text: &str) -> Option<f64> {
+        parse_number(text)
+            .or_else(|| self.constants.get(text).cloned())
+            .or_else(|| self.labels.get(text).map(|&addr| addr as f64))
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let value = self.number()?;
+        to_byte(value)
+    }
+
+    fn nibble(&mut self) -> Result<usize, String> {
+        let value = self.number()?.floor();
+        if value < 0.0 || value > 15.0 {
+            return Err(format!("{} does not fit in 4 bits", value));
+        }
+        Ok(value as usize)
+    }
+
+    /// An address, which may be a label defined further down
+    fn address(&mut self, kind: FixupKind) -> Result<usize, String> {
+        let token = self.next()?;
+        self.address_of(token, kind)
+    }
+
+    fn address_of(&mut self, token: Token, kind: FixupKind) -> Result<usize, String> {
+        let max = match kind {
+            FixupKind::Address => 0xFFF,
+            FixupKind::Long => 0xFFFF,
+        };
+        match self.resolve(&token.text) {
+            Some(value) => {
+                let value = value.floor();
+                if value < 0.0 || value > max as f64 {
+                    return Err(format!("Address {} is out of reach", token.text));
+                }
+                Ok(value as usize)
+            }
+            None => {
+                self.check_name(&token.text)?;
+                // Whatever holds the address is pushed next
+                let offset = self.rom.len();
+                self.fixups.push(Fixup {
+                    offset: offset,
+                    kind: kind,
+                    label: token.text,
+                    line: token.line,
+                });
+                Ok(0)
+            }
+        }
+    }
+
+    /// `{ expression }`, evaluated right to left without precedence
+    fn calc(&mut self) -> Result<f64, String> {
+        self.expect("{")?;
+        let value = self.calc_expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expression(&mut self) -> Result<f64, String> {
+        let left = self.calc_term()?;
+        let op = match self.peek(0) {
+            Some(op) if BINARY_OPERATORS.contains(&op) => op.to_string(),
+            _ => return Ok(left),
+        };
+        self.next()?;
+        let right = self.calc_expression()?;
+        Ok(match op.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (left as i64 & right as i64) as f64,
+            "|" => (left as i64 | right as i64) as f64,
+            "^" => (left as i64 ^ right as i64) as f64,
+            "<<" => ((left as i64) << (right as i64 & 63)) as f64,
+            ">>" => ((left as i64) >> (right as i64 & 63)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => flag(left < right),
+            "<=" => flag(left <= right),
+            "==" => flag(left == right),
+            "!=" => flag(left != right),
+            ">=" => flag(left >= right),
+            _ => flag(left > right),
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, String> {
+        let token = self.next()?;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression()?;
+                self.expect(")")?;
+                return Ok(value);
+            }
+            "HERE" => return Ok(self.here() as f64),
+            "PI" => return Ok(consts::PI),
+            "E" => return Ok(consts::E),
+            // Reads a byte of the ROM compiled so far
+            "@" => {
+                let addr = self.calc_term()? as usize;
+                let byte = self.rom.get(addr.wrapping_sub(ROM_START)).cloned();
+                return byte.map(|byte| byte as f64)
+                    .ok_or_else(|| format!("No ROM byte at 0x{:x}", addr));
+            }
+            "-" => Some(neg),
+            "~" => Some(bitwise_not),
+            "!" => Some(logical_not),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        match unary {
+            Some(f) => self.calc_term().map(f),
+            None => {
+                self.resolve(&token.text)
+                    .ok_or_else(|| format!("Undefined name {} in :calc", token.text))
+            }
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = if text.starts_with('-') {
+        (true, &text[1..])
+    } else {
+        (false, text)
+    };
+    let lower = digits.to_lowercase();
+    let parsed = if lower.starts_with("0x") {
+        i64::from_str_radix(&lower[2..], 16)
+    } else if lower.starts_with("0b") {
+        i64::from_str_radix(&lower[2..], 2)
+    } else if lower.starts_with(|c: char| c.is_digit(10)) {
+        lower.parse()
+    } else {
+        return None;
+    };
+    parsed.ok().map(|value| if negative { -value as f64 } else { value as f64 })
+}
+
+/// Bytes can be written signed, -1 is 0xff
+fn to_byte(value: f64) -> Result<u8, String> {
+    let value = value.floor();
+    if value < -128.0 || value > 255.0 {
+        return Err(format!("{} does not fit in a byte", value));
+    }
+    Ok(value as i64 as u8)
+}
+
+fn flag(condition: bool) -> f64 {
+    if condition { 1.0 } else { 0.0 }
+}
+
+fn neg(value: f64) -> f64 {
+    -value
+}
+
+fn bitwise_not(value: f64) -> f64 {
+    !(value as i64) as f64
+}
+
+fn logical_not(value: f64) -> f64 {
+    flag(value == 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::Chip8;
+    use peripherals::Peripherals;
+    use quirks::Quirks;
+
+    /// Compiles and runs a program until it spins on its final `loop again`
+    fn run(source: &str) -> Chip8 {
+        let rom = compile("test.8o", source).unwrap_or_else(|err| panic!("{}", err));
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(&rom, Quirks::chip48());
+        for _ in 0..2000 {
+            if chip8.is_spinning() {
+                return chip8;
+            }
+            chip8.step(&mut peripherals).unwrap();
+        }
+        panic!("{} never finished", source);
+    }
+
+    /// Runs `then`, `begin` and `begin ... else` branches on a comparison and
+    /// returns whether each of them was taken
+    fn branches(a: u8, op: &str, b: u8) -> [bool; 3] {
+        let source = format!(": main
+                                v0 := {a} v1 := {b} v2 := 0 v3 := 0 v4 := 0
+                                if v0 {op} v1 then v2 := 1
+                                if v0 {op} {b} begin v3 := 1 end
+                                if v0 {op} v1 begin v4 := 1 else v4 := 2 end
+                                loop again",
+                             a = a,
+                             op = op,
+                             b = b);
+        let chip8 = run(&source);
+        let v = chip8.reg_v();
+        assert!(v[4] != 0, "{}: neither begin nor else ran", source);
+        [v[2] == 1, v[3] == 1, v[4] == 1]
+    }
+
+    #[test]
+    fn labels_and_assignments() {
+        assert_eq!(compile("test.8o", ": main v0 := 5 v1 := v0 v1 += 3 loop again").unwrap(),
+                   vec![0x60, 5, 0x81, 0x00, 0x71, 3, 0x12, 0x06]);
+        assert_eq!(compile("test.8o", ": sprite 0x3C 0x42 : main i := sprite").unwrap(),
+                   vec![0x12, 0x04, 0x3C, 0x42, 0xA2, 0x02]);
+        assert_eq!(compile("test.8o", ": main sub i := long data ; : sub return : data 1")
+                       .unwrap(),
+                   vec![0x22, 0x08, 0xF0, 0x00, 0x02, 0x0A, 0x00, 0xEE, 0x00, 0xEE, 1]);
+        assert_eq!(compile("test.8o",
+                           ": main v3 -= 1 va =- vb vf >>= v0 sprite v1 v2 5 i := hex v2
+                            delay := v0 v2 := random 0x0f v3 := key bcd v5 clear")
+                       .unwrap(),
+                   vec![0x73, 0xff, 0x8a, 0xb7, 0x8f, 0x06, 0xd1, 0x25, 0xf2, 0x29, 0xf0, 0x15,
+                        0xc2, 0x0f, 0xf3, 0x0a, 0xf5, 0x33, 0x00, 0xe0]);
+    }
+
+    #[test]
+    fn if_then_and_begin_else() {
+        for &(a, b) in &[(1, 1), (1, 2), (255, 0)] {
+            let equal = a == b;
+            assert_eq!(branches(a, "==", b), [equal, equal, equal]);
+            assert_eq!(branches(a, "!=", b), [!equal, !equal, !equal]);
+        }
+    }
+
+    #[test]
+    fn ordering_pseudo_ops() {
+        let values = [0, 1, 5, 200, 255];
+        let ops: [(&str, fn(u8, u8) -> bool); 4] = [("<", |a, b| a < b),
+                                                    (">", |a, b| a > b),
+                                                    ("<=", |a, b| a <= b),
+                                                    (">=", |a, b| a >= b)];
+        for &a in &values {
+            for &b in &values {
+                for &(op, holds) in &ops {
+                    let expected = holds(a, b);
+                    assert!(branches(a, op, b) == [expected, expected, expected],
+                            "{} {} {}",
+                            a,
+                            op,
+                            b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let chip8 = run(": main
+                           v0 := 0 v1 := 0
+                           loop
+                             while v0 != 10
+                             v0 += 1
+                             v1 += 2
+                           again
+                           loop again");
+        assert_eq!((chip8.reg_v()[0], chip8.reg_v()[1]), (10, 20));
+
+        let chip8 = run(": double v1 += v1 ;
+                         : main
+                           v1 := 1 v2 := 0
+                           loop
+                             v2 += 1
+                             double
+                             if v2 == 5 then jump done
+                           again
+                         : done loop again");
+        assert_eq!(chip8.reg_v()[1], 32);
+    }
+
+    #[test]
+    fn macros_consts_and_calcs() {
+        let chip8 = run(":const COUNT 10
+                         :calc DOUBLE { COUNT * 2 }
+                         :calc RIGHT_TO_LEFT { 2 * 3 + 1 }
+                         :alias counter v5
+                         :macro incr reg amount { reg += amount }
+                         : main
+                           v0 := 0 counter := 0
+                           loop
+                             while v0 != DOUBLE
+                             incr v0 1
+                             incr counter 2
+                           again
+                           v6 := RIGHT_TO_LEFT
+                           loop again");
+        // Like Octo, :calc has no precedence and evaluates from the right
+        assert_eq!(&chip8.reg_v()[..], &[20, 0, 0, 0, 0, 40, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn aliases_can_be_redefined() {
+        let chip8 = run(":alias x v1 : main x := 3 :alias x v2 x := 4 loop again");
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[2]), (3, 4));
+    }
+
+    #[test]
+    fn malformed_programs_are_rejected() {
+        for source in &["v0 := 1",
+                        ": main v0 := 300",
+                        ": main jump nowhere",
+                        ": main : main",
+                        ": main if v0 == 1 v0 := 2",
+                        ": main loop",
+                        ": main again",
+                        ": main end",
+                        ": main if v0 == 1 begin",
+                        ":org 0x300 : main",
+                        ": main v0 |= 3",
+                        ": main v0 ** v1",
+                        ":macro m a { m a } : main m 1",
+                        ":calc X { Y + 1 }",
+                        ": main sprite v0 v1 16",
+                        ": main :byte 256",
+                        ":const v1 3",
+                        ": main v0 :="] {
+            assert!(compile("test.8o", source).is_err(), "{} compiled", source);
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use std::collections::HashMap;
 use std::convert::{From, TryFrom};
+use savestate::{SaveStateError, StateReader, StateWriter};
+use sound::{AudioSink, NullSink, Sound};
 use video_engine::VideoEngine;
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
@@ -23,8 +24,8 @@ pub enum Key {
 }
 
 impl TryFrom<u8> for Key {
-    type Err = String;
-    fn try_from(val: u8) -> Result<Self, Self::Err> {
+    type Error = String;
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
         match val {
             0 => Ok(Key::Key0),
             1 => Ok(Key::Key1),
@@ -71,20 +72,36 @@ impl From<Key> for u8 {
     }
 }
 
+pub const NUM_KEYS: usize = 16;
+
+/// State of the 16 key hex keypad.
+///
+/// Besides the current state of each key, the keypad remembers which keys
+/// went down or up since the last `end_frame`, so that a press and release
+/// delivered within a single frame is not lost.
+#[derive(Clone)]
 pub struct Keypad {
-    key_states: HashMap<Key, bool>,
+    key_states: [bool; NUM_KEYS],
+    pressed_edges: [bool; NUM_KEYS],
+    released_edges: [bool; NUM_KEYS],
 }
 
 pub struct Peripherals {
     pub keypad: Keypad,
     pub video_engine: VideoEngine,
+    pub sound: Sound,
 }
 
 impl Peripherals {
     pub fn new() -> Self {
+        Peripherals::with_audio_sink(Box::new(NullSink))
+    }
+
+    pub fn with_audio_sink(sink: Box<AudioSink>) -> Self {
         Peripherals {
             keypad: Keypad::new(),
             video_engine: VideoEngine::new(),
+            sound: Sound::new(sink),
         }
     }
 }
@@ -92,22 +109,144 @@ impl Peripherals {
 
 impl Keypad {
     pub fn new() -> Self {
-        Keypad { key_states: HashMap::new() }
+        Keypad {
+            key_states: [false; NUM_KEYS],
+            pressed_edges: [false; NUM_KEYS],
+            released_edges: [false; NUM_KEYS],
+        }
     }
 
     pub fn set_button_state(&mut self, key: Key, is_down: bool) {
-        self.key_states.insert(key, is_down);
+        let idx = u8::from(key) as usize;
+        if self.key_states[idx] != is_down {
+            if is_down {
+                self.pressed_edges[idx] = true;
+            } else {
+                self.released_edges[idx] = true;
+            }
+            self.key_states[idx] = is_down;
+        }
+    }
+
+    /// Forgets the press and release edges, called at the end of each frame
+    pub fn end_frame(&mut self) {
+        self.pressed_edges = [false; NUM_KEYS];
+        self.released_edges = [false; NUM_KEYS];
+    }
+
+    pub fn is_pressed(&self, key: Key) -> bool {
+        self.key_states[u8::from(key) as usize]
+    }
+
+    /// True if the key went down during the current frame
+    pub fn was_pressed(&self, key: Key) -> bool {
+        self.pressed_edges[u8::from(key) as usize]
+    }
 
+    /// True if the key went up during the current frame
+    pub fn was_released(&self, key: Key) -> bool {
+        self.released_edges[u8::from(key) as usize]
     }
 
-    pub fn get_current_key_input(&self) -> Option<u8> {
-        for (key, state) in &self.key_states {
+    /// The lowest key that went down during the current frame. The press is
+    /// forgotten, so each one is only taken once.
+    pub fn take_press(&mut self) -> Option<Key> {
+        let key = (0..NUM_KEYS).map(key_from_index).find(|&key| self.was_pressed(key));
+        if let Some(key) = key {
+            self.pressed_edges[u8::from(key) as usize] = false;
+        }
+        key
+    }
+
+    /// Current key states as a bitmask, bit N being key N
+    pub fn state_mask(&self) -> u16 {
+        let mut mask = 0u16;
+        for (idx, state) in self.key_states.iter().enumerate() {
             if *state {
-                return Some(*key as u8);
-            } else {
-                continue;
+                mask |= 1 << idx;
             }
         }
-        None
+        mask
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u16(self.state_mask());
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let mask = reader.get_u16()?;
+        for idx in 0..NUM_KEYS {
+            self.key_states[idx] = mask & (1 << idx) != 0;
+        }
+        self.end_frame();
+        Ok(())
+    }
+}
+
+/// Converts a key index known to be below `NUM_KEYS`
+pub fn key_from_index(idx: usize) -> Key {
+    Key::try_from(idx as u8).expect("Every index below NUM_KEYS is a valid key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_independent() {
+        let mut keypad = Keypad::new();
+        keypad.set_button_state(Key::Key1, true);
+        keypad.set_button_state(Key::KeyA, true);
+        keypad.set_button_state(Key::KeyF, true);
+        keypad.set_button_state(Key::KeyA, false);
+        assert!(keypad.is_pressed(Key::Key1));
+        assert!(!keypad.is_pressed(Key::KeyA));
+        assert!(keypad.is_pressed(Key::KeyF));
+        assert_eq!(keypad.state_mask(), 0x8002);
+    }
+
+    #[test]
+    fn edges_last_until_the_end_of_the_frame() {
+        let mut keypad = Keypad::new();
+        keypad.set_button_state(Key::Key2, true);
+        keypad.set_button_state(Key::Key2, false);
+        keypad.set_button_state(Key::Key4, true);
+        assert!(keypad.was_pressed(Key::Key2) && keypad.was_released(Key::Key2));
+        assert!(keypad.was_pressed(Key::Key4) && !keypad.was_released(Key::Key4));
+        assert!(!keypad.was_pressed(Key::Key3));
+        keypad.end_frame();
+        assert!(!keypad.was_pressed(Key::Key2) && !keypad.was_pressed(Key::Key4));
+        assert!(keypad.is_pressed(Key::Key4));
+        // Repeating the current state is not an edge
+        keypad.set_button_state(Key::Key4, true);
+        assert!(!keypad.was_pressed(Key::Key4));
+    }
+
+    #[test]
+    fn presses_are_taken_lowest_first_and_once() {
+        let mut keypad = Keypad::new();
+        keypad.set_button_state(Key::Key9, true);
+        keypad.end_frame();
+        keypad.set_button_state(Key::KeyB, true);
+        keypad.set_button_state(Key::Key6, true);
+        keypad.set_button_state(Key::Key6, false);
+        assert_eq!(keypad.take_press(), Some(Key::Key6));
+        assert_eq!(keypad.take_press(), Some(Key::KeyB));
+        assert_eq!(keypad.take_press(), None);
+        assert!(keypad.is_pressed(Key::Key9) && keypad.is_pressed(Key::KeyB));
+    }
+
+    #[test]
+    fn save_state_keeps_the_held_keys() {
+        let mut keypad = Keypad::new();
+        keypad.set_button_state(Key::Key0, true);
+        keypad.set_button_state(Key::KeyE, true);
+        let mut writer = StateWriter::new();
+        keypad.save_state(&mut writer);
+        let data = writer.into_inner();
+        let mut restored = Keypad::new();
+        restored.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(restored.state_mask(), keypad.state_mask());
+        assert!(!restored.was_pressed(Key::Key0));
     }
 }
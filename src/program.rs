@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use disasm::ROM_START;
+use instruction::Instruction;
+
+/// Builds a ROM out of instructions, for tests and tools generating code.
+/// Jumps can target labels placed before or after them.
+///
+/// ```ignore
+/// let rom = Program::new()
+///     .push(Instruction::Mov { vr: 0, k: 0 })
+///     .label("loop")
+///     .push(Instruction::Add { vr: 0, k: 1 })
+///     .jmp("loop")
+///     .build()?;
+/// ```
+pub struct Program {
+    bytes: Vec<u8>,
+    labels: HashMap<String, usize>,
+    /// Offsets of instructions whose address comes from a label
+    fixups: Vec<(usize, String)>,
+    duplicates: Vec<String>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Program {
+            bytes: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            duplicates: Vec::new(),
+        }
+    }
+
+    /// Address the next instruction goes to
+    pub fn here(&self) -> usize {
+        ROM_START + self.bytes.len()
+    }
+
+    pub fn push(&mut self, instruction: Instruction) -> &mut Self {
+        let opcode = u16::from(instruction);
+        self.bytes.push((opcode >> 8) as u8);
+        self.bytes.push(opcode as u8);
+        self
+    }
+
+    /// Raw data, such as sprites or the address word after `lmvi`
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        let addr = self.here();
+        if self.labels.insert(name.into(), addr).is_some() {
+            self.duplicates.push(name.into());
+        }
+        self
+    }
+
+    pub fn jmp(&mut self, label: &str) -> &mut Self {
+        self.push_to(Instruction::Jmp { addr: 0 }, label)
+    }
+
+    pub fn jsr(&mut self, label: &str) -> &mut Self {
+        self.push_to(Instruction::Jsr { addr: 0 }, label)
+    }
+
+    pub fn mvi(&mut self, label: &str) -> &mut Self {
+        self.push_to(Instruction::Mvi { k: 0 }, label)
+    }
+
+    /// Pushes an instruction whose 12 bit address is filled in by `build`
+    fn push_to(&mut self, instruction: Instruction, label: &str) -> &mut Self {
+        let offset = self.bytes.len();
+        self.fixups.push((offset, label.into()));
+        self.push(instruction)
+    }
+
+    /// The ROM bytes, failing on missing, duplicate or unreachable labels
+    pub fn build(&self) -> Result<Vec<u8>, String> {
+        if let Some(name) = self.duplicates.first() {
+            return Err(format!("Label {} is defined more than once", name));
+        }
+        let mut rom = self.bytes.clone();
+        for &(offset, ref label) in &self.fixups {
+            let addr = *self.labels.get(label).ok_or_else(|| format!("Unknown label {}", label))?;
+            if addr > 0xFFF {
+                return Err(format!("Label {} at 0x{:x} is out of reach", label, addr));
+            }
+            rom[offset] |= (addr >> 8) as u8;
+            rom[offset + 1] = addr as u8;
+        }
+        Ok(rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions_are_encoded_big_endian() {
+        let rom = Program::new()
+            .push(Instruction::Cls)
+            .push(Instruction::Mov { vr: 3, k: 0x42 })
+            .push(Instruction::Add { vr: 0xA, k: 0xFF })
+            .bytes(&[0xF0, 0x90])
+            .push(Instruction::Ret)
+            .build()
+            .unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0, 0x63, 0x42, 0x7A, 0xFF, 0xF0, 0x90, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn labels_resolve_backwards_and_forwards() {
+        let rom = Program::new()
+            .jsr("sub")
+            .label("loop")
+            .mvi("sprite")
+            .jmp("loop")
+            .label("sub")
+            .push(Instruction::Ret)
+            .label("sprite")
+            .bytes(&[0xFF])
+            .build()
+            .unwrap();
+        assert_eq!(rom, vec![0x22, 0x06, 0xA2, 0x08, 0x12, 0x02, 0x00, 0xEE, 0xFF]);
+    }
+
+    #[test]
+    fn here_follows_the_pushed_bytes() {
+        let mut program = Program::new();
+        assert_eq!(program.here(), ROM_START);
+        program.push(Instruction::Cls).bytes(&[1, 2, 3]);
+        assert_eq!(program.here(), ROM_START + 5);
+    }
+
+    #[test]
+    fn unknown_labels_are_an_error() {
+        let error = Program::new().jmp("nowhere").build().unwrap_err();
+        assert_eq!(error, "Unknown label nowhere");
+    }
+
+    #[test]
+    fn duplicate_labels_are_an_error() {
+        let error = Program::new()
+            .label("twice")
+            .push(Instruction::Cls)
+            .label("twice")
+            .build()
+            .unwrap_err();
+        assert_eq!(error, "Label twice is defined more than once");
+    }
+
+    #[test]
+    fn labels_beyond_12_bits_are_out_of_reach() {
+        let mut program = Program::new();
+        program.jmp("far").bytes(&vec![0; 0x1000 - ROM_START]).label("far");
+        assert_eq!(program.build().unwrap_err(), "Label far at 0x1002 is out of reach");
+    }
+}
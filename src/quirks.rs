@@ -0,0 +1,183 @@
+use savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Behaviours that differ between Chip8 interpreters. ROMs written for one
+/// interpreter often misbehave when these don't match what they expect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift VY into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    /// `FX55`/`FX65` leave I pointing past the last register transferred
+    pub load_store_increment_i: bool,
+    /// `8XY1`/`8XY2`/`8XY3` reset VF to 0
+    pub logic_resets_vf: bool,
+    /// Sprites wrap around the screen edges instead of being clipped
+    pub sprite_wrap: bool,
+    /// `DXYN` waits for the vertical blank, so at most one sprite is drawn per frame
+    pub display_wait: bool,
+    /// `BNNN` jumps to NNN + VX, X being the highest nibble of NNN, instead of NNN + V0
+    pub jump_vx: bool,
+}
+
+pub const PRESET_NAMES: [&'static str; 4] = ["vip", "chip48", "schip", "xochip"];
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter
+    pub fn vip() -> Self {
+        Quirks {
+            shift_vy: true,
+            load_store_increment_i: true,
+            logic_resets_vf: true,
+            sprite_wrap: false,
+            display_wait: true,
+            jump_vx: false,
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Self {
+        Quirks {
+            shift_vy: false,
+            load_store_increment_i: false,
+            logic_resets_vf: false,
+            sprite_wrap: false,
+            display_wait: false,
+            jump_vx: true,
+        }
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn schip() -> Self {
+        Quirks::chip48()
+    }
+
+    /// XO-CHIP, as implemented by Octo
+    pub fn xochip() -> Self {
+        Quirks {
+            shift_vy: true,
+            load_store_increment_i: true,
+            logic_resets_vf: false,
+            sprite_wrap: true,
+            display_wait: false,
+            jump_vx: false,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "vip" | "chip8" => Ok(Quirks::vip()),
+            "chip48" => Ok(Quirks::chip48()),
+            "schip" | "superchip" => Ok(Quirks::schip()),
+            "xochip" => Ok(Quirks::xochip()),
+            _ => {
+                Err(format!("Unknown quirks preset {} (expected one of {})",
+                            name,
+                            PRESET_NAMES.join(", ")))
+            }
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bool(self.shift_vy);
+        writer.put_bool(self.load_store_increment_i);
+        writer.put_bool(self.logic_resets_vf);
+        writer.put_bool(self.sprite_wrap);
+        writer.put_bool(self.display_wait);
+        writer.put_bool(self.jump_vx);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.shift_vy = reader.get_bool()?;
+        self.load_store_increment_i = reader.get_bool()?;
+        self.logic_resets_vf = reader.get_bool()?;
+        self.sprite_wrap = reader.get_bool()?;
+        self.display_wait = reader.get_bool()?;
+        self.jump_vx = reader.get_bool()?;
+        Ok(())
+    }
+}
+
+/// The original COSMAC VIP behaviour, the other presets being opt-in
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::vip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::Chip8;
+    use peripherals::Peripherals;
+
+    /// Runs the first `steps` instructions of a ROM with one quirk set to
+    /// `on` and the others left as in the default
+    fn run(rom: &[u8],
+           quirk: fn(&mut Quirks) -> &mut bool,
+           on: bool,
+           steps: usize)
+           -> (Chip8, Peripherals) {
+        let mut quirks = Quirks::default();
+        *quirk(&mut quirks) = on;
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(rom, quirks);
+        for _ in 0..steps {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        (chip8, peripherals)
+    }
+
+    #[test]
+    fn default_is_the_cosmac_vip() {
+        assert_eq!(Quirks::default(), Quirks::vip());
+    }
+
+    #[test]
+    fn shift_vy() {
+        // v1 := 4, v2 := 0x10, v1 >>= v2
+        let rom = [0x61, 0x04, 0x62, 0x10, 0x81, 0x26];
+        assert_eq!(run(&rom, |q| &mut q.shift_vy, true, 3).0.reg_v()[1], 0x08);
+        assert_eq!(run(&rom, |q| &mut q.shift_vy, false, 3).0.reg_v()[1], 0x02);
+    }
+
+    #[test]
+    fn load_store_increment_i() {
+        let rom = [0xA3, 0x00, 0xF2, 0x55];
+        let quirk: fn(&mut Quirks) -> &mut bool = |q| &mut q.load_store_increment_i;
+        assert_eq!(run(&rom, quirk, true, 2).0.reg_i(), 0x303);
+        assert_eq!(run(&rom, quirk, false, 2).0.reg_i(), 0x300);
+    }
+
+    #[test]
+    fn logic_resets_vf() {
+        let rom = [0x6F, 0x05, 0x81, 0x21];
+        assert_eq!(run(&rom, |q| &mut q.logic_resets_vf, true, 2).0.reg_v()[0xF], 0);
+        assert_eq!(run(&rom, |q| &mut q.logic_resets_vf, false, 2).0.reg_v()[0xF], 5);
+    }
+
+    #[test]
+    fn sprite_wrap() {
+        // The top row of digit 0, F0, drawn at x = 62
+        let rom = [0x61, 0x3E, 0xA0, 0x00, 0xD1, 0x01];
+        let (_, peripherals) = run(&rom, |q| &mut q.sprite_wrap, true, 3);
+        assert_eq!(peripherals.video_engine.pixel(1, 0), 1);
+        let (_, peripherals) = run(&rom, |q| &mut q.sprite_wrap, false, 3);
+        assert_eq!(peripherals.video_engine.pixel(1, 0), 0);
+        assert_eq!(peripherals.video_engine.pixel(63, 0), 1);
+    }
+
+    #[test]
+    fn display_wait() {
+        // The second sprite of a frame waits for the next one
+        let rom = [0xD0, 0x01, 0xD0, 0x01];
+        assert_eq!(run(&rom, |q| &mut q.display_wait, true, 2).0.pc(), 0x202);
+        assert_eq!(run(&rom, |q| &mut q.display_wait, false, 2).0.pc(), 0x204);
+    }
+
+    #[test]
+    fn jump_vx() {
+        // v0 := 1, v3 := 2, jump0 0x300
+        let rom = [0x60, 0x01, 0x63, 0x02, 0xB3, 0x00];
+        assert_eq!(run(&rom, |q| &mut q.jump_vx, true, 3).0.pc(), 0x302);
+        assert_eq!(run(&rom, |q| &mut q.jump_vx, false, 3).0.pc(), 0x301);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+
+use chip8::Chip8;
+use peripherals::Peripherals;
+use savestate;
+
+/// Snapshots kept by default: 30 seconds of frames
+pub const DEFAULT_REWIND_FRAMES: usize = 30 * 60;
+/// Upper bound on the memory used by the snapshots, whatever their number
+pub const DEFAULT_REWIND_BYTES: usize = 32 * 1024 * 1024;
+
+/// Bounded history of machine states, taken once per frame.
+///
+/// Only the newest snapshot is stored in full. Each older one is stored as
+/// the run-length encoded XOR against the snapshot that followed it, which
+/// is mostly zeros since a frame rarely touches more than a few bytes.
+pub struct Rewind {
+    max_snapshots: usize,
+    max_bytes: usize,
+    latest: Option<(u64, Vec<u8>)>,
+    deltas: VecDeque<(u64, Vec<u8>)>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(max_snapshots: usize, max_bytes: usize) -> Self {
+        Rewind {
+            max_snapshots: max_snapshots,
+            max_bytes: max_bytes,
+            latest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// Records the machine state at the start of `frame`
+    pub fn snapshot(&mut self, frame: u64, chip8: &Chip8, peripherals: &Peripherals) {
+        if self.max_snapshots == 0 {
+            return;
+        }
+        self.push(frame, savestate::save(chip8, peripherals, frame));
+    }
+
+    /// Restores the newest snapshot and forgets it. Returns the frame it was
+    /// taken at, or None once the history is exhausted.
+    pub fn step_back(&mut self, chip8: &mut Chip8, peripherals: &mut Peripherals) -> Option<u64> {
+        self.pop().map(|(_, state)| {
+            savestate::load(&state, chip8, peripherals)
+                .expect("Rewind snapshots are taken from the running ROM")
+        })
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        if let Some((latest_frame, latest_state)) = self.latest.take() {
+            let delta = encode_delta(&latest_state, &state);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back((latest_frame, delta));
+        }
+        self.latest = Some((frame, state));
+
+        let latest_bytes = self.latest.as_ref().map_or(0, |&(_, ref state)| state.len());
+        while !self.deltas.is_empty() &&
+              (self.deltas.len() + 1 > self.max_snapshots ||
+               self.delta_bytes + latest_bytes > self.max_bytes) {
+            let (_, oldest) = self.deltas.pop_front().expect("deltas is not empty");
+            self.delta_bytes -= oldest.len();
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<(u64, Vec<u8>)> {
+        let (frame, state) = match self.latest.take() {
+            Some(latest) => latest,
+            None => return None,
+        };
+        if let Some((previous_frame, delta)) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            self.latest = Some((previous_frame, apply_delta(&state, &delta)));
+        }
+        Some((frame, state))
+    }
+
+    /// Forgets every snapshot, e.g. after loading a save state
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    /// Changes the bound on the number of snapshots, 0 disabling rewind
+    pub fn set_max_snapshots(&mut self, max_snapshots: usize) {
+        self.max_snapshots = max_snapshots;
+        if max_snapshots == 0 {
+            self.clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + if self.latest.is_some() { 1 } else { 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Bytes held by the snapshots
+    pub fn memory_usage(&self) -> usize {
+        self.delta_bytes + self.latest.as_ref().map_or(0, |&(_, ref state)| state.len())
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(DEFAULT_REWIND_FRAMES, DEFAULT_REWIND_BYTES)
+    }
+}
+
+/// Encodes `old` relative to `new`: the length of `old`, then pairs of runs
+/// (unchanged bytes, changed bytes followed by their XOR), lengths being
+/// LEB128 varints. Bytes past the end of the shorter input count as zeros.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let len = old.len().max(new.len());
+    let xor_at = |i: usize| old.get(i).unwrap_or(&0) ^ new.get(i).unwrap_or(&0);
+
+    let mut delta = Vec::new();
+    put_varint(&mut delta, old.len());
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && xor_at(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < len && xor_at(i) != 0 {
+            i += 1;
+        }
+        put_varint(&mut delta, literal_start - zeros_start);
+        put_varint(&mut delta, i - literal_start);
+        for j in literal_start..i {
+            delta.push(xor_at(j));
+        }
+    }
+    delta
+}
+
+fn apply_delta(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let old_len = get_varint(delta, &mut pos);
+    let mut old = new.to_vec();
+    old.resize(old_len.max(new.len()), 0);
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += get_varint(delta, &mut pos);
+        let literal_len = get_varint(delta, &mut pos);
+        for byte in &delta[pos..pos + literal_len] {
+            old[i] ^= *byte;
+            i += 1;
+        }
+        pos += literal_len;
+    }
+    old.truncate(old_len);
+    old
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::ClockConfig;
+    use emulator::Emulator;
+    use headless::{HeadlessFrontend, KeyScript};
+
+    /// A state of `len` bytes, each frame changing a different part of it
+    fn state(frame: u64, len: usize) -> Vec<u8> {
+        (0..len).map(|i| if i % 7 == frame as usize % 7 { frame as u8 } else { i as u8 }).collect()
+    }
+
+    #[test]
+    fn deltas_restore_the_older_state() {
+        let long_change: Vec<u8> = (0..300).map(|i| i as u8 ^ 0xff).collect();
+        let cases: Vec<(Vec<u8>, Vec<u8>)> = vec![(vec![], vec![]),
+                                                  (vec![1, 2, 3], vec![1, 2, 3]),
+                                                  (vec![1, 2, 3, 4], vec![1, 0, 3, 0]),
+                                                  (long_change, vec![0; 300]),
+                                                  (vec![5; 10], vec![5; 1000]),
+                                                  (vec![5; 1000], vec![6; 10]),
+                                                  (vec![], vec![1; 200])];
+        for (old, new) in cases {
+            let delta = encode_delta(&old, &new);
+            assert_eq!(apply_delta(&new, &delta), old);
+        }
+        // Unchanged stretches cost a few bytes whatever their length
+        assert!(encode_delta(&vec![0; 4096], &vec![0; 4096]).len() <= 8);
+    }
+
+    #[test]
+    fn snapshots_come_back_newest_first() {
+        let mut rewind = Rewind::new(100, DEFAULT_REWIND_BYTES);
+        // The state grows when the display switches to high resolution
+        let states: Vec<Vec<u8>> =
+            (0..10).map(|frame| state(frame, if frame < 5 { 100 } else { 400 })).collect();
+        for (frame, state) in states.iter().enumerate() {
+            rewind.push(frame as u64, state.clone());
+        }
+        assert_eq!(rewind.len(), 10);
+        for frame in (0..10).rev() {
+            assert_eq!(rewind.pop(), Some((frame, states[frame as usize].clone())));
+        }
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn limits_evict_the_oldest_snapshots() {
+        let mut rewind = Rewind::new(3, DEFAULT_REWIND_BYTES);
+        for frame in 0..10 {
+            rewind.push(frame, state(frame, 100));
+        }
+        assert_eq!(rewind.len(), 3);
+        let frames: Vec<u64> = (0..4)
+            .filter_map(|_| rewind.pop())
+            .map(|(frame, _)| frame)
+            .collect();
+        assert_eq!(frames, vec![9, 8, 7]);
+
+        // States without anything in common cost their full size
+        let mut rewind = Rewind::new(100, 3500);
+        for frame in 0..10 {
+            rewind.push(frame, vec![frame as u8; 1000]);
+        }
+        assert!(rewind.memory_usage() <= 3500);
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop().map(|(frame, _)| frame), Some(9));
+
+        rewind.set_max_snapshots(0);
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn rewinding_restores_the_registers_and_framebuffer() {
+        // Draws digit v1 at x = v1 every frame, switching to high
+        // resolution when v1 reaches 10
+        let rom = [0x71, 0x01, 0x41, 0x0A, 0x00, 0xFF, 0xF1, 0x29, 0xD1, 0x25, 0x12, 0x00];
+        let mut emulator = Emulator::new(&rom, HeadlessFrontend::new(KeyScript::new()));
+        emulator.set_clock(ClockConfig {
+            instructions_per_frame: 6,
+            throttle: false,
+        });
+        let mut history = Vec::new();
+        for _ in 0..20 {
+            history.push((emulator.chip8().clone(), emulator.peripherals().video_engine.clone()));
+            emulator.run_frame().unwrap();
+        }
+        for &(frames, frame) in &[(5, 15), (10, 5)] {
+            assert_eq!(emulator.rewind(frames), frames);
+            assert_eq!(emulator.frame(), frame);
+            let (ref chip8, ref video) = history[frame as usize];
+            assert_eq!(emulator.chip8().pc(), chip8.pc());
+            assert_eq!(emulator.chip8().reg_v(), chip8.reg_v());
+            assert_eq!(emulator.chip8().reg_i(), chip8.reg_i());
+            assert_eq!(emulator.peripherals().video_engine.is_hires(), video.is_hires());
+            assert_eq!(emulator.peripherals().video_engine.vram(), video.vram());
+        }
+        assert!(!emulator.peripherals().video_engine.is_hires());
+        assert_eq!(emulator.rewind(10), 5);
+        assert_eq!(emulator.frame(), 0);
+    }
+}
@@ -0,0 +1,61 @@
+use rand::{thread_rng, Rng, SeedableRng};
+
+/// xorshift64* generator used by `CXNN`. Its whole state is a single u64,
+/// so it can be stored in save states and replayed from a seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    /// A fresh seed for sessions that do not need to be reproduced
+    pub fn random_seed() -> u64 {
+        thread_rng().next_u64()
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// Restores a state returned by `state`. Zero is the one state the
+    /// generator never reaches, so it is rejected.
+    pub fn set_state(&mut self, state: u64) -> Result<(), String> {
+        if state == 0 {
+            return Err("random generator state cannot be zero".into());
+        }
+        self.state = state;
+        Ok(())
+    }
+}
+
+impl Rng for XorShift64 {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+}
+
+impl SeedableRng<u64> for XorShift64 {
+    fn reseed(&mut self, seed: u64) {
+        // One splitmix64 round, so that similar seeds give unrelated sequences
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        self.state = if z == 0 { 1 } else { z };
+    }
+
+    fn from_seed(seed: u64) -> Self {
+        let mut rng = XorShift64 { state: 1 };
+        rng.reseed(seed);
+        rng
+    }
+}
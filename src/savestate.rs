@@ -0,0 +1,318 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use chip8::Chip8;
+use peripherals::Peripherals;
+
+/// Save state files start with this magic number, followed by the format
+/// version, a hash of the ROM the state was taken from and the frame counter
+pub const MAGIC: &'static [u8; 4] = b"C8SS";
+pub const VERSION: u16 = 4;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u64, found: u64 },
+    Corrupt(String),
+    /// Movies replay from power on, so a state cannot be loaded into one
+    MovieInProgress,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::Io(ref e) => write!(f, "I/O error: {}", e),
+            SaveStateError::BadMagic => write!(f, "Not a save state file"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f,
+                       "Unsupported save state version {} (expected {})",
+                       version,
+                       VERSION)
+            }
+            SaveStateError::RomMismatch { expected, found } => {
+                write!(f,
+                       "Save state belongs to another ROM (hash {:016x}, loaded ROM is {:016x})",
+                       found,
+                       expected)
+            }
+            SaveStateError::Corrupt(ref msg) => write!(f, "Corrupt save state: {}", msg),
+            SaveStateError::MovieInProgress => {
+                write!(f, "States cannot be loaded while a movie is recorded or replayed")
+            }
+        }
+    }
+}
+
+impl error::Error for SaveStateError {
+    fn description(&self) -> &str {
+        "invalid save state"
+    }
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> Self {
+        SaveStateError::Io(e)
+    }
+}
+
+/// 64 bit FNV-1a hash, used to tie a save state to its ROM
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in rom {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Serializes the whole machine at the start of `frame`, header included
+pub fn save(chip8: &Chip8, peripherals: &Peripherals, frame: u64) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    writer.put_bytes(MAGIC);
+    writer.put_u16(VERSION);
+    writer.put_u64(chip8.rom_hash());
+    writer.put_u64(frame);
+    chip8.save_state(&mut writer);
+    peripherals.video_engine.save_state(&mut writer);
+    peripherals.keypad.save_state(&mut writer);
+    writer.into_inner()
+}
+
+/// Restores a machine serialized by `save` and returns its frame counter.
+/// The state is only applied once it has been fully validated, so on error
+/// the machine is left untouched.
+pub fn load(data: &[u8],
+            chip8: &mut Chip8,
+            peripherals: &mut Peripherals)
+            -> Result<u64, SaveStateError> {
+    let mut reader = StateReader::new(data);
+    if reader.get_bytes(MAGIC.len())? != MAGIC {
+        return Err(SaveStateError::BadMagic);
+    }
+    let version = reader.get_u16()?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let hash = reader.get_u64()?;
+    if hash != chip8.rom_hash() {
+        return Err(SaveStateError::RomMismatch {
+            expected: chip8.rom_hash(),
+            found: hash,
+        });
+    }
+    let frame = reader.get_u64()?;
+
+    let mut new_chip8 = chip8.clone();
+    let mut new_video = peripherals.video_engine.clone();
+    let mut new_keypad = peripherals.keypad.clone();
+    new_chip8.load_state(&mut reader)?;
+    new_video.load_state(&mut reader)?;
+    new_keypad.load_state(&mut reader)?;
+    if !reader.is_empty() {
+        return Err(SaveStateError::Corrupt("trailing data".into()));
+    }
+
+    *chip8 = new_chip8;
+    peripherals.video_engine = new_video;
+    peripherals.keypad = new_keypad;
+    Ok(frame)
+}
+
+pub fn save_to_file(path: &str,
+                    chip8: &Chip8,
+                    peripherals: &Peripherals,
+                    frame: u64)
+                    -> Result<(), SaveStateError> {
+    let mut f = File::create(path)?;
+    f.write_all(&save(chip8, peripherals, frame))?;
+    Ok(())
+}
+
+pub fn load_from_file(path: &str,
+                      chip8: &mut Chip8,
+                      peripherals: &mut Peripherals)
+                      -> Result<u64, SaveStateError> {
+    let mut f = File::open(path)?;
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)?;
+    load(&data, chip8, peripherals)
+}
+
+/// Little endian serializer used by the machine components
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(if value { 1 } else { 0 });
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        self.put_u8(value as u8);
+        self.put_u8((value >> 8) as u8);
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.put_u16(value as u16);
+        self.put_u16((value >> 16) as u16);
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.put_u32(value as u32);
+        self.put_u32((value >> 32) as u32);
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Writes a length prefixed block of bytes
+    pub fn put_block(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len() as u32);
+        self.put_bytes(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Counterpart of `StateWriter`, failing on truncated input
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    pub fn get_bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() - self.pos < len {
+            return Err(SaveStateError::Corrupt("unexpected end of file".into()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(SaveStateError::Corrupt(format!("invalid boolean {}", other))),
+        }
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, SaveStateError> {
+        let lo = self.get_u8()? as u16;
+        let hi = self.get_u8()? as u16;
+        Ok(lo | (hi << 8))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, SaveStateError> {
+        let lo = self.get_u16()? as u32;
+        let hi = self.get_u16()? as u32;
+        Ok(lo | (hi << 16))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, SaveStateError> {
+        let lo = self.get_u32()? as u64;
+        let hi = self.get_u32()? as u64;
+        Ok(lo | (hi << 32))
+    }
+
+    /// Reads a length prefixed block, checking it has the expected size
+    pub fn get_block(&mut self, expected_len: usize) -> Result<&'a [u8], SaveStateError> {
+        let len = self.get_u32()? as usize;
+        if len != expected_len {
+            return Err(SaveStateError::Corrupt(format!("block of {} bytes, expected {}",
+                                                       len,
+                                                       expected_len)));
+        }
+        self.get_bytes(len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    #[test]
+    fn loading_restores_the_saved_machine() {
+        // v1 := 7, i := 0x210, sprite v1 v1 5, call 0x20A, spin
+        let rom = [0x61, 0x07, 0xA2, 0x10, 0xD1, 0x15, 0x22, 0x0A, 0x12, 0x08, 0x00, 0xEE];
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(&rom, Quirks::default());
+        for _ in 0..4 {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        let data = save(&chip8, &peripherals, 42);
+
+        let mut new_peripherals = Peripherals::new();
+        let mut new_chip8 = Chip8::new(&rom, Quirks::default());
+        assert_eq!(load(&data, &mut new_chip8, &mut new_peripherals).unwrap(), 42);
+        assert_eq!((new_chip8.pc(), new_chip8.sp()), (chip8.pc(), chip8.sp()));
+        assert_eq!(new_chip8.reg_v(), chip8.reg_v());
+        assert_eq!(new_peripherals.video_engine.vram(), peripherals.video_engine.vram());
+        assert_eq!(save(&new_chip8, &new_peripherals, 42), data);
+    }
+
+    #[test]
+    fn loading_checks_the_state() {
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(&[0x12, 0x00], Quirks::default());
+        let data = save(&chip8, &peripherals, 0);
+        let mut other = Chip8::new(&[0x12, 0x02], Quirks::default());
+        match load(&data, &mut other, &mut peripherals) {
+            Err(SaveStateError::RomMismatch { .. }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(load(&data[..100], &mut chip8, &mut peripherals).is_err());
+        assert!(load(b"C8MV", &mut chip8, &mut peripherals).is_err());
+    }
+
+    #[test]
+    fn loading_drops_code_decoded_since_the_save() {
+        // v1 := 1, then store v0 = 0x62 over it, turning it into v2 := 1
+        let rom = [0x61, 0x01, 0x60, 0x62, 0xA2, 0x00, 0xF0, 0x55, 0x12, 0x00];
+        let mut peripherals = Peripherals::new();
+        let mut chip8 = Chip8::new(&rom, Quirks::default());
+        let data = save(&chip8, &peripherals, 0);
+        for _ in 0..6 {
+            chip8.step(&mut peripherals).unwrap();
+        }
+        assert_eq!(chip8.reg_v()[2], 1);
+
+        load(&data, &mut chip8, &mut peripherals).unwrap();
+        chip8.step(&mut peripherals).unwrap();
+        assert_eq!((chip8.reg_v()[1], chip8.reg_v()[2]), (1, 0));
+    }
+}
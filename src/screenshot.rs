@@ -0,0 +1,148 @@
+use std::io;
+use std::io::prelude::*;
+
+use video_engine::VideoEngine;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One digit per pixel giving the lit planes, as printed by `vdump`
+    Ascii,
+    /// Plain (P1) portable bitmap, any lit plane counting as black
+    Pbm,
+    /// RGB PNG using the current palette
+    Png,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "ascii" | "txt" => Ok(Format::Ascii),
+            "pbm" => Ok(Format::Pbm),
+            "png" => Ok(Format::Png),
+            _ => Err(format!("Unknown image format {} (expected ascii, pbm or png)", name)),
+        }
+    }
+
+    /// Guesses the format from a file extension, defaulting to ASCII
+    pub fn from_path(path: &str) -> Self {
+        match path.rsplit('.').next() {
+            Some(ext) => Format::from_name(&ext.to_lowercase()).unwrap_or(Format::Ascii),
+            None => Format::Ascii,
+        }
+    }
+}
+
+pub fn encode(video: &VideoEngine, format: Format) -> Vec<u8> {
+    match format {
+        Format::Ascii => ascii(video).into_bytes(),
+        Format::Pbm => pbm(video).into_bytes(),
+        Format::Png => png(video),
+    }
+}
+
+pub fn write<W: Write>(w: &mut W, video: &VideoEngine, format: Format) -> io::Result<()> {
+    w.write_all(&encode(video, format))
+}
+
+pub fn ascii(video: &VideoEngine) -> String {
+    let mut out = String::with_capacity((video.width() + 1) * video.height());
+    for y in 0..video.height() {
+        for x in 0..video.width() {
+            out.push((b'0' + video.pixel(x, y)) as char);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+pub fn pbm(video: &VideoEngine) -> String {
+    let mut out = format!("P1\n{} {}\n", video.width(), video.height());
+    for y in 0..video.height() {
+        let row: Vec<&str> = (0..video.width())
+            .map(|x| if video.pixel(x, y) != 0 { "1" } else { "0" })
+            .collect();
+        out.push_str(&row.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+/// Encodes the framebuffer as a PNG. The image data is stored uncompressed,
+/// which keeps the encoder tiny and is plenty for a 128x64 screen.
+pub fn png(video: &VideoEngine) -> Vec<u8> {
+    let (width, height) = (video.width(), video.height());
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for y in 0..height {
+        raw.push(0); // No filter
+        for x in 0..width {
+            let color = video.vram()[x + y * width];
+            raw.push((color >> 16) as u8);
+            raw.push((color >> 8) as u8);
+            raw.push(color as u8);
+        }
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+
+    let mut ihdr = Vec::new();
+    push_u32_be(&mut ihdr, width as u32);
+    push_u32_be(&mut ihdr, height as u32);
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit RGB, no interlace
+    png_chunk(&mut out, b"IHDR", &ihdr);
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    push_u32_be(out, data.len() as u32);
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    push_u32_be(out, crc);
+}
+
+/// Wraps `data` in a zlib stream made of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(if last { 1 } else { 0 });
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    push_u32_be(&mut out, adler32(data));
+    out
+}
+
+fn push_u32_be(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8,
+                            value as u8]);
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
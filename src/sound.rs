@@ -0,0 +1,360 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+
+use clock::FRAME_RATE;
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / FRAME_RATE) as usize;
+const TONE_FREQUENCY: u32 = 440;
+const TONE_AMPLITUDE: i16 = 8000;
+const PATTERN_BITS: usize = 128;
+const DEFAULT_PITCH: u8 = 64;
+
+/// Destination for the 16 bit mono PCM samples rendered by the sound peripheral
+pub trait AudioSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+}
+
+/// Discards every sample
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _samples: &[i16]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records the rendered samples to a WAV file. The header sizes are fixed up
+/// when the sink is dropped.
+pub struct WavSink {
+    writer: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavSink {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_wav_header(&mut writer, 0)?;
+        Ok(WavSink {
+            writer: writer,
+            data_len: 0,
+        })
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.data_len)?;
+        self.writer.flush()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            write_u16(&mut self.writer, *sample as u16)?;
+        }
+        self.data_len += (samples.len() * 2) as u32;
+        Ok(())
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            println!("Could not finalize WAV file: {}", e);
+        }
+    }
+}
+
+fn write_wav_header<W: Write>(w: &mut W, data_len: u32) -> io::Result<()> {
+    w.write_all(b"RIFF")?;
+    write_u32(w, 36 + data_len)?;
+    w.write_all(b"WAVE")?;
+    w.write_all(b"fmt ")?;
+    write_u32(w, 16)?; // fmt chunk size
+    write_u16(w, 1)?; // PCM
+    write_u16(w, 1)?; // Mono
+    write_u32(w, SAMPLE_RATE)?;
+    write_u32(w, SAMPLE_RATE * 2)?; // Byte rate
+    write_u16(w, 2)?; // Block align
+    write_u16(w, 16)?; // Bits per sample
+    w.write_all(b"data")?;
+    write_u32(w, data_len)
+}
+
+fn write_u16<W: Write>(w: &mut W, value: u16) -> io::Result<()> {
+    w.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(w: &mut W, value: u32) -> io::Result<()> {
+    write_u16(w, value as u16)?;
+    write_u16(w, (value >> 16) as u16)
+}
+
+/// Square wave generator producing the classic Chip8 buzzer tone
+pub struct SquareWave {
+    frequency: u32,
+    amplitude: i16,
+    phase: u32,
+}
+
+impl SquareWave {
+    pub fn new(frequency: u32, amplitude: i16) -> Self {
+        SquareWave {
+            frequency: frequency,
+            amplitude: amplitude,
+            phase: 0,
+        }
+    }
+
+    /// Appends `count` samples to `out`, keeping the phase across calls so
+    /// that consecutive frames join without clicks
+    pub fn render(&mut self, out: &mut Vec<i16>, count: usize) {
+        for _ in 0..count {
+            // The phase is tracked in units of 1 / SAMPLE_RATE of a period
+            let sample = if self.phase < SAMPLE_RATE / 2 {
+                self.amplitude
+            } else {
+                -self.amplitude
+            };
+            out.push(sample);
+            self.phase = (self.phase + self.frequency) % SAMPLE_RATE;
+        }
+    }
+}
+
+/// XO-CHIP 1-bit audio pattern, looped at a rate set by the pitch register
+pub struct PatternWave {
+    pattern: [u8; 16],
+    bits_per_second: f32,
+    amplitude: i16,
+    position: f32,
+}
+
+impl PatternWave {
+    pub fn new(pattern: [u8; 16], pitch: u8, amplitude: i16) -> Self {
+        let mut wave = PatternWave {
+            pattern: pattern,
+            bits_per_second: 0.0,
+            amplitude: amplitude,
+            position: 0.0,
+        };
+        wave.set_pitch(pitch);
+        wave
+    }
+
+    pub fn set_pattern(&mut self, pattern: [u8; 16]) {
+        self.pattern = pattern;
+    }
+
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.bits_per_second = 4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0);
+    }
+
+    pub fn render(&mut self, out: &mut Vec<i16>, count: usize) {
+        let step = self.bits_per_second / SAMPLE_RATE as f32;
+        for _ in 0..count {
+            let bit = self.position as usize % PATTERN_BITS;
+            let sample = if self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+                self.amplitude
+            } else {
+                -self.amplitude
+            };
+            out.push(sample);
+            self.position = (self.position + step) % PATTERN_BITS as f32;
+        }
+    }
+}
+
+pub struct Sound {
+    sink: Box<AudioSink>,
+    generator: SquareWave,
+    /// Replaces the square wave once a ROM loads an XO-CHIP pattern
+    pattern: Option<PatternWave>,
+    pitch: u8,
+    buffer: Vec<i16>,
+    playing: bool,
+}
+
+impl Sound {
+    pub fn new(sink: Box<AudioSink>) -> Self {
+        Sound {
+            sink: sink,
+            generator: SquareWave::new(TONE_FREQUENCY, TONE_AMPLITUDE),
+            pattern: None,
+            pitch: DEFAULT_PITCH,
+            buffer: Vec::with_capacity(SAMPLES_PER_FRAME),
+            playing: false,
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Box<AudioSink>) {
+        self.sink = sink;
+    }
+
+    pub fn set_pattern(&mut self, pattern: [u8; 16]) {
+        if let Some(ref mut wave) = self.pattern {
+            wave.set_pattern(pattern);
+            return;
+        }
+        self.pattern = Some(PatternWave::new(pattern, self.pitch, TONE_AMPLITUDE));
+    }
+
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+        if let Some(ref mut wave) = self.pattern {
+            wave.set_pitch(pitch);
+        }
+    }
+
+    /// Renders one 60 Hz frame worth of samples, either the tone or silence
+    pub fn render_frame(&mut self, active: bool) {
+        self.playing = active;
+        self.buffer.clear();
+        if active {
+            match self.pattern {
+                Some(ref mut wave) => wave.render(&mut self.buffer, SAMPLES_PER_FRAME),
+                None => self.generator.render(&mut self.buffer, SAMPLES_PER_FRAME),
+            }
+        } else {
+            self.buffer.resize(SAMPLES_PER_FRAME, 0);
+        }
+        if let Err(e) = self.sink.write(&self.buffer) {
+            println!("Audio output failed, muting: {}", e);
+            self.sink = Box::new(NullSink);
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// The XO-CHIP pattern, if the ROM loaded one
+    pub fn pattern(&self) -> Option<[u8; 16]> {
+        self.pattern.as_ref().map(|wave| wave.pattern)
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
+
+    /// Keeps each write for inspection
+    struct CaptureSink(Rc<RefCell<Vec<Vec<i16>>>>);
+
+    impl AudioSink for CaptureSink {
+        fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+            self.0.borrow_mut().push(samples.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Number of times the samples go from negative to positive
+    fn rising_edges(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|pair| pair[0] < 0 && pair[1] > 0).count()
+    }
+
+    #[test]
+    fn square_wave_plays_the_tone_frequency() {
+        let mut wave = SquareWave::new(440, 100);
+        let mut samples = Vec::new();
+        wave.render(&mut samples, 100);
+        wave.render(&mut samples, SAMPLE_RATE as usize - 100);
+        assert_eq!(samples.len(), SAMPLE_RATE as usize);
+        assert!(samples.iter().all(|&sample| sample == 100 || sample == -100));
+        // Half of the 100.2 samples long period is high, starting with it
+        assert!(samples[..51].iter().all(|&sample| sample == 100));
+        assert_eq!(samples[51], -100);
+        assert_eq!(rising_edges(&samples), 440 - 1);
+    }
+
+    #[test]
+    fn pattern_wave_plays_the_pattern_bits_at_the_pitch_rate() {
+        let mut pattern = [0; 16];
+        pattern[0] = 0x80;
+        // 4000 bits per second, 11.025 samples per bit
+        let mut wave = PatternWave::new(pattern, 64, 100);
+        let mut samples = Vec::new();
+        wave.render(&mut samples, 1400);
+        assert!(samples[..12].iter().all(|&sample| sample == 100));
+        assert!(samples[12..1400].iter().all(|&sample| sample == -100));
+        // An octave up is twice the rate
+        let mut wave = PatternWave::new(pattern, 64 + 48, 100);
+        samples.clear();
+        wave.render(&mut samples, 20);
+        assert!(samples[..6].iter().all(|&sample| sample == 100));
+        assert!(samples[6..].iter().all(|&sample| sample == -100));
+
+        let mut wave = PatternWave::new([0xAA; 16], 64, 100);
+        samples.clear();
+        wave.render(&mut samples, SAMPLE_RATE as usize);
+        let edges = rising_edges(&samples);
+        assert!(edges >= 1999 && edges <= 2000, "{} rising edges", edges);
+    }
+
+    #[test]
+    fn frames_are_a_tone_or_silence() {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let mut sound = Sound::new(Box::new(CaptureSink(frames.clone())));
+        sound.render_frame(true);
+        assert!(sound.is_playing());
+        sound.render_frame(false);
+        assert!(!sound.is_playing());
+        sound.set_pattern([0xFF; 16]);
+        sound.render_frame(true);
+
+        let frames = frames.borrow();
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|frame| frame.len() == SAMPLES_PER_FRAME));
+        assert_eq!(rising_edges(&frames[0]), 7);
+        assert!(frames[1].iter().all(|&sample| sample == 0));
+        assert!(frames[2].iter().all(|&sample| sample == TONE_AMPLITUDE));
+    }
+
+    #[test]
+    fn null_sink_accepts_anything() {
+        let mut sound = Sound::new(Box::new(NullSink));
+        for _ in 0..3 {
+            sound.render_frame(true);
+        }
+        assert!(NullSink.write(&[1, 2, 3]).is_ok());
+    }
+
+    #[test]
+    fn wav_files_have_a_header_matching_their_length() {
+        let path = env::temp_dir().join("chip8emu-sound-test.wav");
+        let path = path.to_str().unwrap();
+        {
+            let mut sink = WavSink::create(path).unwrap();
+            sink.write(&[0; 100]).unwrap();
+            sink.write(&[1, -1, i16::max_value()]).unwrap();
+        }
+        let mut wav = Vec::new();
+        File::open(path).and_then(|mut f| f.read_to_end(&mut wav)).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let u16_at = |pos: usize| wav[pos] as u32 | (wav[pos + 1] as u32) << 8;
+        let u32_at = |pos: usize| u16_at(pos) | u16_at(pos + 2) << 16;
+        let data_len = 103 * 2;
+        assert_eq!(wav.len(), 44 + data_len);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + data_len as u32);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!((u16_at(20), u16_at(22)), (1, 1));
+        assert_eq!((u32_at(24), u32_at(28)), (SAMPLE_RATE, SAMPLE_RATE * 2));
+        assert_eq!((u16_at(32), u16_at(34)), (2, 16));
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(40), data_len as u32);
+        assert_eq!(&wav[244..], &[0x01, 0x00, 0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+}
@@ -0,0 +1,54 @@
+/// The Chip8 delay and sound timers.
+///
+/// Both count down by one on every `tick` until they reach zero. The unit has
+/// no notion of wall-clock time: the emulation loop is expected to tick it at
+/// 60 Hz, which keeps runs reproducible.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timers {
+    delay: u8,
+    sound: u8,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers { delay: 0, sound: 0 }
+    }
+
+    pub fn tick(&mut self) {
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+    }
+
+    pub fn delay(&self) -> u8 {
+        self.delay
+    }
+
+    pub fn set_delay(&mut self, value: u8) {
+        self.delay = value;
+    }
+
+    pub fn sound(&self) -> u8 {
+        self.sound
+    }
+
+    pub fn set_sound(&mut self, value: u8) {
+        self.sound = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_count_both_timers_down_to_zero() {
+        let mut timers = Timers::new();
+        timers.set_delay(2);
+        timers.set_sound(1);
+        timers.tick();
+        assert_eq!((timers.delay(), timers.sound()), (1, 0));
+        timers.tick();
+        timers.tick();
+        assert_eq!((timers.delay(), timers.sound()), (0, 0));
+    }
+}
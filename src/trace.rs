@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+
+use instruction::Instruction;
+use peripherals::Key;
+use video_engine::VideoEngine;
+
+/// Instructions remembered once the debugger is in use. Until then the
+/// trace is off, as recording every instruction slows the emulation down.
+pub const DEFAULT_TRACE_LENGTH: usize = 1024;
+
+/// How to put the screen back the way it was before an instruction
+#[derive(Clone)]
+pub enum VideoUndo {
+    Unchanged,
+    /// Pixels flipped by a sprite, flipping them again restores them
+    Flips(Vec<(usize, usize, u8)>),
+    /// The whole engine, for the rare instructions that rewrite it
+    Snapshot(Box<VideoEngine>),
+}
+
+/// One executed instruction: where it ran and everything it changed, with
+/// the values from before so that it can be undone.
+///
+/// Sound patterns and pitch are not recorded, undoing `F002`/`FX3A` leaves
+/// the audio as it is.
+#[derive(Clone)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub opcode: u16,
+    /// Changed V registers as (index, old value, new value)
+    pub regs: Vec<(usize, u8, u8)>,
+    /// I before and after
+    pub reg_i: (u16, u16),
+    /// Memory writes as (address, old value, new value), in execution order
+    pub mem: Vec<(usize, u8, u8)>,
+    pub(crate) sp: usize,
+    pub(crate) stack: Vec<(usize, usize)>,
+    pub(crate) rpl: Vec<(usize, u8)>,
+    pub(crate) delay: u8,
+    pub(crate) sound: u8,
+    pub(crate) vblank: bool,
+    pub(crate) halted: bool,
+    pub(crate) key_wait: Option<Key>,
+    pub(crate) rng_state: u64,
+    pub(crate) video: VideoUndo,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:03x} {:04x}", self.pc, self.opcode)?;
+        if let Ok(instruction) = Instruction::try_from(self.opcode) {
+            write!(f, " {:?}", instruction)?;
+        }
+        let mut separator = " |";
+        for &(idx, old, new) in &self.regs {
+            write!(f, "{} v{:X} 0x{:02x}->0x{:02x}", separator, idx, old, new)?;
+            separator = ",";
+        }
+        if self.reg_i.0 != self.reg_i.1 {
+            write!(f, "{} i 0x{:04x}->0x{:04x}", separator, self.reg_i.0, self.reg_i.1)?;
+            separator = ",";
+        }
+        for &(addr, old, new) in &self.mem {
+            write!(f, "{} [0x{:03x}] 0x{:02x}->0x{:02x}", separator, addr, old, new)?;
+            separator = ",";
+        }
+        Ok(())
+    }
+}
+
+/// Ring buffer of the last executed instructions
+#[derive(Clone)]
+pub struct Trace {
+    entries: VecDeque<TraceEntry>,
+    capacity: usize,
+}
+
+impl Trace {
+    pub fn new(capacity: usize) -> Self {
+        Trace {
+            entries: VecDeque::new(),
+            capacity: capacity,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Removes the most recent entry
+    pub fn pop(&mut self) -> Option<TraceEntry> {
+        self.entries.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Changes the number of instructions remembered, 0 disabling the trace
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// The last `count` entries, oldest first
+    pub fn last(&self, count: usize) -> Vec<&TraceEntry> {
+        let skip = self.entries.len().saturating_sub(count);
+        self.entries.iter().skip(skip).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A disabled trace
+impl Default for Trace {
+    fn default() -> Self {
+        Trace::new(0)
+    }
+}
@@ -1,45 +1,169 @@
+use savestate::{SaveStateError, StateReader, StateWriter};
+
 pub const BACK_COLOR: u32 = 0x0;
 pub const FRONT_COLOR: u32 = 0xFFFFFFFF;
 pub const SCREEN_X_SIZE: usize = 64;
 pub const SCREEN_Y_SIZE: usize = 32;
+pub const HIRES_X_SIZE: usize = 128;
+pub const HIRES_Y_SIZE: usize = 64;
+
+/// XO-CHIP draws on two independent bitplanes
+pub const NUM_PLANES: usize = 2;
+pub const ALL_PLANES: u8 = 0x3;
+/// Colours for a pixel with no plane set, only plane 1, only plane 2 and both
+pub const DEFAULT_PALETTE: [u32; 4] = [BACK_COLOR, FRONT_COLOR, 0xFFAAAAAA, 0xFF555555];
 
+#[derive(Clone)]
 pub struct VideoEngine {
+    width: usize,
+    height: usize,
+    /// One byte per pixel, bit N set when the pixel is lit on plane N + 1
+    planes: Vec<u8>,
+    selected_planes: u8,
+    palette: [u32; 4],
+    /// `planes` translated through the palette, ready to be presented
     video_ram: Vec<u32>,
 }
 
 impl VideoEngine {
     pub fn new() -> Self {
-        VideoEngine { video_ram: vec![BACK_COLOR; 32 * 64] }
-    }
-
-    pub fn set_pixel_to_1(&mut self, vx: usize, vy: usize) -> bool {
-        if vx < SCREEN_X_SIZE && vy < SCREEN_Y_SIZE {
-            let displacement = vx + (vy * 64);
-            let current_value = self.video_ram[displacement];
-            match current_value {
-                BACK_COLOR => {
-                    self.video_ram[displacement] = FRONT_COLOR;
-                    false
-                }
-                FRONT_COLOR => {
-                    self.video_ram[displacement] = BACK_COLOR;
-                    true
-                }
-                _ => {
-                    panic!("Color {} not supported", current_value);
-                }
-            }
+        VideoEngine {
+            width: SCREEN_X_SIZE,
+            height: SCREEN_Y_SIZE,
+            planes: vec![0; SCREEN_X_SIZE * SCREEN_Y_SIZE],
+            selected_planes: 1,
+            palette: DEFAULT_PALETTE,
+            video_ram: vec![BACK_COLOR; SCREEN_X_SIZE * SCREEN_Y_SIZE],
+        }
+    }
+
+    /// XORs a pixel on the given planes, returning true if any of them was
+    /// already lit
+    pub fn flip_pixel(&mut self, vx: usize, vy: usize, plane_mask: u8) -> bool {
+        if vx < self.width && vy < self.height {
+            let displacement = vx + (vy * self.width);
+            let current_value = self.planes[displacement];
+            self.set_planes(displacement, current_value ^ plane_mask);
+            current_value & plane_mask != 0
         } else {
             false
         }
     }
 
+    /// Returns the planes lit at a given pixel
+    pub fn pixel(&self, vx: usize, vy: usize) -> u8 {
+        self.planes[vx + (vy * self.width)]
+    }
+
+    fn set_planes(&mut self, displacement: usize, value: u8) {
+        self.planes[displacement] = value;
+        self.video_ram[displacement] = self.palette[value as usize];
+    }
+
+    /// Clears the selected planes
     pub fn cls(&mut self) {
-        for i in 0..self.video_ram.len() {
-            self.video_ram[i] = BACK_COLOR
+        let keep = !self.selected_planes;
+        for i in 0..self.planes.len() {
+            let value = self.planes[i] & keep;
+            self.set_planes(i, value);
+        }
+    }
+
+    /// Switches between the 64x32 and the SUPER-CHIP 128x64 resolution,
+    /// clearing the screen
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires {
+            (HIRES_X_SIZE, HIRES_Y_SIZE)
+        } else {
+            (SCREEN_X_SIZE, SCREEN_Y_SIZE)
+        };
+        self.width = width;
+        self.height = height;
+        self.planes = vec![0; width * height];
+        self.video_ram = vec![self.palette[0]; width * height];
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_X_SIZE
+    }
+
+    /// Selects the planes affected by drawing, clearing and scrolling
+    pub fn select_planes(&mut self, plane_mask: u8) {
+        self.selected_planes = plane_mask & ALL_PLANES;
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.palette = palette;
+        for i in 0..self.planes.len() {
+            let value = self.planes[i];
+            self.set_planes(i, value);
         }
     }
 
+    /// Moves the selected planes by `dx`, `dy` pixels, filling with blanks
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let mask = self.selected_planes;
+        let (width, height) = (self.width as isize, self.height as isize);
+        let old = self.planes.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let moved = if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    old[(src_x + src_y * width) as usize] & mask
+                } else {
+                    0
+                };
+                let displacement = (x + y * width) as usize;
+                let value = (old[displacement] & !mask) | moved;
+                self.set_planes(displacement, value);
+            }
+        }
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll(0, rows as isize);
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll(0, -(rows as isize));
+    }
+
+    pub fn scroll_right(&mut self, columns: usize) {
+        self.scroll(columns as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, columns: usize) {
+        self.scroll(-(columns as isize), 0);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bool(self.is_hires());
+        writer.put_u8(self.selected_planes);
+        writer.put_block(&self.planes);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.set_hires(reader.get_bool()?);
+        self.select_planes(reader.get_u8()?);
+        let planes = reader.get_block(self.width * self.height)?;
+        for (i, value) in planes.iter().enumerate() {
+            self.set_planes(i, *value & ALL_PLANES);
+        }
+        Ok(())
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn vram(&self) -> &Vec<u32> {
         &self.video_ram
     }
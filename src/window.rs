@@ -0,0 +1,201 @@
+use minifb::{WindowOptions, Scale, Window, Key, KeyRepeat};
+
+use chip8emu_rs::{Frontend, Hotkey, InputSource, Keymap};
+use chip8emu_rs::input::KeyStates;
+use chip8emu_rs::input::gamepad;
+use chip8emu_rs::peripherals;
+use chip8emu_rs::video_engine::{VideoEngine, HIRES_X_SIZE, HIRES_Y_SIZE};
+
+pub struct WindowFrontend {
+    window: Window,
+    buffer: Vec<u32>,
+    bindings: Vec<(peripherals::Key, Vec<Key>)>,
+}
+
+impl WindowFrontend {
+    pub fn new(keymap: &Keymap) -> Result<Self, String> {
+        let mut bindings = Vec::new();
+        for (target_key, hosts) in keymap.iter() {
+            let mut mapped_keys = Vec::new();
+            // Gamepad controls are left to the gamepad input source
+            for host in hosts.iter().filter(|host| !host.starts_with(gamepad::CONTROL_PREFIX)) {
+                mapped_keys.push(host_key(host)?);
+            }
+            bindings.push((target_key, mapped_keys));
+        }
+
+        let window_options = WindowOptions {
+            borderless: false,
+            title: true,
+            resize: false,
+            scale: Scale::X8,
+        };
+
+        // The window always has the hi-res size, lower resolutions get scaled up
+        Ok(WindowFrontend {
+            window: Window::new("RUST Chip8 Emulator",
+                                HIRES_X_SIZE,
+                                HIRES_Y_SIZE,
+                                window_options)
+                .unwrap(),
+            buffer: vec![0; HIRES_X_SIZE * HIRES_Y_SIZE],
+            bindings: bindings,
+        })
+    }
+}
+
+impl Frontend for WindowFrontend {
+    fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+
+    fn present(&mut self, video: &VideoEngine) {
+        let vram = video.vram();
+        let scale_x = HIRES_X_SIZE / video.width();
+        let scale_y = HIRES_Y_SIZE / video.height();
+        for (i, pixel) in self.buffer.iter_mut().enumerate() {
+            let x = (i % HIRES_X_SIZE) / scale_x;
+            let y = (i / HIRES_X_SIZE) / scale_y;
+            *pixel = vram[x + y * video.width()];
+        }
+        self.window.update_with_buffer(&self.buffer);
+    }
+
+    fn idle(&mut self) {
+        self.window.update();
+    }
+
+    fn is_hotkey_pressed(&mut self, hotkey: Hotkey) -> bool {
+        let key = match hotkey {
+            Hotkey::Debug => Key::F12,
+            Hotkey::SaveState => Key::F5,
+            Hotkey::LoadState => Key::F9,
+            Hotkey::Rewind => return self.window.is_key_down(Key::Backspace),
+        };
+        self.window.is_key_pressed(key, KeyRepeat::No)
+    }
+}
+
+impl InputSource for WindowFrontend {
+    fn poll(&mut self, keys: &mut KeyStates) {
+        for &(target_key, ref mapped_keys) in &self.bindings {
+            if mapped_keys.iter().any(|key| self.window.is_key_down(*key)) {
+                keys[u8::from(target_key) as usize] = true;
+            }
+        }
+    }
+}
+
+/// Resolves a host key name, as spelled by minifb's `Key` variants
+fn host_key(name: &str) -> Result<Key, String> {
+    let key = match name {
+        "A" => Key::A,
+        "B" => Key::B,
+        "C" => Key::C,
+        "D" => Key::D,
+        "E" => Key::E,
+        "F" => Key::F,
+        "G" => Key::G,
+        "H" => Key::H,
+        "I" => Key::I,
+        "J" => Key::J,
+        "K" => Key::K,
+        "L" => Key::L,
+        "M" => Key::M,
+        "N" => Key::N,
+        "O" => Key::O,
+        "P" => Key::P,
+        "Q" => Key::Q,
+        "R" => Key::R,
+        "S" => Key::S,
+        "T" => Key::T,
+        "U" => Key::U,
+        "V" => Key::V,
+        "W" => Key::W,
+        "X" => Key::X,
+        "Y" => Key::Y,
+        "Z" => Key::Z,
+        "Key0" => Key::Key0,
+        "Key1" => Key::Key1,
+        "Key2" => Key::Key2,
+        "Key3" => Key::Key3,
+        "Key4" => Key::Key4,
+        "Key5" => Key::Key5,
+        "Key6" => Key::Key6,
+        "Key7" => Key::Key7,
+        "Key8" => Key::Key8,
+        "Key9" => Key::Key9,
+        "F1" => Key::F1,
+        "F2" => Key::F2,
+        "F3" => Key::F3,
+        "F4" => Key::F4,
+        "F5" => Key::F5,
+        "F6" => Key::F6,
+        "F7" => Key::F7,
+        "F8" => Key::F8,
+        "F9" => Key::F9,
+        "F10" => Key::F10,
+        "F11" => Key::F11,
+        "F12" => Key::F12,
+        "F13" => Key::F13,
+        "F14" => Key::F14,
+        "F15" => Key::F15,
+        "Down" => Key::Down,
+        "Left" => Key::Left,
+        "Right" => Key::Right,
+        "Up" => Key::Up,
+        "Apostrophe" => Key::Apostrophe,
+        "Backquote" => Key::Backquote,
+        "Backslash" => Key::Backslash,
+        "Comma" => Key::Comma,
+        "Equal" => Key::Equal,
+        "LeftBracket" => Key::LeftBracket,
+        "Minus" => Key::Minus,
+        "Period" => Key::Period,
+        "RightBracket" => Key::RightBracket,
+        "Semicolon" => Key::Semicolon,
+        "Slash" => Key::Slash,
+        "Backspace" => Key::Backspace,
+        "Delete" => Key::Delete,
+        "End" => Key::End,
+        "Enter" => Key::Enter,
+        "Escape" => Key::Escape,
+        "Home" => Key::Home,
+        "Insert" => Key::Insert,
+        "Menu" => Key::Menu,
+        "PageDown" => Key::PageDown,
+        "PageUp" => Key::PageUp,
+        "Pause" => Key::Pause,
+        "Space" => Key::Space,
+        "Tab" => Key::Tab,
+        "NumLock" => Key::NumLock,
+        "CapsLock" => Key::CapsLock,
+        "ScrollLock" => Key::ScrollLock,
+        "LeftShift" => Key::LeftShift,
+        "RightShift" => Key::RightShift,
+        "LeftCtrl" => Key::LeftCtrl,
+        "RightCtrl" => Key::RightCtrl,
+        "NumPad0" => Key::NumPad0,
+        "NumPad1" => Key::NumPad1,
+        "NumPad2" => Key::NumPad2,
+        "NumPad3" => Key::NumPad3,
+        "NumPad4" => Key::NumPad4,
+        "NumPad5" => Key::NumPad5,
+        "NumPad6" => Key::NumPad6,
+        "NumPad7" => Key::NumPad7,
+        "NumPad8" => Key::NumPad8,
+        "NumPad9" => Key::NumPad9,
+        "NumPadDot" => Key::NumPadDot,
+        "NumPadSlash" => Key::NumPadSlash,
+        "NumPadAsterisk" => Key::NumPadAsterisk,
+        "NumPadMinus" => Key::NumPadMinus,
+        "NumPadPlus" => Key::NumPadPlus,
+        "NumPadEnter" => Key::NumPadEnter,
+        "LeftAlt" => Key::LeftAlt,
+        "RightAlt" => Key::RightAlt,
+        "LeftSuper" => Key::LeftSuper,
+        "RightSuper" => Key::RightSuper,
+        _ => return Err(format!("Unknown host key \"{}\"", name)),
+    };
+    Ok(key)
+}